@@ -52,7 +52,17 @@ export class InfraStack extends cdk.Stack {
     this.uploadRole.addToPolicy(
       new iam.PolicyStatement({
         effect: iam.Effect.ALLOW,
-        actions: ['s3:PutObject', 's3:DeleteObject'],
+        // PutObjectAcl is only exercised when S3_OBJECT_ACL is set,
+        // PutObjectRetention when S3_OBJECT_LOCK_MODE is;
+        // GetObject and RestoreObject only by `restore`.
+        actions: [
+          's3:PutObject',
+          's3:PutObjectAcl',
+          's3:PutObjectRetention',
+          's3:DeleteObject',
+          's3:GetObject',
+          's3:RestoreObject',
+        ],
         resources: [`${this.bucket.bucketArn}/*`],
       }),
     );
@@ -60,7 +70,7 @@ export class InfraStack extends cdk.Stack {
     this.uploadRole.addToPolicy(
       new iam.PolicyStatement({
         effect: iam.Effect.ALLOW,
-        actions: ['s3:ListBucket'],
+        actions: ['s3:ListBucket', 's3:GetBucketObjectLockConfiguration'],
         resources: [this.bucket.bucketArn],
       }),
     );
@@ -0,0 +1,88 @@
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// A concurrency limit that tunes itself (additive increase, multiplicative
+/// decrease): it starts at `min` (or at `max`, from `at_max`), grows by one
+/// after a full window of unthrottled transfers, and halves as soon as Drive
+/// or S3 asks us to slow down, never leaving `min..=max`.
+pub struct Aimd {
+    slots: Semaphore,
+    state: Mutex<State>,
+}
+
+struct State {
+    limit: usize,
+    min: usize,
+    max: usize,
+    /// Unthrottled transfers since the limit last changed.
+    successes: usize,
+    /// Permits still to be withdrawn after a decrease, as they come back.
+    debt: usize,
+}
+
+impl Aimd {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.clamp(1, max);
+        Self {
+            slots: Semaphore::new(min),
+            state: Mutex::new(State {
+                limit: min,
+                min,
+                max,
+                successes: 0,
+                debt: 0,
+            }),
+        }
+    }
+
+    /// Like `new`, but starting at `max`: a fixed limit that only backs off
+    /// while throttled, then works its way back.
+    pub fn at_max(min: usize, max: usize) -> Self {
+        let min = min.clamp(1, max);
+        Self {
+            slots: Semaphore::new(max),
+            state: Mutex::new(State {
+                limit: max,
+                min,
+                max,
+                successes: 0,
+                debt: 0,
+            }),
+        }
+    }
+
+    /// Waits for a slot under the current limit.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        // The semaphore is never closed.
+        self.slots.acquire().await.unwrap()
+    }
+
+    /// Gives `permit` back after a transfer and adjusts the limit. Returns
+    /// the new limit when it changed.
+    pub fn release(&self, permit: SemaphorePermit<'_>, throttled: bool) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.limit;
+        if throttled {
+            let limit = (state.limit / 2).max(state.min);
+            state.debt += state.limit - limit;
+            state.limit = limit;
+            state.successes = 0;
+        } else {
+            state.successes += 1;
+            if state.successes >= state.limit && state.limit < state.max {
+                state.limit += 1;
+                state.successes = 0;
+                if state.debt > 0 {
+                    state.debt -= 1;
+                } else {
+                    self.slots.add_permits(1);
+                }
+            }
+        }
+        if state.debt > 0 {
+            state.debt -= 1;
+            permit.forget();
+        }
+        (state.limit != before).then_some(state.limit)
+    }
+}
@@ -1,8 +1,17 @@
+use crate::config::{Config, Source};
+use crate::fsutil;
+use crate::http_log::SendLogged;
 use anyhow::{Context, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
 use chrono::{DateTime, Duration, Utc};
 use reqwest::Client;
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use uuid::Uuid;
@@ -10,6 +19,14 @@ use uuid::Uuid;
 const AUTH_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
 const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
 const SCOPE: &str = "https://www.googleapis.com/auth/drive";
+/// Added for `--source=photos`. Since 2025-03-31 it only reaches media
+/// items and albums this OAuth client created, not the whole library.
+const PHOTOS_SCOPE: &str = "https://www.googleapis.com/auth/photoslibrary.readonly";
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+/// How long to wait for the browser's redirect before reminding that the
+/// URL can be pasted instead.
+const PASTE_REMINDER_AFTER: std::time::Duration = std::time::Duration::from_secs(60);
 
 #[derive(Deserialize)]
 struct CredentialsFile {
@@ -22,31 +39,141 @@ struct InstalledCreds {
     client_secret: String,
 }
 
+/// The subset of a service account JSON key we need to mint tokens.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: Option<String>,
+}
+
+enum Credentials {
+    /// OAuth client for the interactive installed-app flow.
+    Installed(InstalledCreds),
+    /// Service account key; tokens are minted from a signed JWT, no browser.
+    ServiceAccount(ServiceAccountKey),
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Token {
     pub access_token: String,
-    refresh_token: String,
+    /// Absent for service account tokens, which are re-minted instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
     expiry: DateTime<Utc>,
+    /// Space-separated scopes Google granted. Missing from tokens cached
+    /// before this was recorded, which only ever had the Drive scope.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
 }
 
 impl Token {
     pub fn is_expired(&self) -> bool {
         Utc::now() >= self.expiry - Duration::seconds(60)
     }
+
+    /// Whether this token was granted every scope in `required`.
+    fn covers(&self, required: &str) -> bool {
+        let granted = self.scope.as_deref().unwrap_or(SCOPE);
+        required
+            .split(' ')
+            .all(|s| granted.split(' ').any(|g| g == s))
+    }
 }
 
-pub async fn load_or_authenticate(
-    http: &Client,
-    creds_path: &str,
-    token_path: &str,
-) -> Result<Token> {
+/// The OAuth scopes a run with `config` needs, space-separated.
+fn scopes(config: &Config) -> String {
+    match config.source {
+        Source::Drive => SCOPE.to_string(),
+        Source::Photos => format!("{SCOPE} {PHOTOS_SCOPE}"),
+    }
+}
+
+/// The current Google token, shared by every client that needs one. Cloning
+/// is cheap and all clones see the same token, so a refresh through any of
+/// them reaches the rest on their next request.
+#[derive(Clone)]
+pub struct TokenHolder {
+    inner: Arc<HolderInner>,
+}
+
+struct HolderInner {
+    http: Client,
+    config: Config,
+    token: RwLock<Token>,
+    // Serializes refreshes so concurrent callers don't each hit the endpoint.
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl TokenHolder {
+    pub fn new(http: &Client, config: &Config, token: Token) -> Self {
+        Self {
+            inner: Arc::new(HolderInner {
+                http: http.clone(),
+                config: config.clone(),
+                token: RwLock::new(token),
+                refreshing: tokio::sync::Mutex::new(()),
+            }),
+        }
+    }
+
+    /// The access token to put on the next request.
+    pub fn access_token(&self) -> String {
+        self.inner.token.read().unwrap().access_token.clone()
+    }
+
+    /// Refreshes the token if it's close to expiry, or unconditionally with
+    /// `force` (after Google rejected it). When `rejected` is given and the
+    /// token has already moved on since that value was sent, another caller
+    /// beat us to the refresh and nothing more is done. A refreshed OAuth
+    /// token is written back to the token file as well.
+    pub async fn refresh(&self, force: bool, rejected: Option<&str>) -> Result<()> {
+        let _guard = self.inner.refreshing.lock().await;
+        let current = self.inner.token.read().unwrap().clone();
+        if rejected.is_some_and(|r| r != current.access_token) {
+            return Ok(());
+        }
+        let (http, config) = (&self.inner.http, &self.inner.config);
+        let fresh = if force {
+            if !current.is_expired() {
+                eprintln!(
+                    "Google rejected an access token that hasn't expired by our clock — refreshing it."
+                );
+            }
+            force_refresh(http, config, &current).await?
+        } else {
+            ensure_fresh(http, config, current).await?
+        };
+        *self.inner.token.write().unwrap() = fresh;
+        Ok(())
+    }
+}
+
+pub async fn load_or_authenticate(http: &Client, config: &Config) -> Result<Token> {
+    let (creds_path, token_path) = (config.creds_file.as_str(), config.token_file.as_str());
+
+    // Service accounts mint a fresh token non-interactively, so there's
+    // nothing cached to load and no refresh token to keep.
+    if Path::new(creds_path).exists() {
+        if let Credentials::ServiceAccount(key) = load_creds(creds_path).await? {
+            return service_account_token(http, &key, config).await;
+        }
+    }
+
     if Path::new(token_path).exists() {
         let data = tokio::fs::read_to_string(token_path).await?;
-        if let Ok(token) = serde_json::from_str::<Token>(&data) {
+        let cached = serde_json::from_str::<Token>(&data).ok().filter(|t| {
+            let covered = t.covers(&scopes(config));
+            if !covered {
+                println!("Cached token lacks a scope this run needs, re-authenticating ...");
+            }
+            covered
+        });
+        if let Some(token) = cached {
             if !token.is_expired() {
                 return Ok(token);
             }
-            let creds = load_creds(creds_path).await?;
+            let creds = load_installed_creds(creds_path).await?;
             match do_refresh(http, &creds, &token).await {
                 Ok(refreshed) => {
                     save_token(token_path, &refreshed).await?;
@@ -57,32 +184,146 @@ pub async fn load_or_authenticate(
         }
     }
 
-    let creds = load_creds(creds_path).await?;
-    let token = browser_flow(http, &creds).await?;
+    let creds = load_installed_creds(creds_path).await?;
+    let template = config.oauth_page_template.as_deref();
+    // With GOOGLE_ACCOUNTS, make Google ask which account to sign in with
+    // rather than reusing whichever the browser is signed in as.
+    let account = config.accounts.first().map(String::as_str);
+    if let Some(account) = account {
+        println!("Sign in with the Google account for \"{account}\".");
+    }
+    let token = browser_flow(http, &creds, &scopes(config), template, account.is_some()).await?;
     save_token(token_path, &token).await?;
     Ok(token)
 }
 
 /// Refresh the token if it is close to expiry. Returns the (possibly new) token.
-pub async fn ensure_fresh(http: &Client, creds_path: &str, token_path: &str, token: Token) -> Result<Token> {
+pub async fn ensure_fresh(http: &Client, config: &Config, token: Token) -> Result<Token> {
     if !token.is_expired() {
         return Ok(token);
     }
-    let creds = load_creds(creds_path).await?;
-    let refreshed = do_refresh(http, &creds, &token).await?;
-    save_token(token_path, &refreshed).await?;
-    Ok(refreshed)
+    force_refresh(http, config, &token).await
 }
 
-async fn load_creds(path: &str) -> Result<InstalledCreds> {
+/// Refresh the token regardless of its recorded expiry — for when Google has
+/// rejected a token (401) that the local clock says is still valid.
+pub async fn force_refresh(http: &Client, config: &Config, token: &Token) -> Result<Token> {
+    match load_creds(&config.creds_file).await? {
+        Credentials::ServiceAccount(key) => service_account_token(http, &key, config).await,
+        Credentials::Installed(creds) => {
+            let refreshed = do_refresh(http, &creds, token).await?;
+            // Persisted so the next run (or one resuming after a crash)
+            // starts from it. A write failure mid-run shouldn't throw away
+            // a token that works.
+            if let Err(e) = save_token(&config.token_file, &refreshed).await {
+                eprintln!(
+                    "Warning: could not save the refreshed token to {}: {e:#}",
+                    config.token_file
+                );
+            }
+            Ok(refreshed)
+        }
+    }
+}
+
+async fn load_creds(path: &str) -> Result<Credentials> {
     let data = tokio::fs::read_to_string(path)
         .await
         .with_context(|| format!("Cannot read credentials file: {path}"))?;
-    let f: CredentialsFile = serde_json::from_str(&data)?;
-    Ok(f.installed)
+    let value: serde_json::Value = serde_json::from_str(&data)?;
+    if value["type"] == "service_account" {
+        let key = serde_json::from_value(value).context("Invalid service account key")?;
+        return Ok(Credentials::ServiceAccount(key));
+    }
+    let f: CredentialsFile = serde_json::from_value(value)?;
+    Ok(Credentials::Installed(f.installed))
 }
 
-async fn browser_flow(http: &Client, creds: &InstalledCreds) -> Result<Token> {
+async fn load_installed_creds(path: &str) -> Result<InstalledCreds> {
+    match load_creds(path).await? {
+        Credentials::Installed(creds) => Ok(creds),
+        Credentials::ServiceAccount(_) => {
+            unreachable!("service accounts never use the installed flow")
+        }
+    }
+}
+
+/// Exchanges a signed JWT for an access token (the OAuth2 service account
+/// flow). With `subject` set, the token acts as that user via domain-wide
+/// delegation; otherwise it acts as the service account itself.
+async fn service_account_token(
+    http: &Client,
+    key: &ServiceAccountKey,
+    config: &Config,
+) -> Result<Token> {
+    let subject = config.impersonate_user.as_deref();
+    let token_uri = key.token_uri.as_deref().unwrap_or(TOKEN_URL);
+    let now = Utc::now().timestamp();
+    let mut claims = serde_json::json!({
+        "iss": key.client_email,
+        "scope": scopes(config),
+        "aud": token_uri,
+        "iat": now,
+        "exp": now + 3600,
+    });
+    if let Some(sub) = subject {
+        claims["sub"] = sub.into();
+    }
+    let assertion = sign_jwt(&claims, &key.private_key)?;
+
+    let resp = http
+        .post(token_uri)
+        .form(&[
+            ("grant_type", JWT_BEARER_GRANT),
+            ("assertion", assertion.as_str()),
+        ])
+        .send_logged()
+        .await?;
+    warn_on_clock_skew(&resp);
+    let resp: serde_json::Value = resp.json().await?;
+
+    parse_token_response(resp, None)
+}
+
+/// Builds an RS256-signed JWT from `claims` using a PEM (PKCS#8) RSA key.
+fn sign_jwt(claims: &serde_json::Value, private_key_pem: &str) -> Result<String> {
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"RS256","typ":"JWT"}"#);
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+    let signing_input = format!("{header}.{payload}");
+
+    let der: String = private_key_pem
+        .lines()
+        .filter(|l| !l.starts_with("-----"))
+        .collect();
+    let der = STANDARD
+        .decode(der.trim())
+        .context("Service account private_key is not valid PEM")?;
+    let key_pair = RsaKeyPair::from_pkcs8(&der)
+        .map_err(|e| anyhow::anyhow!("Invalid service account private_key: {e}"))?;
+
+    let mut signature = vec![0u8; key_pair.public().modulus_len()];
+    key_pair
+        .sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            signing_input.as_bytes(),
+            &mut signature,
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to sign service account JWT"))?;
+
+    Ok(format!(
+        "{signing_input}.{}",
+        URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+async fn browser_flow(
+    http: &Client,
+    creds: &InstalledCreds,
+    scope: &str,
+    template: Option<&Path>,
+    pick_account: bool,
+) -> Result<Token> {
     let listener = TcpListener::bind("127.0.0.1:0").await?;
     let port = listener.local_addr()?.port();
     let redirect_uri = format!("http://127.0.0.1:{port}");
@@ -96,54 +337,172 @@ async fn browser_flow(http: &Client, creds: &InstalledCreds) -> Result<Token> {
         .append_pair("client_id", &creds.client_id)
         .append_pair("redirect_uri", &redirect_uri)
         .append_pair("response_type", "code")
-        .append_pair("scope", SCOPE)
+        .append_pair("scope", scope)
         .append_pair("access_type", "offline")
-        .append_pair("prompt", "consent")
+        .append_pair(
+            "prompt",
+            if pick_account {
+                "select_account consent"
+            } else {
+                "consent"
+            },
+        )
         .append_pair("state", &state);
 
     let auth_url_str = auth_url.to_string();
     println!("Opening browser for Google authentication ...");
     println!("If it doesn't open automatically, visit:\n  {auth_url_str}");
+    println!(
+        "If the browser then can't reach {redirect_uri} (e.g. it runs on another machine), \
+         paste the full URL from its address bar here and press Enter."
+    );
     let _ = open::that(&auth_url_str);
 
-    let (mut stream, _) = listener.accept().await?;
+    let pasted = pasted_line();
+    tokio::pin!(pasted);
+    let reminder = tokio::time::sleep(PASTE_REMINDER_AFTER);
+    tokio::pin!(reminder);
+    let mut stream = loop {
+        tokio::select! {
+            accepted = listener.accept() => break accepted?.0,
+            line = &mut pasted => {
+                let url = url::Url::parse(line.trim())
+                    .context("That doesn't look like the redirect URL")?;
+                let code = parse_redirect(&url, &state)?;
+                return exchange_code(http, creds, &code, &redirect_uri).await;
+            }
+            () = &mut reminder, if !reminder.is_elapsed() => println!(
+                "Still waiting for the browser. If it shows a connection error, paste the URL \
+                 it was sent to (starting {redirect_uri}/?) here."
+            ),
+        }
+    };
     let mut buf = vec![0u8; 4096];
     let n = stream.read(&mut buf).await?;
     let request = String::from_utf8_lossy(&buf[..n]);
 
+    let outcome = parse_callback(&request, &state);
+    let page = match outcome {
+        Ok(_) => {
+            callback_page(
+                template,
+                true,
+                "Authentication successful",
+                "You can close this tab and return to the terminal.",
+            )
+            .await
+        }
+        Err(ref e) => callback_page(template, false, "Authentication failed", &e.to_string()).await,
+    };
+    let status = if outcome.is_ok() {
+        "200 OK"
+    } else {
+        "400 Bad Request"
+    };
     stream
         .write_all(
-            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: close\r\n\r\n\
-              <html><body><h2>Authentication successful!</h2>\
-              <p>You can close this tab.</p></body></html>",
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/html; charset=utf-8\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{page}",
+                page.len()
+            )
+            .as_bytes(),
         )
         .await?;
     stream.flush().await.ok();
 
+    let code = outcome?;
+    exchange_code(http, creds, &code, &redirect_uri).await
+}
+
+/// The first non-empty line typed on stdin, for pasting the redirect URL.
+/// Nothing is read until a line is waiting, so dropping this once the
+/// browser reaches the loopback listener leaves stdin to later prompts.
+/// Never resolves when stdin can't be polled (e.g. `/dev/null` under cron).
+async fn pasted_line() -> String {
+    use std::io::BufRead;
+    if let Ok(stdin) = tokio::io::unix::AsyncFd::new(std::io::stdin()) {
+        while let Ok(mut ready) = stdin.readable().await {
+            let mut line = String::new();
+            match stdin.get_ref().lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) if !line.trim().is_empty() => return line,
+                Ok(_) => ready.clear_ready(),
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// Pulls the auth code out of the browser's redirect request, or explains
+/// why there isn't one (consent denied, state mismatch, malformed request).
+fn parse_callback(request: &str, state: &str) -> Result<String> {
     let path = request
         .lines()
         .next()
         .and_then(|line| line.split_whitespace().nth(1))
         .context("Malformed HTTP request from browser")?;
 
-    let parsed_url = url::Url::parse(&format!("http://localhost{path}"))?;
-    let params: std::collections::HashMap<_, _> = parsed_url.query_pairs().collect();
+    parse_redirect(&url::Url::parse(&format!("http://localhost{path}"))?, state)
+}
+
+/// The auth code from the URL Google redirected to, whether the browser
+/// delivered it to the loopback listener or it was pasted in.
+fn parse_redirect(url: &url::Url, state: &str) -> Result<String> {
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
 
     // Validate the state parameter to guard against CSRF.
-    let returned_state = params
-        .get("state")
-        .map(|s| s.as_ref())
-        .unwrap_or_default();
+    let returned_state = params.get("state").map(|s| s.as_ref()).unwrap_or_default();
     if returned_state != state {
         anyhow::bail!("OAuth state mismatch — possible CSRF attack, aborting.");
     }
 
-    let code = params
+    // Google sends `error=access_denied` when consent is refused.
+    if let Some(error) = params.get("error") {
+        anyhow::bail!("Google returned an error: {error}");
+    }
+
+    params
         .get("code")
         .map(|s| s.to_string())
-        .context("No auth code in redirect URL")?;
+        .context("No auth code in redirect URL")
+}
 
-    exchange_code(http, creds, &code, &redirect_uri).await
+/// The built-in page shown in the browser after the OAuth redirect. It tries
+/// to close its own tab, which browsers only allow for some tabs.
+const CALLBACK_PAGE: &str = r#"<!doctype html>
+<html><head><meta charset="utf-8"><title>{{title}} — {{tool}}</title>
+<style>body{font-family:system-ui,sans-serif;max-width:32em;margin:4em auto;color:#222}
+.error h2{color:#b00020}</style></head>
+<body class="{{status}}"><h2>{{title}}</h2><p>{{message}}</p><p><small>{{tool}}</small></p>
+<script>if (document.body.className === "success") setTimeout(() => window.close(), 3000);</script>
+</body></html>"#;
+
+/// Renders the OAuth callback page from `template` (a file path, falling
+/// back to the built-in page). `{{title}}`, `{{message}}`, `{{status}}`
+/// (`success` or `error`) and `{{tool}}` are substituted, HTML-escaped.
+async fn callback_page(template: Option<&Path>, ok: bool, title: &str, message: &str) -> String {
+    let html = match template {
+        Some(path) => tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Cannot read OAuth page template {}", path.display()))
+            .unwrap_or_else(|e| {
+                eprintln!("Warning: {e:#}; using the built-in page");
+                CALLBACK_PAGE.to_string()
+            }),
+        None => CALLBACK_PAGE.to_string(),
+    };
+    html.replace("{{title}}", &escape_html(title))
+        .replace("{{message}}", &escape_html(message))
+        .replace("{{status}}", if ok { "success" } else { "error" })
+        .replace("{{tool}}", &escape_html(crate::config::DEFAULT_USER_AGENT))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 async fn exchange_code(
@@ -152,7 +511,7 @@ async fn exchange_code(
     code: &str,
     redirect_uri: &str,
 ) -> Result<Token> {
-    let resp: serde_json::Value = http
+    let resp = http
         .post(TOKEN_URL)
         .form(&[
             ("code", code),
@@ -161,29 +520,64 @@ async fn exchange_code(
             ("redirect_uri", redirect_uri),
             ("grant_type", "authorization_code"),
         ])
-        .send()
-        .await?
-        .json()
+        .send_logged()
         .await?;
+    warn_on_clock_skew(&resp);
+    let resp: serde_json::Value = resp.json().await?;
 
-    parse_token_response(resp, None)
+    let token = parse_token_response(resp, None)?;
+    if token.refresh_token.is_none() {
+        anyhow::bail!("Missing refresh_token");
+    }
+    Ok(token)
 }
 
 async fn do_refresh(http: &Client, creds: &InstalledCreds, token: &Token) -> Result<Token> {
-    let resp: serde_json::Value = http
+    let refresh_token = token
+        .refresh_token
+        .as_deref()
+        .context("Token has no refresh_token")?;
+    let resp = http
         .post(TOKEN_URL)
         .form(&[
-            ("refresh_token", token.refresh_token.as_str()),
+            ("refresh_token", refresh_token),
             ("client_id", creds.client_id.as_str()),
             ("client_secret", creds.client_secret.as_str()),
             ("grant_type", "refresh_token"),
         ])
-        .send()
-        .await?
-        .json()
+        .send_logged()
         .await?;
+    warn_on_clock_skew(&resp);
+    let resp: serde_json::Value = resp.json().await?;
 
-    parse_token_response(resp, Some(&token.refresh_token))
+    parse_token_response(resp, Some(refresh_token))
+}
+
+/// If the server's `Date` header disagrees with the local clock by more than
+/// this, token expiry calculations (and AWS request signing) can't be trusted.
+const MAX_CLOCK_SKEW_SECS: i64 = 120;
+
+/// Warns (once per run) when Google's clock and ours disagree noticeably.
+fn warn_on_clock_skew(resp: &reqwest::Response) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    let Some(server_time) = resp
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+    else {
+        return;
+    };
+    let skew = Utc::now().signed_duration_since(server_time).num_seconds();
+    if skew.abs() > MAX_CLOCK_SKEW_SECS && !WARNED.swap(true, Ordering::Relaxed) {
+        eprintln!(
+            "Warning: local clock is {}s {} Google's — token expiry checks may be wrong. \
+             Sync your system clock (e.g. sudo sntp -sS time.apple.com).",
+            skew.abs(),
+            if skew > 0 { "ahead of" } else { "behind" }
+        );
+    }
 }
 
 fn parse_token_response(resp: serde_json::Value, existing_refresh: Option<&str>) -> Result<Token> {
@@ -198,20 +592,20 @@ fn parse_token_response(resp: serde_json::Value, existing_refresh: Option<&str>)
     let refresh_token = resp["refresh_token"]
         .as_str()
         .map(String::from)
-        .or_else(|| existing_refresh.map(String::from))
-        .context("Missing refresh_token")?;
+        .or_else(|| existing_refresh.map(String::from));
     let expires_in = resp["expires_in"].as_i64().unwrap_or(3600);
     Ok(Token {
         access_token,
         refresh_token,
         expiry: Utc::now() + Duration::seconds(expires_in),
+        scope: resp["scope"].as_str().map(String::from),
     })
 }
 
 async fn save_token(path: &str, token: &Token) -> Result<()> {
     let json = serde_json::to_string_pretty(token)?;
 
-    // Write to a temp file first, then atomically rename to avoid corruption
+    // Write to a temp file first, then move it into place to avoid corruption
     // if the process is killed mid-write.
     let tmp_path = format!("{path}.tmp");
     tokio::fs::write(&tmp_path, &json).await?;
@@ -224,6 +618,6 @@ async fn save_token(path: &str, token: &Token) -> Result<()> {
         tokio::fs::set_permissions(&tmp_path, perms).await?;
     }
 
-    tokio::fs::rename(&tmp_path, path).await?;
+    fsutil::move_file(Path::new(&tmp_path), Path::new(path)).await?;
     Ok(())
 }
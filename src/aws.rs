@@ -1,30 +1,544 @@
+use crate::config::{ByteSize, PrefixFormat, Runtime};
+use crate::http_log;
+use crate::retry::retry;
 use anyhow::{Context, Result};
 use aws_config::BehaviorVersion;
 use aws_credential_types::Credentials;
+use aws_sdk_s3::config::RequestChecksumCalculation;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
 use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
-use bytes::Bytes;
+use aws_sdk_s3::types::{
+    ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart, GlacierJobParameters,
+    ObjectCannedAcl, ObjectLockEnabled, ObjectLockMode, RestoreRequest, StorageClass, Tier,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
 use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 /// Files larger than this are uploaded using S3 multipart upload.
 /// Single PUT is capped at 5 GB; we switch well before that.
-const MULTIPART_THRESHOLD: u64 = 100 * 1024 * 1024; // 100 MB
+pub const MULTIPART_THRESHOLD: u64 = 100 * 1024 * 1024; // 100 MB
 
 /// Size of each multipart chunk. Must be ≥ 5 MB (S3 minimum) for all but the last part.
 const PART_SIZE: usize = 64 * 1024 * 1024; // 64 MB
 
+/// Memory one streamed multipart upload holds at its peak: the part being
+/// filled plus the one being sent.
+pub const UPLOAD_MEMORY: u64 = 2 * PART_SIZE as u64;
+
+/// Memory one multipart upload of a file holds at its peak, with
+/// `part_concurrency` parts in flight.
+pub fn file_upload_memory(part_concurrency: usize) -> u64 {
+    part_concurrency as u64 * PART_SIZE as u64
+}
+
+/// An object as reported by ListObjectsV2.
+pub struct S3Object {
+    pub key: String,
+    pub size: u64,
+    /// ETag with the surrounding quotes removed. Equals the content MD5 for
+    /// single-part uploads; multipart ETags contain a `-`.
+    pub etag: Option<String>,
+}
+
+/// S3's limit on key length, in UTF-8 bytes.
+pub const MAX_KEY_BYTES: usize = 1024;
+
+/// Checks `name` against the S3 bucket naming rules, so a typo in
+/// `S3_BUCKET_NAME` fails up front instead of as an obscure SDK error.
+pub fn validate_bucket_name(name: &str) -> Result<()> {
+    let problem = if !(3..=63).contains(&name.len()) {
+        Some("must be 3 to 63 characters long")
+    } else if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+    {
+        Some("may only contain lowercase letters, digits, dots and hyphens")
+    } else if !name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        || !name.ends_with(|c: char| c.is_ascii_alphanumeric())
+    {
+        Some("must start and end with a letter or digit")
+    } else if name.contains("..") {
+        Some("must not contain two dots in a row")
+    } else if name.parse::<std::net::Ipv4Addr>().is_ok() {
+        Some("must not look like an IP address")
+    } else if name.starts_with("xn--") || name.starts_with("sthree-") {
+        Some("must not start with xn-- or sthree-")
+    } else if name.ends_with("-s3alias") || name.ends_with("--ol-s3") {
+        Some("must not end with -s3alias or --ol-s3")
+    } else {
+        None
+    };
+    match problem {
+        Some(problem) => {
+            anyhow::bail!("Invalid S3_BUCKET_NAME \"{name}\": a bucket name {problem}")
+        }
+        None => Ok(()),
+    }
+}
+
+/// Checks a generated object key before anything is transferred to it.
+pub fn validate_key(key: &str) -> Result<()> {
+    let problem = if key.starts_with('/') {
+        Some("starts with a slash")
+    } else if key.ends_with('/') {
+        Some("ends with a slash")
+    } else if key.contains("//") {
+        Some("contains an empty path segment (//)")
+    } else if key.len() > MAX_KEY_BYTES {
+        Some("is longer than S3's 1024-byte limit")
+    } else {
+        None
+    };
+    match problem {
+        Some(problem) => anyhow::bail!("Invalid S3 key \"{key}\": it {problem}"),
+        None => Ok(()),
+    }
+}
+
+/// `metadata` as the SDK takes it, `None` rather than an empty map.
+fn user_metadata(metadata: &[(&str, String)]) -> Option<HashMap<String, String>> {
+    (!metadata.is_empty()).then(|| {
+        metadata
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    })
+}
+
+/// One S3 call `doctor` tried, named by the IAM action it needs.
+pub struct Probe {
+    pub action: &'static str,
+    pub call: &'static str,
+    /// Not needed for backups; failing is only reported.
+    pub optional: bool,
+    /// What was learned on success, or why it failed.
+    pub result: std::result::Result<Option<String>, ProbeError>,
+}
+
+/// How an S3 call failed: S3's error code (`AccessDenied`, `NoSuchBucket`,
+/// …) and HTTP status when it answered, or only a message when it couldn't
+/// be reached.
+pub struct ProbeError {
+    pub code: Option<String>,
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl ProbeError {
+    fn from_sdk<E>(e: &SdkError<E>) -> Self
+    where
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+    {
+        Self {
+            code: e.code().map(String::from),
+            status: e.raw_response().map(|r| r.status().as_u16()),
+            message: e
+                .message()
+                .map(String::from)
+                .unwrap_or_else(|| error_chain(e)),
+        }
+    }
+}
+
+/// `e` and its sources, `: `-separated, for SDK errors whose own message
+/// is only "dispatch failure".
+pub fn error_chain(e: &(dyn std::error::Error + 'static)) -> String {
+    let mut chain = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        chain.push_str(": ");
+        chain.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    chain
+}
+
+/// Whether an object's content can be read right now.
+pub enum Retrieval {
+    /// In a Glacier class with no retrieved copy.
+    Archived,
+    /// A retrieval was requested and hasn't finished.
+    InProgress,
+    Ready,
+}
+
 pub struct S3Uploader {
     client: aws_sdk_s3::Client,
     bucket: String,
+    /// Canned ACL applied to every object written, if configured.
+    acl: Option<ObjectCannedAcl>,
+    /// What S3 checks each upload's content against.
+    checksum: ChecksumAlgo,
+    /// When the assumed role's credentials lapse. They're never refreshed,
+    /// so requests after this fail.
+    expires_at: Option<SystemTime>,
+    /// Object Lock retention applied to every object written, if configured.
+    lock: Option<ObjectLock>,
+    /// Storage class for each backed-up object by its size; the bucket's
+    /// default (STANDARD) when unset.
+    storage_classes: Option<StorageClasses>,
+    /// Most parts of one file's multipart upload sent at once.
+    part_concurrency: usize,
+    /// `--no-overwrite`: upload only to keys that hold no object yet.
+    no_overwrite: bool,
+}
+
+/// An upload `--no-overwrite` refused because its key already holds an
+/// object. Not retried: it would only be refused again.
+#[derive(Debug)]
+pub struct AlreadyExists {
+    pub key: String,
+}
+
+impl std::fmt::Display for AlreadyExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "S3 already holds an object at {}", self.key)
+    }
+}
+
+impl std::error::Error for AlreadyExists {}
+
+/// True if `e` is an upload refused by `--no-overwrite`.
+pub fn is_already_exists(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<AlreadyExists>().is_some()
+}
+
+/// Where backups go, from the environment.
+pub struct S3Settings {
+    pub bucket: String,
+    pub role_arn: String,
+    pub acl: Option<ObjectCannedAcl>,
+    pub lock: Option<ObjectLock>,
+    pub storage_classes: Option<StorageClasses>,
+}
+
+impl S3Settings {
+    pub fn from_env() -> Result<Self> {
+        let bucket = std::env::var("S3_BUCKET_NAME")
+            .ok()
+            .context("S3_BUCKET_NAME must be set")?;
+        let role_arn = std::env::var("AWS_UPLOAD_ROLE_ARN")
+            .ok()
+            .context("AWS_UPLOAD_ROLE_ARN must be set")?;
+        validate_bucket_name(&bucket)?;
+
+        let acl = std::env::var("S3_OBJECT_ACL")
+            .ok()
+            .map(|name| parse_acl(&name))
+            .transpose()?;
+
+        let lock = match (
+            std::env::var("S3_OBJECT_LOCK_MODE").ok(),
+            std::env::var("S3_OBJECT_LOCK_UNTIL").ok(),
+        ) {
+            (Some(mode), Some(until)) => Some(parse_object_lock(&mode, &until)?),
+            (None, None) => None,
+            _ => anyhow::bail!("S3_OBJECT_LOCK_MODE and S3_OBJECT_LOCK_UNTIL must be set together"),
+        };
+        let storage_classes = std::env::var("S3_STORAGE_CLASS")
+            .ok()
+            .map(|spec| parse_storage_classes(&spec))
+            .transpose()?;
+        Ok(Self {
+            bucket,
+            role_arn,
+            acl,
+            lock,
+            storage_classes,
+        })
+    }
+}
+
+/// `S3_OBJECT_LOCK_MODE` / `S3_OBJECT_LOCK_UNTIL`: retention for every
+/// object written.
+#[derive(Clone)]
+pub struct ObjectLock {
+    pub mode: ObjectLockMode,
+    pub until: RetainUntil,
+}
+
+#[derive(Clone, Copy)]
+pub enum RetainUntil {
+    /// This long after each object is written.
+    For(Duration),
+    /// A fixed date, the same for every object.
+    Date(DateTime<Utc>),
+}
+
+impl ObjectLock {
+    /// Retain-until date for an object written now.
+    fn retain_until(&self) -> aws_sdk_s3::primitives::DateTime {
+        let at = match self.until {
+            RetainUntil::For(d) => SystemTime::now() + d,
+            RetainUntil::Date(date) => date.into(),
+        };
+        aws_sdk_s3::primitives::DateTime::from(at)
+    }
+}
+
+/// Parses `S3_OBJECT_LOCK_MODE` (`GOVERNANCE` or `COMPLIANCE`) and
+/// `S3_OBJECT_LOCK_UNTIL`: a duration (`365d`, see `Runtime`) or a date
+/// (`2031-01-01`, or RFC 3339).
+pub fn parse_object_lock(mode: &str, until: &str) -> Result<ObjectLock> {
+    let mode = match mode.to_ascii_uppercase().as_str() {
+        "GOVERNANCE" => ObjectLockMode::Governance,
+        "COMPLIANCE" => ObjectLockMode::Compliance,
+        _ => anyhow::bail!(
+            "Invalid S3_OBJECT_LOCK_MODE \"{mode}\" (expected GOVERNANCE or COMPLIANCE)"
+        ),
+    };
+    let until = if let Ok(date) = DateTime::parse_from_rfc3339(until) {
+        RetainUntil::Date(date.to_utc())
+    } else if let Ok(date) = chrono::NaiveDate::parse_from_str(until, "%Y-%m-%d") {
+        RetainUntil::Date(date.and_time(chrono::NaiveTime::MIN).and_utc())
+    } else {
+        let runtime: Runtime = until.parse().with_context(|| {
+            format!(
+                "Invalid S3_OBJECT_LOCK_UNTIL \"{until}\" (expected a duration like 365d or a \
+                 date like 2031-01-01)"
+            )
+        })?;
+        RetainUntil::For(runtime.0)
+    };
+    match until {
+        RetainUntil::Date(date) if date <= Utc::now() => {
+            anyhow::bail!("S3_OBJECT_LOCK_UNTIL {date} is in the past")
+        }
+        RetainUntil::For(d) if d.is_zero() => {
+            anyhow::bail!("S3_OBJECT_LOCK_UNTIL must be positive")
+        }
+        _ => {}
+    }
+    Ok(ObjectLock { mode, until })
+}
+
+/// `--checksum-algo`: the checksum sent with every upload, which S3 checks
+/// the received content against before storing it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgo {
+    /// CRC32C, the cheapest to compute.
+    Crc32c,
+    Sha256,
+    /// `Content-MD5`, on single uploads and each multipart part.
+    Md5,
+    None,
+}
+
+impl FromStr for ChecksumAlgo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "crc32c" => Ok(Self::Crc32c),
+            "sha256" => Ok(Self::Sha256),
+            "md5" => Ok(Self::Md5),
+            "none" => Ok(Self::None),
+            _ => anyhow::bail!("expected crc32c, sha256, md5 or none"),
+        }
+    }
+}
+
+impl ChecksumAlgo {
+    /// The algorithm a multipart upload is created with, for a checksum
+    /// of the whole object; MD5 is only checked per part.
+    fn algorithm(self) -> Option<ChecksumAlgorithm> {
+        match self {
+            ChecksumAlgo::Crc32c => Some(ChecksumAlgorithm::Crc32C),
+            ChecksumAlgo::Sha256 => Some(ChecksumAlgorithm::Sha256),
+            ChecksumAlgo::Md5 | ChecksumAlgo::None => None,
+        }
+    }
+
+    /// A `Hasher` for this algorithm, to checksum data as it streams past.
+    pub fn hasher(self) -> Hasher {
+        match self {
+            ChecksumAlgo::Crc32c => {
+                Hasher::Crc32c(crc_fast::Digest::new(crc_fast::CrcAlgorithm::Crc32Iscsi))
+            }
+            ChecksumAlgo::Sha256 => {
+                Hasher::Sha256(ring::digest::Context::new(&ring::digest::SHA256))
+            }
+            ChecksumAlgo::Md5 => Hasher::Md5(Md5::new()),
+            ChecksumAlgo::None => Hasher::None,
+        }
+    }
+
+    /// `data`'s checksum as S3 takes it: base64 of the digest (of the
+    /// big-endian CRC), or `None` if nothing is sent.
+    fn digest(self, data: &[u8]) -> Option<String> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// The checksum of a file read from disk, like `digest`. For files that
+    /// weren't checksummed as they were written.
+    async fn file_digest(self, path: &Path) -> Result<Option<String>> {
+        let mut hasher = self.hasher();
+        if !hasher.is_active() {
+            return Ok(None);
+        }
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("Cannot open file: {}", path.display()))?;
+        let mut buf = vec![0u8; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finish())
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgo::Crc32c => "CRC32C",
+            ChecksumAlgo::Sha256 => "SHA-256",
+            ChecksumAlgo::Md5 => "MD5",
+            ChecksumAlgo::None => "none",
+        }
+    }
+}
+
+/// A `ChecksumAlgo` checksum in progress, fed a chunk at a time.
+pub enum Hasher {
+    Crc32c(crc_fast::Digest),
+    Sha256(ring::digest::Context),
+    Md5(Md5),
+    /// `--checksum-algo none`, or nothing worth computing.
+    None,
+}
+
+impl Hasher {
+    pub fn is_active(&self) -> bool {
+        !matches!(self, Hasher::None)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32c(crc) => crc.update(data),
+            Hasher::Sha256(sha) => sha.update(data),
+            Hasher::Md5(md5) => md5.update(data),
+            Hasher::None => {}
+        }
+    }
+
+    /// The checksum as S3 takes it, like `ChecksumAlgo::digest`.
+    pub fn finish(self) -> Option<String> {
+        let digest = match self {
+            Hasher::Crc32c(crc) => (crc.finalize() as u32).to_be_bytes().to_vec(),
+            Hasher::Sha256(sha) => sha.finish().as_ref().to_vec(),
+            Hasher::Md5(md5) => md5.finalize().to_vec(),
+            Hasher::None => return None,
+        };
+        Some(STANDARD.encode(digest))
+    }
+}
+
+/// `S3_STORAGE_CLASS`: the storage class each backed-up object is written
+/// with, by its size.
+#[derive(Clone)]
+pub struct StorageClasses {
+    /// For objects smaller than the first threshold.
+    base: StorageClass,
+    /// From each size up, by ascending size.
+    from: Vec<(u64, StorageClass)>,
+}
+
+/// The classes an upload may ask for; the rest are for other kinds of
+/// bucket or storage.
+const STORAGE_CLASSES: &[&str] = &[
+    "STANDARD",
+    "REDUCED_REDUNDANCY",
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER_IR",
+    "GLACIER",
+    "DEEP_ARCHIVE",
+];
+
+impl StorageClasses {
+    /// The class for an object of `size` bytes. One whose size isn't known
+    /// up front, a streamed bundle say, is taken to be large.
+    pub fn for_size(&self, size: Option<u64>) -> StorageClass {
+        let Some(size) = size else {
+            return self.from.last().map_or(&self.base, |(_, c)| c).clone();
+        };
+        self.from
+            .iter()
+            .rev()
+            .find(|(at, _)| size >= *at)
+            .map_or(&self.base, |(_, c)| c)
+            .clone()
+    }
+}
+
+/// Parses `S3_STORAGE_CLASS`: a class, optionally followed by
+/// `,<size>=<class>` rules that apply from that size up, e.g.
+/// `STANDARD_IA,100M=DEEP_ARCHIVE`.
+pub fn parse_storage_classes(spec: &str) -> Result<StorageClasses> {
+    let class = |name: &str| -> Result<StorageClass> {
+        let name = name.trim().to_ascii_uppercase();
+        if !STORAGE_CLASSES.contains(&name.as_str()) {
+            anyhow::bail!(
+                "Invalid storage class \"{name}\" in S3_STORAGE_CLASS (expected one of: {})",
+                STORAGE_CLASSES.join(", ")
+            );
+        }
+        Ok(StorageClass::from(name.as_str()))
+    };
+    let mut rules = spec.split(',');
+    let base = class(rules.next().unwrap_or_default())?;
+    let mut from: Vec<(u64, StorageClass)> = Vec::new();
+    for rule in rules {
+        let Some((size, name)) = rule.split_once('=') else {
+            anyhow::bail!("Invalid rule \"{rule}\" in S3_STORAGE_CLASS (expected <size>=<class>, e.g. 100M=DEEP_ARCHIVE)");
+        };
+        let size: ByteSize = size
+            .parse()
+            .with_context(|| format!("Invalid size \"{size}\" in S3_STORAGE_CLASS"))?;
+        if from.last().is_some_and(|(at, _)| size.0 <= *at) {
+            anyhow::bail!("S3_STORAGE_CLASS sizes must increase from rule to rule");
+        }
+        from.push((size.0, class(name)?));
+    }
+    Ok(StorageClasses { base, from })
+}
+
+/// Parses a canned ACL name such as `bucket-owner-full-control`.
+pub fn parse_acl(name: &str) -> Result<ObjectCannedAcl> {
+    if !ObjectCannedAcl::values().contains(&name) {
+        anyhow::bail!(
+            "Invalid S3_OBJECT_ACL \"{name}\" (expected one of: {})",
+            ObjectCannedAcl::values().join(", ")
+        );
+    }
+    Ok(ObjectCannedAcl::from(name))
 }
 
 impl S3Uploader {
-    pub async fn new(bucket: String, role_arn: &str) -> Result<Self> {
+    pub async fn new(bucket: String, role_arn: &str, acl: Option<ObjectCannedAcl>) -> Result<Self> {
         // Use the IAM user credentials from the environment to call STS.
         let base_config = aws_config::defaults(BehaviorVersion::latest()).load().await;
-        let sts = aws_sdk_sts::Client::new(&base_config);
+        let mut sts_config = aws_sdk_sts::config::Builder::from(&base_config);
+        if http_log::enabled() {
+            sts_config = sts_config.interceptor(http_log::SdkLogger);
+        }
+        let sts = aws_sdk_sts::Client::from_conf(sts_config.build());
 
         let assumed = sts
             .assume_role()
@@ -39,11 +553,12 @@ impl S3Uploader {
             .credentials
             .context("No credentials in AssumeRole response")?;
 
+        let expires_at = SystemTime::try_from(c.expiration).ok();
         let temp_creds = Credentials::new(
             c.access_key_id,
             c.secret_access_key,
             Some(c.session_token),
-            None,
+            expires_at,
             "assumed-role",
         );
 
@@ -52,31 +567,325 @@ impl S3Uploader {
             .load()
             .await;
 
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&s3_config);
+        if http_log::enabled() {
+            s3_config = s3_config.interceptor(http_log::SdkLogger);
+        }
+
         Ok(Self {
-            client: aws_sdk_s3::Client::new(&s3_config),
+            client: aws_sdk_s3::Client::from_conf(s3_config.build()),
             bucket,
+            acl,
+            checksum: ChecksumAlgo::Crc32c,
+            expires_at,
+            lock: None,
+            storage_classes: None,
+            part_concurrency: 1,
+            no_overwrite: false,
         })
     }
 
-    /// Returns top-level date prefixes (e.g. ["2024-01-01/", "2024-02-01/"]) sorted ascending.
-    pub async fn list_backup_prefixes(&self) -> Result<Vec<String>> {
+    pub fn with_storage_classes(mut self, classes: Option<StorageClasses>) -> Self {
+        self.storage_classes = classes;
+        self
+    }
+
+    /// The storage class for a backed-up object of `size` bytes, if set.
+    fn storage_class(&self, size: Option<u64>) -> Option<StorageClass> {
+        self.storage_classes.as_ref().map(|c| c.for_size(size))
+    }
+
+    /// Applies `lock` to every object written from now on, after checking
+    /// that the bucket has Object Lock enabled: S3 rejects retention
+    /// settings otherwise, and Object Lock can't be turned on for a bucket
+    /// after the fact without versioning.
+    pub async fn with_object_lock(mut self, lock: ObjectLock) -> Result<Self> {
         let resp = self
             .client
-            .list_objects_v2()
+            .get_object_lock_configuration()
             .bucket(&self.bucket)
-            .delimiter("/")
             .send()
             .await
-            .context("S3 ListObjectsV2 failed")?;
+            .with_context(|| {
+                format!(
+                    "Cannot read the Object Lock configuration of bucket {} \
+                     (S3_OBJECT_LOCK_MODE needs a bucket with Object Lock enabled)",
+                    self.bucket
+                )
+            })?;
+        let enabled = resp
+            .object_lock_configuration()
+            .and_then(|c| c.object_lock_enabled())
+            == Some(&ObjectLockEnabled::Enabled);
+        if !enabled {
+            anyhow::bail!(
+                "Bucket {} doesn't have Object Lock enabled, which S3_OBJECT_LOCK_MODE needs",
+                self.bucket
+            );
+        }
+        self.lock = Some(lock);
+        Ok(self)
+    }
 
-        let mut prefixes: Vec<String> = resp
-            .common_prefixes()
-            .iter()
-            .filter_map(|p| p.prefix().map(|s| s.to_string()))
-            .collect();
+    pub fn with_part_concurrency(mut self, parts: usize) -> Self {
+        self.part_concurrency = parts;
+        self
+    }
+
+    pub fn with_no_overwrite(mut self, no_overwrite: bool) -> Self {
+        self.no_overwrite = no_overwrite;
+        self
+    }
+
+    /// `If-None-Match: *` for an upload under `--no-overwrite`.
+    fn if_none_match(&self) -> Option<String> {
+        self.no_overwrite.then(|| "*".to_string())
+    }
+
+    /// Whether an upload sent with `if_none_match` was turned down for the
+    /// key being taken: 412 when the object exists, 409 when a concurrent
+    /// conditional write to the same key won.
+    fn refused_overwrite<E>(&self, e: &SdkError<E>) -> bool {
+        self.no_overwrite
+            && e.raw_response()
+                .is_some_and(|r| matches!(r.status().as_u16(), 409 | 412))
+    }
+
+    /// When the upload role's credentials expire, if STS said.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    /// How much longer the credentials are valid; zero once they've lapsed.
+    pub fn credentials_remaining(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|at| at.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    pub fn with_checksum(mut self, checksum: ChecksumAlgo) -> Self {
+        self.checksum = checksum;
+        // The SDK otherwise adds a CRC32 of its own to every upload.
+        if checksum == ChecksumAlgo::None {
+            let config = self
+                .client
+                .config()
+                .to_builder()
+                .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+                .build();
+            self.client = aws_sdk_s3::Client::from_conf(config);
+        }
+        self
+    }
+
+    /// `doctor`: makes each kind of S3 call a backup does, writing, reading
+    /// and deleting a throwaway object at `probe_key`. The probe object
+    /// gets no Object Lock retention, so it can always be deleted again;
+    /// with `check_lock`, reading the bucket's lock configuration is tried
+    /// instead. Stops after `HeadBucket` if the bucket isn't there at all.
+    pub async fn probe(&self, probe_key: &str, check_lock: bool) -> Vec<Probe> {
+        let mut probes = Vec::new();
+        let head = self.client.head_bucket().bucket(&self.bucket).send().await;
+        let missing = matches!(head, Err(ref e) if e.raw_response().is_some_and(|r| r.status().as_u16() != 403));
+        probes.push(Probe {
+            action: "s3:ListBucket",
+            call: "HeadBucket",
+            optional: false,
+            result: head.map(|_| None).map_err(|e| ProbeError::from_sdk(&e)),
+        });
+        if missing {
+            return probes;
+        }
+
+        let listed = self.client.list_buckets().send().await;
+        probes.push(Probe {
+            action: "s3:ListAllMyBuckets",
+            call: "ListBuckets",
+            optional: true,
+            result: listed
+                .map(|r| {
+                    let names: Vec<&str> = r.buckets().iter().filter_map(|b| b.name()).collect();
+                    Some(format!(
+                        "{} bucket(s) visible{}",
+                        names.len(),
+                        if names.contains(&self.bucket.as_str()) {
+                            ", including this one"
+                        } else {
+                            ""
+                        }
+                    ))
+                })
+                .map_err(|e| ProbeError::from_sdk(&e)),
+        });
+
+        if check_lock {
+            let lock = self
+                .client
+                .get_object_lock_configuration()
+                .bucket(&self.bucket)
+                .send()
+                .await;
+            probes.push(Probe {
+                action: "s3:GetBucketObjectLockConfiguration",
+                call: "GetObjectLockConfiguration",
+                optional: false,
+                result: lock
+                    .map(|r| {
+                        let enabled = r
+                            .object_lock_configuration()
+                            .and_then(|c| c.object_lock_enabled())
+                            == Some(&ObjectLockEnabled::Enabled);
+                        Some(
+                            if enabled {
+                                "Object Lock enabled"
+                            } else {
+                                "Object Lock NOT enabled"
+                            }
+                            .to_string(),
+                        )
+                    })
+                    .map_err(|e| ProbeError::from_sdk(&e)),
+            });
+        }
+
+        let put = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(probe_key)
+            .content_type("text/plain")
+            .body(ByteStream::from_static(
+                b"google-photos-backup doctor probe\n",
+            ))
+            .send()
+            .await;
+        let written = put.is_ok();
+        probes.push(Probe {
+            action: "s3:PutObject",
+            call: "PutObject",
+            optional: false,
+            result: put.map(|_| None).map_err(|e| ProbeError::from_sdk(&e)),
+        });
+        if !written {
+            return probes;
+        }
+
+        if let Some(ref acl) = self.acl {
+            let set = self
+                .client
+                .put_object_acl()
+                .bucket(&self.bucket)
+                .key(probe_key)
+                .acl(acl.clone())
+                .send()
+                .await;
+            probes.push(Probe {
+                action: "s3:PutObjectAcl",
+                call: "PutObjectAcl",
+                optional: false,
+                result: set.map(|_| None).map_err(|e| ProbeError::from_sdk(&e)),
+            });
+        }
+
+        let read = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(probe_key)
+            .send()
+            .await;
+        probes.push(Probe {
+            action: "s3:GetObject",
+            call: "HeadObject",
+            optional: false,
+            result: read
+                .map(|h| {
+                    h.storage_class()
+                        .map(|c| format!("stored as {}", c.as_str()))
+                })
+                .map_err(|e| ProbeError::from_sdk(&e)),
+        });
 
-        prefixes.sort();
-        Ok(prefixes)
+        let deleted = self
+            .client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(probe_key)
+            .send()
+            .await;
+        probes.push(Probe {
+            action: "s3:DeleteObject",
+            call: "DeleteObject",
+            optional: false,
+            result: deleted.map(|_| None).map_err(|e| ProbeError::from_sdk(&e)),
+        });
+        probes
+    }
+
+    /// Returns the date prefixes directly under `root` (e.g. ["2024-01-01/",
+    /// "2024-02-01/"], each starting with `root`) sorted ascending. Prefixes
+    /// not shaped like a `--prefix-format` one are left out.
+    pub async fn list_backup_prefixes(&self, root: &str) -> Result<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut req = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(root)
+                .delimiter("/");
+
+            if let Some(ref token) = continuation_token {
+                req = req.continuation_token(token);
+            }
+
+            let page = req.send().await.context("S3 ListObjectsV2 failed")?;
+            let truncated = page.is_truncated().unwrap_or(false);
+            continuation_token = page.next_continuation_token().map(|s| s.to_string());
+            pages.push(page);
+            if !truncated {
+                break;
+            }
+        }
+
+        Ok(backup_prefixes(root, &pages))
+    }
+
+    /// Lists every object under `prefix` (e.g. "2024-01-01/").
+    pub async fn list_objects(&self, prefix: &str) -> Result<Vec<S3Object>> {
+        let mut objects = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut req = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix);
+
+            if let Some(ref token) = continuation_token {
+                req = req.continuation_token(token);
+            }
+
+            let page = req.send().await.context("S3 ListObjectsV2 failed")?;
+
+            objects.extend(page.contents().iter().filter_map(|o| {
+                Some(S3Object {
+                    key: o.key()?.to_string(),
+                    size: o.size().unwrap_or(0) as u64,
+                    etag: o.e_tag().map(|t| t.trim_matches('"').to_string()),
+                })
+            }));
+
+            if page.is_truncated().unwrap_or(false) {
+                continuation_token = page.next_continuation_token().map(|s| s.to_string());
+            } else {
+                break;
+            }
+        }
+
+        Ok(objects)
     }
 
     /// Deletes all objects under `prefix` (e.g. "2024-01-01/").
@@ -124,154 +933,681 @@ impl S3Uploader {
         Ok(deleted_count)
     }
 
-    pub async fn upload(&self, key: &str, path: &Path) -> Result<()> {
+    /// Writes a small object of `content_type` at `key`. With `if_absent`,
+    /// the write only succeeds if nothing is there yet (`If-None-Match: *`),
+    /// and `false` is returned if something was.
+    pub async fn put_small(
+        &self,
+        key: &str,
+        body: String,
+        content_type: &str,
+        metadata: &[(&str, String)],
+        if_absent: bool,
+    ) -> Result<bool> {
+        let mut req = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_type(content_type)
+            .body(ByteStream::from(body.into_bytes()));
+        for (name, value) in metadata {
+            req = req.metadata(*name, value);
+        }
+        if if_absent {
+            req = req.if_none_match("*");
+        }
+        match req.send().await {
+            Ok(_) => Ok(true),
+            // 412 when the object exists; 409 when a concurrent conditional
+            // write to the same key won.
+            Err(e)
+                if e.raw_response()
+                    .is_some_and(|r| matches!(r.status().as_u16(), 409 | 412)) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e).with_context(|| format!("S3 PutObject failed for key: {key}")),
+        }
+    }
+
+    /// When the object at `key` was last written, or `None` if there's no
+    /// such object. Read from a listing, since our objects can't be read
+    /// back once archived.
+    pub async fn last_modified(&self, key: &str) -> Result<Option<DateTime<Utc>>> {
+        let page = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(key)
+            .max_keys(1)
+            .send()
+            .await
+            .context("S3 ListObjectsV2 failed")?;
+        Ok(page
+            .contents()
+            .iter()
+            .find(|o| o.key() == Some(key))
+            .and_then(|o| o.last_modified())
+            .and_then(|t| DateTime::from_timestamp(t.secs(), 0)))
+    }
+
+    /// The object at `key` as S3 has it now, or `None` if there's no such
+    /// object. Works on archived objects too: only metadata is read.
+    pub async fn head(&self, key: &str) -> Result<Option<S3Object>> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(head) => Ok(Some(S3Object {
+                key: key.to_string(),
+                size: head.content_length().unwrap_or(0) as u64,
+                etag: head.e_tag().map(|t| t.trim_matches('"').to_string()),
+            })),
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 404) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("S3 HeadObject failed for key: {key}")),
+        }
+    }
+
+    /// When the object at `key` was last written and its user metadata, or
+    /// `None` if there's no such object. Unlike the body, both can still be
+    /// read once the object is archived.
+    pub async fn head_metadata(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Option<DateTime<Utc>>, HashMap<String, String>)>> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(head) => Ok(Some((
+                head.last_modified()
+                    .and_then(|t| DateTime::from_timestamp(t.secs(), 0)),
+                head.metadata().cloned().unwrap_or_default(),
+            ))),
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 404) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("S3 HeadObject failed for key: {key}")),
+        }
+    }
+
+    /// Whether the object at `key` can be read now, along with its
+    /// `Content-Type`. Objects the lifecycle moved to Glacier or Deep Archive
+    /// need a retrieval first (`request_retrieval`).
+    pub async fn retrieval_state(&self, key: &str) -> Result<(Retrieval, Option<String>)> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .with_context(|| format!("S3 HeadObject failed for key: {key}"))?;
+        let archived = matches!(
+            head.storage_class(),
+            Some(StorageClass::Glacier | StorageClass::DeepArchive)
+        );
+        // `ongoing-request="false", expiry-date="..."` once a retrieved copy is ready.
+        let state = match head.restore() {
+            _ if !archived => Retrieval::Ready,
+            Some(r) if r.contains("ongoing-request=\"true\"") => Retrieval::InProgress,
+            Some(_) => Retrieval::Ready,
+            None => Retrieval::Archived,
+        };
+        Ok((state, head.content_type().map(String::from)))
+    }
+
+    /// Starts retrieving an archived object, keeping the readable copy for
+    /// `days`. The bulk tier is cheapest and takes up to 48 hours from Deep
+    /// Archive. Asking again while a retrieval is running is not an error.
+    pub async fn request_retrieval(&self, key: &str, days: i32, tier: Tier) -> Result<()> {
+        let request = RestoreRequest::builder()
+            .days(days)
+            .glacier_job_parameters(GlacierJobParameters::builder().tier(tier).build()?)
+            .build();
+        match self
+            .client
+            .restore_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .restore_request(request)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 409) => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("S3 RestoreObject failed for key: {key}")),
+        }
+    }
+
+    /// Downloads the object at `key` into `dest`, returning its size.
+    pub async fn download(&self, key: &str, dest: &Path) -> Result<u64> {
+        let mut body = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .with_context(|| format!("S3 GetObject failed for key: {key}"))?
+            .body;
+        let mut file = File::create(dest)
+            .await
+            .with_context(|| format!("Cannot create {}", dest.display()))?;
+        let mut written = 0u64;
+        while let Some(chunk) = body.try_next().await? {
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
+    }
+
+    pub async fn delete_object(&self, key: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .with_context(|| format!("S3 DeleteObject failed for key: {key}"))?;
+        Ok(())
+    }
+
+    /// Uploads `path` to `key`, tagging the object with `content_type` when
+    /// given and with any user `metadata`. `checksum` is the file's, when it
+    /// was computed as the file was written (see `Hasher`); otherwise the
+    /// file is read once more for it.
+    pub async fn upload(
+        &self,
+        key: &str,
+        path: &Path,
+        checksum: Option<String>,
+        content_type: Option<&str>,
+        metadata: &[(&str, String)],
+    ) -> Result<()> {
         let file_size = tokio::fs::metadata(path)
             .await
             .with_context(|| format!("Cannot stat file: {}", path.display()))?
             .len();
 
         if file_size <= MULTIPART_THRESHOLD {
-            self.put_object(key, path).await
+            let checksum = match checksum {
+                Some(checksum) => Some(checksum),
+                None => self.checksum.file_digest(path).await?,
+            };
+            let body = ByteStream::from_path(path)
+                .await
+                .with_context(|| format!("Cannot read file: {}", path.display()))?;
+            self.put_object(key, body, file_size, checksum, content_type, metadata)
+                .await
         } else {
-            self.multipart_upload(key, path).await
+            self.multipart_upload(key, path, file_size, content_type, metadata)
+                .await
         }
     }
 
-    async fn put_object(&self, key: &str, path: &Path) -> Result<()> {
-        let body = ByteStream::from_path(path)
-            .await
-            .with_context(|| format!("Cannot read file: {}", path.display()))?;
+    /// Uploads `body`, already in memory, to `key` with a single `PutObject`.
+    /// For files no larger than `MULTIPART_THRESHOLD`.
+    pub async fn upload_bytes(
+        &self,
+        key: &str,
+        body: Bytes,
+        content_type: Option<&str>,
+        metadata: &[(&str, String)],
+    ) -> Result<()> {
+        let checksum = self.checksum.digest(&body);
+        let size = body.len() as u64;
+        self.put_object(
+            key,
+            ByteStream::from(body),
+            size,
+            checksum,
+            content_type,
+            metadata,
+        )
+        .await
+    }
 
-        self.client
+    /// A single `PutObject` of `body`. S3 rejects the object if its content
+    /// doesn't match `checksum`.
+    async fn put_object(
+        &self,
+        key: &str,
+        body: ByteStream,
+        size: u64,
+        checksum: Option<String>,
+        content_type: Option<&str>,
+        metadata: &[(&str, String)],
+    ) -> Result<()> {
+        let mut req = self
+            .client
             .put_object()
             .bucket(&self.bucket)
             .key(key)
-            .body(body)
-            .send()
-            .await
-            .with_context(|| format!("S3 PutObject failed for key: {key}"))?;
+            .set_content_type(content_type.map(String::from))
+            .set_storage_class(self.storage_class(Some(size)))
+            .set_acl(self.acl.clone())
+            .set_object_lock_mode(self.lock.as_ref().map(|l| l.mode.clone()))
+            .set_object_lock_retain_until_date(self.lock.as_ref().map(ObjectLock::retain_until))
+            .set_if_none_match(self.if_none_match())
+            .set_metadata(user_metadata(metadata));
+        req = match self.checksum {
+            ChecksumAlgo::Crc32c => req.set_checksum_crc32_c(checksum),
+            ChecksumAlgo::Sha256 => req.set_checksum_sha256(checksum),
+            ChecksumAlgo::Md5 => req.set_content_md5(checksum),
+            ChecksumAlgo::None => req,
+        };
+        let result = req.body(body).send().await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) if self.refused_overwrite(&e) => Err(AlreadyExists {
+                key: key.to_string(),
+            }
+            .into()),
+            Err(e) => Err(e).with_context(|| format!("S3 PutObject failed for key: {key}")),
+        }
+    }
 
-        Ok(())
+    async fn multipart_upload(
+        &self,
+        key: &str,
+        path: &Path,
+        size: u64,
+        content_type: Option<&str>,
+        metadata: &[(&str, String)],
+    ) -> Result<()> {
+        let upload_id = self
+            .begin_multipart(key, Some(size), content_type, metadata)
+            .await?;
+
+        // Abort the multipart upload on any failure so we don't leave
+        // orphaned parts accumulating storage charges.
+        match self.upload_parts(key, path, &upload_id).await {
+            Ok(completed_parts) => {
+                self.complete_multipart(key, &upload_id, completed_parts)
+                    .await
+            }
+            Err(e) => {
+                self.abort_multipart(key, &upload_id).await;
+                Err(e)
+            }
+        }
     }
 
-    async fn multipart_upload(&self, key: &str, path: &Path) -> Result<()> {
-        // 1. Initiate the multipart upload.
+    /// Uploads `body` to `key` as it arrives, without staging it on disk,
+    /// and returns the number of bytes sent. Always a multipart upload, since
+    /// the size isn't known until the stream ends. When `expected` is given
+    /// and the count differs once the upload completes, the object is
+    /// deleted again and an error returned, so a short read never passes as
+    /// a finished archive.
+    pub async fn upload_stream(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        body: impl Stream<Item = Result<Bytes>>,
+        expected: Option<u64>,
+        metadata: &[(&str, String)],
+    ) -> Result<u64> {
+        let upload_id = self
+            .begin_multipart(key, expected, content_type, metadata)
+            .await?;
+        let sent = match self.upload_stream_parts(key, &upload_id, body).await {
+            Ok((parts, sent)) => {
+                self.complete_multipart(key, &upload_id, parts).await?;
+                sent
+            }
+            Err(e) => {
+                self.abort_multipart(key, &upload_id).await;
+                return Err(e);
+            }
+        };
+
+        if let Some(expected) = expected.filter(|&n| n != sent) {
+            let _ = self
+                .client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await;
+            anyhow::bail!(
+                "Incomplete transfer: expected {expected} bytes, sent {sent} bytes to S3 (object removed)"
+            );
+        }
+        Ok(sent)
+    }
+
+    /// Cuts `body` into `PART_SIZE` parts and uploads each as it fills.
+    async fn upload_stream_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        body: impl Stream<Item = Result<Bytes>>,
+    ) -> Result<(Vec<CompletedPart>, u64)> {
+        let mut body = std::pin::pin!(body);
+        let mut parts = Vec::new();
+        let mut buf = BytesMut::with_capacity(PART_SIZE);
+        let mut sent = 0u64;
+
+        loop {
+            match body.next().await.transpose()? {
+                Some(chunk) => {
+                    buf.extend_from_slice(&chunk);
+                    while buf.len() >= PART_SIZE {
+                        let part = buf.split_to(PART_SIZE).freeze();
+                        sent += part.len() as u64;
+                        let number = parts.len() as i32 + 1;
+                        parts.push(self.put_part(key, upload_id, number, part).await?);
+                    }
+                }
+                None => {
+                    // The remainder is the last part (S3 needs at least one,
+                    // even if empty).
+                    if !buf.is_empty() || parts.is_empty() {
+                        let part = buf.split().freeze();
+                        sent += part.len() as u64;
+                        let number = parts.len() as i32 + 1;
+                        parts.push(self.put_part(key, upload_id, number, part).await?);
+                    }
+                    return Ok((parts, sent));
+                }
+            }
+        }
+    }
+
+    async fn begin_multipart(
+        &self,
+        key: &str,
+        size: Option<u64>,
+        content_type: Option<&str>,
+        metadata: &[(&str, String)],
+    ) -> Result<String> {
         let create = self
             .client
             .create_multipart_upload()
             .bucket(&self.bucket)
             .key(key)
+            .set_content_type(content_type.map(String::from))
+            .set_storage_class(self.storage_class(size))
+            .set_acl(self.acl.clone())
+            .set_object_lock_mode(self.lock.as_ref().map(|l| l.mode.clone()))
+            .set_object_lock_retain_until_date(self.lock.as_ref().map(ObjectLock::retain_until))
+            .set_checksum_algorithm(self.checksum.algorithm())
+            .set_metadata(user_metadata(metadata))
             .send()
             .await
             .with_context(|| format!("Failed to initiate multipart upload for {key}"))?;
 
-        let upload_id = create
+        Ok(create
             .upload_id()
             .context("No upload_id in CreateMultipartUpload response")?
-            .to_string();
+            .to_string())
+    }
 
-        // 2. Upload parts, aborting the multipart upload on any failure so we
-        //    don't leave orphaned parts accumulating storage charges.
-        match self.upload_parts(key, path, &upload_id).await {
-            Ok(completed_parts) => {
-                // 3. Complete.
-                let completed = CompletedMultipartUpload::builder()
-                    .set_parts(Some(completed_parts))
-                    .build();
+    /// Completes the upload and checks the object's composite checksum (the
+    /// checksum of the part checksums) against the parts we sent. On a
+    /// mismatch the object is deleted and an error returned.
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<()> {
+        let mut part_hashes = Vec::with_capacity(parts.len() * 32);
+        if self.checksum.algorithm().is_some() {
+            for part in &parts {
+                let hash = match self.checksum {
+                    ChecksumAlgo::Crc32c => part.checksum_crc32_c(),
+                    _ => part.checksum_sha256(),
+                }
+                .context("Part sent without a checksum")?;
+                part_hashes.extend(STANDARD.decode(hash)?);
+            }
+        }
+        let expected = self.checksum.digest(&part_hashes);
 
-                self.client
-                    .complete_multipart_upload()
-                    .bucket(&self.bucket)
-                    .key(key)
-                    .upload_id(&upload_id)
-                    .multipart_upload(completed)
-                    .send()
-                    .await
-                    .with_context(|| format!("Failed to complete multipart upload for {key}"))?;
+        let completed = CompletedMultipartUpload::builder()
+            .set_parts(Some(parts))
+            .build();
 
-                Ok(())
+        let response = match self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(completed)
+            .set_if_none_match(self.if_none_match())
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            // The parts would otherwise be billed until the bucket's
+            // lifecycle cleans them up.
+            Err(e) if self.refused_overwrite(&e) => {
+                self.abort_multipart(key, upload_id).await;
+                return Err(AlreadyExists {
+                    key: key.to_string(),
+                }
+                .into());
             }
             Err(e) => {
-                // Best-effort abort to clean up any uploaded parts.
-                let _ = self
-                    .client
-                    .abort_multipart_upload()
-                    .bucket(&self.bucket)
-                    .key(key)
-                    .upload_id(&upload_id)
-                    .send()
-                    .await;
+                return Err(e)
+                    .with_context(|| format!("Failed to complete multipart upload for {key}"))
+            }
+        };
 
-                Err(e)
+        // `<base64>-<part count>`. Left unchecked when the store doesn't
+        // report one (some S3-compatible services).
+        let got = match self.checksum {
+            ChecksumAlgo::Crc32c => response.checksum_crc32_c(),
+            ChecksumAlgo::Sha256 => response.checksum_sha256(),
+            ChecksumAlgo::Md5 | ChecksumAlgo::None => None,
+        };
+        if let (Some(got), Some(expected)) = (got, expected) {
+            let got = got.split('-').next().unwrap_or(got);
+            if got != expected {
+                let _ = self.delete_object(key).await;
+                anyhow::bail!(
+                    "Checksum mismatch for {key}: S3 computed {} {got}, expected {expected} (object removed)",
+                    self.checksum.name()
+                );
             }
         }
+        Ok(())
+    }
+
+    /// Best-effort abort to clean up any uploaded parts.
+    async fn abort_multipart(&self, key: &str, upload_id: &str) {
+        let _ = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await;
+    }
+
+    async fn put_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Bytes,
+    ) -> Result<CompletedPart> {
+        // S3 rejects the part if its content doesn't match.
+        let checksum = self.checksum.digest(&data);
+        let req = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number);
+        let req = match self.checksum {
+            ChecksumAlgo::Crc32c => req.set_checksum_crc32_c(checksum.clone()),
+            ChecksumAlgo::Sha256 => req.set_checksum_sha256(checksum.clone()),
+            ChecksumAlgo::Md5 => req.set_content_md5(checksum.clone()),
+            ChecksumAlgo::None => req,
+        };
+        let part = req
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .with_context(|| format!("Failed to upload part {part_number} of {key}"))?;
+
+        let etag = part
+            .e_tag()
+            .context("No ETag in UploadPart response")?
+            .to_string();
+
+        let completed = CompletedPart::builder()
+            .part_number(part_number)
+            .e_tag(etag);
+        Ok(match self.checksum {
+            ChecksumAlgo::Crc32c => completed.set_checksum_crc32_c(checksum),
+            ChecksumAlgo::Sha256 => completed.set_checksum_sha256(checksum),
+            ChecksumAlgo::Md5 | ChecksumAlgo::None => completed,
+        }
+        .build())
     }
 
+    /// Uploads `path` in `PART_SIZE` parts, up to `part_concurrency` at
+    /// once, each read from its offset in the file. A part that fails is
+    /// retried on its own; one that keeps failing fails the whole upload,
+    /// and the caller aborts it.
     async fn upload_parts(
         &self,
         key: &str,
         path: &Path,
         upload_id: &str,
     ) -> Result<Vec<CompletedPart>> {
-        let mut file = File::open(path)
+        let size = tokio::fs::metadata(path)
             .await
-            .with_context(|| format!("Cannot open file: {}", path.display()))?;
+            .with_context(|| format!("Cannot stat file: {}", path.display()))?
+            .len();
+        // S3 needs at least one part, even if empty.
+        let parts = size.div_ceil(PART_SIZE as u64).max(1);
 
-        let mut completed_parts = Vec::new();
-        let mut part_number = 1i32;
-        let mut buf = vec![0u8; PART_SIZE];
+        stream::iter(0..parts)
+            .map(|i| async move {
+                let offset = i * PART_SIZE as u64;
+                let len = (size - offset).min(PART_SIZE as u64) as usize;
+                let part_number = i as i32 + 1;
+                // Read again on each attempt rather than holding the part
+                // through the backoff.
+                retry(|| async {
+                    let data = read_part(path, offset, len).await?;
+                    self.put_part(key, upload_id, part_number, data).await
+                })
+                .await
+            })
+            .buffered(self.part_concurrency)
+            .try_collect()
+            .await
+    }
+}
 
-        loop {
-            // Fill the buffer fully (or until EOF).
-            let mut bytes_read = 0;
-            loop {
-                let n = file.read(&mut buf[bytes_read..]).await?;
-                if n == 0 {
-                    break; // EOF
-                }
-                bytes_read += n;
-                if bytes_read == PART_SIZE {
-                    break; // Buffer full — more data may follow.
-                }
-            }
+/// `len` bytes of `path` from `offset`.
+async fn read_part(path: &Path, offset: u64, len: usize) -> Result<Bytes> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Cannot open file: {}", path.display()))?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut buf = vec![0u8; len];
+    file.read_exact(&mut buf)
+        .await
+        .with_context(|| format!("Cannot read {} at offset {offset}", path.display()))?;
+    Ok(Bytes::from(buf))
+}
 
-            if bytes_read == 0 {
-                break; // Nothing left to upload.
-            }
+/// The backup prefixes among every page of a delimited listing of `root`,
+/// sorted. Pages come oldest first, so all of them are needed to know which
+/// backups are the newest.
+fn backup_prefixes(root: &str, pages: &[ListObjectsV2Output]) -> Vec<String> {
+    let mut prefixes: Vec<String> = pages
+        .iter()
+        .flat_map(|page| page.common_prefixes())
+        .filter_map(|p| p.prefix())
+        .filter(|p| {
+            p.strip_prefix(root)
+                .and_then(|name| name.strip_suffix('/'))
+                .and_then(PrefixFormat::date_of)
+                .is_some()
+        })
+        .map(String::from)
+        .collect();
 
-            let body = ByteStream::from(Bytes::copy_from_slice(&buf[..bytes_read]));
+    prefixes.sort();
+    prefixes
+}
 
-            let part = self
-                .client
-                .upload_part()
-                .bucket(&self.bucket)
-                .key(key)
-                .upload_id(upload_id)
-                .part_number(part_number)
-                .body(body)
-                .send()
-                .await
-                .with_context(|| format!("Failed to upload part {part_number} of {key}"))?;
-
-            let etag = part
-                .e_tag()
-                .context("No ETag in UploadPart response")?
-                .to_string();
-
-            completed_parts.push(
-                CompletedPart::builder()
-                    .part_number(part_number)
-                    .e_tag(etag)
-                    .build(),
-            );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            part_number += 1;
+    #[test]
+    fn crc32c_matches_the_check_value() {
+        // The standard CRC-32C check value for "123456789" is 0xE3069283.
+        let expected = STANDARD.encode(0xE306_9283u32.to_be_bytes());
+        assert_eq!(ChecksumAlgo::Crc32c.digest(b"123456789"), Some(expected));
+    }
 
-            if bytes_read < PART_SIZE {
-                break; // Last part — we've read everything.
+    #[test]
+    fn hashing_in_chunks_matches_hashing_at_once() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        for algo in [
+            ChecksumAlgo::Crc32c,
+            ChecksumAlgo::Sha256,
+            ChecksumAlgo::Md5,
+        ] {
+            let mut hasher = algo.hasher();
+            for chunk in data.chunks(4096) {
+                hasher.update(chunk);
             }
+            assert_eq!(hasher.finish(), algo.digest(&data), "{}", algo.name());
         }
+        assert_eq!(ChecksumAlgo::None.digest(&data), None);
+    }
+
+    #[test]
+    fn backup_prefixes_span_every_page() {
+        use aws_sdk_s3::types::CommonPrefix;
+
+        let page = |names: Vec<String>| {
+            names
+                .into_iter()
+                .fold(ListObjectsV2Output::builder(), |page, name| {
+                    page.common_prefixes(CommonPrefix::builder().prefix(name).build())
+                })
+                .build()
+        };
+        let day = |n: u64| {
+            let date = chrono::NaiveDate::from_ymd_opt(2020, 1, 1).unwrap() + chrono::Days::new(n);
+            format!("host-a/{date}/")
+        };
+        // S3 returns at most 1000 common prefixes a page, in key order.
+        let pages = [
+            page((0..1000).map(day).collect()),
+            page(vec![day(1000), day(1001), "host-a/misc/".into()]),
+        ];
 
-        Ok(completed_parts)
+        let prefixes = backup_prefixes("host-a/", &pages);
+        assert_eq!(prefixes.len(), 1002);
+        assert_eq!(prefixes.first(), Some(&day(0)));
+        assert_eq!(prefixes.last(), Some(&day(1001)));
+        // So pruning keeps the newest days, not the newest of the first page.
+        let old = crate::old_backups(&prefixes, "host-a/", 3);
+        assert_eq!(old.len(), 999);
+        assert!(!old.contains(&&day(1001)) && old.contains(&&day(998)));
     }
 }
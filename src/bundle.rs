@@ -0,0 +1,425 @@
+use crate::drive::{self, DriveFile};
+use crate::failures::{self, Failure, Stage};
+use crate::manifest::{self, Manifest, ManifestEntry, Trashed};
+use crate::progress;
+use crate::retry::retry;
+use crate::transfer::{self, download_style, object_name, Context, RunStats};
+use anyhow::{Context as _, Result};
+use async_compression::tokio::write::ZstdEncoder;
+use bytes::Bytes;
+use chrono::Utc;
+use futures::stream;
+use indicatif::{HumanBytes, ProgressBar};
+use std::path::Path;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// Name of the single object a `--bundle` run uploads, before any
+/// compression suffix.
+pub const BUNDLE_NAME: &str = "takeout-bundle.tar";
+
+/// Name of the manifest stored as the last entry of the tar.
+const MANIFEST_ENTRY: &str = "manifest.json";
+
+/// Tar blocks are 512 bytes; headers take one, content is padded to a whole
+/// number of them.
+const BLOCK: usize = 512;
+
+/// How much of a file is read (and handed to the encoder) at a time.
+const READ_CHUNK: usize = 1024 * 1024;
+
+/// `--bundle` — downloads every file in turn and streams them as one tar
+/// (zstd-compressed unless `--bundle-compression none`) into a single
+/// multipart upload at `<prefix>/takeout-bundle.tar.zst`, followed by the
+/// run's manifest. Files are deleted from Drive only once the whole bundle
+/// is stored. A file that fails to download is left out and stays in Drive.
+pub async fn run(ctx: &Context<'_>, files: &[DriveFile]) -> Result<RunStats> {
+    let config = ctx.config;
+    let compress = config.bundle_compression == "zstd";
+    let suffix = if compress { ".zst" } else { "" };
+    // A later run on the same day gets its own bundle rather than replacing
+    // one whose files are already gone from Drive.
+    let mut key = format!("{}/{BUNDLE_NAME}{suffix}", ctx.date_prefix);
+    let stem = BUNDLE_NAME.trim_end_matches(".tar");
+    for n in 2.. {
+        if ctx.s3.last_modified(&key).await?.is_none() {
+            break;
+        }
+        key = format!("{}/{stem}-{n}.tar{suffix}", ctx.date_prefix);
+    }
+    let key = key;
+    let content_type = if compress {
+        "application/zstd"
+    } else {
+        "application/x-tar"
+    };
+    println!("Bundling into s3://{}/{key}\n", ctx.bucket);
+
+    // A few chunks in flight: the upload drains what the tar side produces.
+    let (tx, mut rx) = mpsc::channel::<Result<Bytes>>(4);
+    let body = stream::poll_fn(move |cx| rx.poll_recv(cx));
+    let upload = ctx
+        .s3
+        .upload_stream(&key, Some(content_type), body, None, &[]);
+
+    let started = Instant::now();
+    let mut stats = RunStats::default();
+    // This bundle's entries; a copy ends the tar.
+    let mut bundled = Manifest::new(ctx.date_prefix);
+    let mut failures = Vec::new();
+    let write = async {
+        // Owned here so the upload sees the end of the body once we're done.
+        let tx = tx;
+        let result = async {
+            let mut sink = Sink::new(compress);
+            for file in files {
+                if config.deadline.is_some_and(|d| Instant::now() >= d) {
+                    stats.out_of_time = true;
+                    stats.not_started = files.len() - stats.started;
+                    break;
+                }
+                stats.started += 1;
+                let began = Instant::now();
+                let entry_name = object_name(config, file);
+                let written = match fetch(ctx, file).await {
+                    Ok(local) => {
+                        let appended = append_file(&mut sink, &tx, &entry_name, &local).await;
+                        if file.local_path.is_none() {
+                            let _ = tokio::fs::remove_file(&local).await;
+                        }
+                        // The tar is unusable past a failed write, so give up.
+                        appended?;
+                        true
+                    }
+                    Err(e) => {
+                        println!("  ✗ {} — download error: {e:#}", file.name);
+                        if let Err(e) =
+                            transfer::discard_failed(config, file, &work_path(ctx, file)).await
+                        {
+                            eprintln!("  Warning: could not keep failed temp file: {e:#}");
+                        }
+                        failures.push(Failure {
+                            file_id: file.delete_id().to_string(),
+                            name: file.name.clone(),
+                            stage: Stage::Download,
+                            error: format!("{e:#}"),
+                        });
+                        stats.failed += 1;
+                        false
+                    }
+                };
+                if written {
+                    println!("  + {entry_name}");
+                    stats.bytes_uploaded += file.size_bytes().unwrap_or(0);
+                    stats.time(file, began.elapsed());
+                    bundled.record(ManifestEntry {
+                        file_id: file.id.clone(),
+                        shortcut_id: file.shortcut_id.clone(),
+                        name: file.name.clone(),
+                        key: format!("{key}/{entry_name}"),
+                        size: file.size_bytes(),
+                        md5: file.md5_checksum.clone(),
+                        uploaded_at: Utc::now(),
+                        deleted_from_drive: false,
+                        duplicate_of: None,
+                        trashed: None,
+                        bundle: Some(key.clone()),
+                        revision: None,
+                    });
+                }
+            }
+
+            // The copy inside the bundle is as of bundling: nothing deleted yet.
+            let json = serde_json::to_vec_pretty(&bundled)?;
+            sink.write(&header(
+                MANIFEST_ENTRY,
+                json.len() as u64,
+                Utc::now().timestamp(),
+            ))
+            .await?;
+            sink.write(&json).await?;
+            sink.write(&padding(json.len() as u64)).await?;
+            // End of archive: two zero blocks.
+            sink.write(&[0; 2 * BLOCK]).await?;
+            send(&tx, sink.finish().await?).await
+        }
+        .await;
+        if let Err(ref e) = result {
+            // Fails the upload, which aborts it rather than storing a
+            // truncated tar.
+            let _ = tx.send(Err(anyhow::anyhow!("{e:#}"))).await;
+        }
+        result
+    };
+    let (written, uploaded) = tokio::join!(write, upload);
+    written?;
+    let sent = uploaded?;
+    stats.elapsed = started.elapsed();
+    println!(
+        "\nBundle stored: {} file(s), {} ({} in S3)",
+        bundled.files.len(),
+        HumanBytes(stats.bytes_uploaded),
+        HumanBytes(sent)
+    );
+    stats.uploaded = bundled.files.len();
+
+    // Only now is everything archived, so only now remove it from Drive.
+    if let Some(drive) = ctx.drive.filter(|_| !config.keep_in_drive) {
+        let trash = config.delete_after.is_some();
+        for entry in &mut bundled.files {
+            let Some(file) = files.iter().find(|f| f.id == entry.file_id) else {
+                continue;
+            };
+            let removed = if !file.can_remove(trash) {
+                Err(anyhow::anyhow!(
+                    "no {} permission in Drive",
+                    if trash { "trash" } else { "delete" }
+                ))
+            } else if trash {
+                drive.trash(file.delete_id()).await
+            } else {
+                drive.delete(file.delete_id()).await
+            };
+            match removed {
+                Ok(()) => {
+                    stats.note_removed(file);
+                    entry.deleted_from_drive = true;
+                    entry.trashed = trash.then(|| Trashed {
+                        id: file.delete_id().to_string(),
+                        at: Utc::now(),
+                        purged_at: None,
+                    });
+                }
+                Err(e) => {
+                    let denied = drive::permission_denied(&e);
+                    match denied {
+                        Some(why) => eprintln!(
+                            "  Warning: {} archived but cannot be deleted: {}",
+                            file.name,
+                            why.advice()
+                        ),
+                        None => eprintln!(
+                            "  Warning: {} archived but not removed from Drive: {e:#}",
+                            file.name
+                        ),
+                    }
+                    failures.push(Failure {
+                        file_id: file.delete_id().to_string(),
+                        name: file.name.clone(),
+                        stage: Stage::Delete,
+                        error: format!("{e:#}"),
+                    });
+                    stats.note_not_deleted(denied);
+                }
+            }
+        }
+    }
+
+    // Earlier runs on the same day share the prefix, so extend their manifest.
+    let manifest_path = manifest::path_for(&config.manifest_dir, ctx.date_prefix);
+    let mut manifest = Manifest::load(&manifest_path)
+        .await?
+        .unwrap_or_else(|| Manifest::new(ctx.date_prefix));
+    for entry in bundled.files {
+        manifest.record(entry);
+    }
+    if let Err(e) = manifest.save(&manifest_path).await {
+        eprintln!("Warning: could not save manifest: {e:#}");
+    }
+    let failures_path = failures::path_for(&config.manifest_dir, ctx.date_prefix);
+    if failures.is_empty() {
+        let _ = tokio::fs::remove_file(&failures_path).await;
+    } else if let Err(e) = failures::write(&failures_path, &failures).await {
+        eprintln!("Warning: could not write failures CSV: {e:#}");
+    }
+    Ok(stats)
+}
+
+/// The local copy of `file` to put in the tar: its test-mode path, or a
+/// fresh download into the work directory.
+async fn fetch(ctx: &Context<'_>, file: &DriveFile) -> Result<std::path::PathBuf> {
+    if let Some(ref local) = file.local_path {
+        return Ok(local.clone());
+    }
+    let drive = ctx.drive.context("Nothing to download from")?;
+    let path = ctx.tmp_dir.join(format!(
+        "bundle-{}",
+        transfer::sanitize_filename(&file.name)
+    ));
+    let _ = tokio::fs::remove_file(&path).await;
+    let bar = ProgressBar::with_draw_target(Some(0), progress::draw_target());
+    bar.set_style(download_style()?);
+    bar.set_message(file.name.clone());
+    let result = retry(|| async {
+        bar.reset();
+        drive.download(file, &path, &bar).await
+    })
+    .await;
+    bar.finish_and_clear();
+    result.map(|_| path)
+}
+
+/// Where `fetch` downloads `file`.
+fn work_path(ctx: &Context<'_>, file: &DriveFile) -> std::path::PathBuf {
+    ctx.tmp_dir.join(format!(
+        "bundle-{}",
+        transfer::sanitize_filename(&file.name)
+    ))
+}
+
+/// Appends `path` to the tar as `name`.
+async fn append_file(
+    sink: &mut Sink,
+    tx: &mpsc::Sender<Result<Bytes>>,
+    name: &str,
+    path: &Path,
+) -> Result<()> {
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Cannot open {}", path.display()))?;
+    let meta = f.metadata().await?;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64);
+    let size = meta.len();
+
+    sink.write(&header(name, size, mtime)).await?;
+    let mut buf = vec![0u8; READ_CHUNK];
+    let mut copied = 0u64;
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        copied += n as u64;
+        sink.write(&buf[..n]).await?;
+        send(tx, sink.drain()).await?;
+    }
+    if copied != size {
+        anyhow::bail!("{} changed size while being bundled", path.display());
+    }
+    sink.write(&padding(size)).await?;
+    send(tx, sink.drain()).await
+}
+
+async fn send(tx: &mpsc::Sender<Result<Bytes>>, chunk: Vec<u8>) -> Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    tx.send(Ok(chunk.into()))
+        .await
+        .map_err(|_| anyhow::anyhow!("Bundle upload stopped"))
+}
+
+/// Where tar bytes go before being sent: as they are, or through zstd.
+enum Sink {
+    Plain(Vec<u8>),
+    Zstd(ZstdEncoder<Vec<u8>>),
+}
+
+impl Sink {
+    fn new(compress: bool) -> Self {
+        if compress {
+            Sink::Zstd(ZstdEncoder::new(Vec::new()))
+        } else {
+            Sink::Plain(Vec::new())
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Sink::Plain(buf) => buf.extend_from_slice(data),
+            Sink::Zstd(encoder) => encoder.write_all(data).await?,
+        }
+        Ok(())
+    }
+
+    /// Takes the output produced so far.
+    fn drain(&mut self) -> Vec<u8> {
+        match self {
+            Sink::Plain(buf) => std::mem::take(buf),
+            Sink::Zstd(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    /// Ends the stream and returns the rest of the output.
+    async fn finish(mut self) -> Result<Vec<u8>> {
+        if let Sink::Zstd(ref mut encoder) = self {
+            encoder.shutdown().await?;
+        }
+        Ok(self.drain())
+    }
+}
+
+/// The header block(s) for a regular file: a pax extended header first when
+/// the name or size doesn't fit the ustar fields.
+fn header(name: &str, size: u64, mtime: i64) -> Vec<u8> {
+    let mut records = String::new();
+    if name.len() > 100 {
+        records.push_str(&pax_record("path", name));
+    }
+    // The ustar size field holds 11 octal digits (8 GiB).
+    if size >= 1 << 33 {
+        records.push_str(&pax_record("size", &size.to_string()));
+    }
+
+    let mut out = Vec::with_capacity(3 * BLOCK);
+    if !records.is_empty() {
+        out.extend(ustar_block(
+            "././@PaxHeader",
+            records.len() as u64,
+            mtime,
+            b'x',
+        ));
+        out.extend_from_slice(records.as_bytes());
+        out.extend(padding(records.len() as u64));
+    }
+    out.extend(ustar_block(name, size.min((1 << 33) - 1), mtime, b'0'));
+    out
+}
+
+fn ustar_block(name: &str, size: u64, mtime: i64, kind: u8) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let mut name_end = name.len().min(100);
+    while !name.is_char_boundary(name_end) {
+        name_end -= 1;
+    }
+    block[..name_end].copy_from_slice(&name.as_bytes()[..name_end]);
+    let mut field = |at: usize, len: usize, value: String| {
+        block[at..at + value.len().min(len)]
+            .copy_from_slice(&value.as_bytes()[..value.len().min(len)]);
+    };
+    field(100, 8, format!("{:07o}\0", 0o644));
+    field(108, 8, format!("{:07o}\0", 0));
+    field(116, 8, format!("{:07o}\0", 0));
+    field(124, 12, format!("{size:011o}\0"));
+    field(136, 12, format!("{:011o}\0", mtime.max(0)));
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field as spaces.
+    block[148..156].copy_from_slice(b"        ");
+    let sum: u32 = block.iter().map(|&b| b as u32).sum();
+    block[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+    block
+}
+
+/// One `<len> <key>=<value>\n` pax record, where `len` counts the whole
+/// record including its own digits.
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {key}={value}\n");
+    let mut len = body.len() + 1;
+    while (len.to_string().len() + body.len()) != len {
+        len = len.to_string().len() + body.len();
+    }
+    format!("{len}{body}")
+}
+
+/// Zeros filling a `size`-byte entry up to the next block boundary.
+fn padding(size: u64) -> Vec<u8> {
+    vec![0; (BLOCK - (size as usize % BLOCK)) % BLOCK]
+}
@@ -0,0 +1,100 @@
+use crate::failures::Stage;
+use anyhow::Result;
+
+#[cfg(feature = "chaos")]
+mod imp {
+    use super::Stage;
+    use anyhow::{Context, Result};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::{Mutex, OnceLock};
+
+    struct Chaos {
+        /// Failure probability for download, upload and delete, in order.
+        rates: [f64; 3],
+        rng: Mutex<StdRng>,
+    }
+
+    static CHAOS: OnceLock<Chaos> = OnceLock::new();
+
+    fn slot(stage: Stage) -> usize {
+        match stage {
+            Stage::Download => 0,
+            Stage::Upload => 1,
+            Stage::Delete => 2,
+        }
+    }
+
+    pub fn init(spec: &str, seed: Option<u64>) -> Result<()> {
+        let mut rates = [0.0; 3];
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (stage, rate) = part
+                .split_once('=')
+                .with_context(|| format!("Expected <stage>=<probability>, got \"{part}\""))?;
+            let stage = match stage.trim() {
+                "download" => Stage::Download,
+                "upload" => Stage::Upload,
+                "delete" => Stage::Delete,
+                other => anyhow::bail!(
+                    "Unknown --simulate-failure stage \"{other}\" (expected download, upload or delete)"
+                ),
+            };
+            let rate: f64 = rate.trim().parse()?;
+            if !(0.0..=1.0).contains(&rate) {
+                anyhow::bail!("--simulate-failure probability {rate} is not between 0 and 1");
+            }
+            rates[slot(stage)] = rate;
+        }
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        eprintln!(
+            "Simulating failures: download {}, upload {}, delete {}",
+            rates[0], rates[1], rates[2]
+        );
+        let _ = CHAOS.set(Chaos {
+            rates,
+            rng: Mutex::new(rng),
+        });
+        Ok(())
+    }
+
+    pub fn inject(stage: Stage) -> Result<()> {
+        let Some(chaos) = CHAOS.get() else {
+            return Ok(());
+        };
+        let rate = chaos.rates[slot(stage)];
+        if rate > 0.0 && chaos.rng.lock().unwrap().random_bool(rate) {
+            anyhow::bail!("Simulated {} failure (--simulate-failure)", stage.as_str());
+        }
+        Ok(())
+    }
+}
+
+/// `--simulate-failure`: turns on failure injection from a
+/// `download=0.2,upload=0.1,delete=0.5` spec, to exercise the retry and
+/// recovery paths. A `seed` makes the sequence of failures repeatable. Only
+/// built with `--features chaos`; a normal build refuses the flag.
+#[cfg(feature = "chaos")]
+pub fn init(spec: &str, seed: Option<u64>) -> Result<()> {
+    imp::init(spec, seed)
+}
+
+#[cfg(not(feature = "chaos"))]
+pub fn init(_spec: &str, _seed: Option<u64>) -> Result<()> {
+    anyhow::bail!("--simulate-failure needs a build with `--features chaos`")
+}
+
+/// Fails with the configured probability for `stage`; always `Ok` unless
+/// `init` turned injection on.
+#[inline]
+pub fn inject(stage: Stage) -> Result<()> {
+    #[cfg(feature = "chaos")]
+    return imp::inject(stage);
+    #[cfg(not(feature = "chaos"))]
+    {
+        let _ = stage;
+        Ok(())
+    }
+}
@@ -0,0 +1,1551 @@
+use crate::aws;
+use crate::drive;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Flags that consume the following argument as their value, so that
+/// `--export-as application/pdf` isn't mistaken for a positional argument.
+/// `--flag=value` is accepted for every flag regardless.
+const VALUE_FLAGS: &[&str] = &[
+    "--export-as",
+    "--concurrency-downloads",
+    "--concurrency-uploads",
+    "--concurrency-parts",
+    "--concurrency-listing",
+    "--upload-queue",
+    "--folder-name",
+    "--concurrency-min",
+    "--max-files",
+    "--newest",
+    "--order",
+    "--max-bytes",
+    "--on-ambiguous",
+    "--keep-local",
+    "--startup-jitter",
+    "--retry-from",
+    "--file-ids",
+    "--source",
+    "--album",
+    "--output-prefix",
+    "--progress-socket",
+    "--only-ext",
+    "--skip-ext",
+    "--delete-after",
+    "--estimate-class",
+    "--price-table",
+    "--account",
+    "--bundle-compression",
+    "--keep-failed-temp",
+    "--max-runtime",
+    "--memory-budget",
+    "--checksum-algo",
+    "--max-file-size",
+    "--simulate-failure",
+    "--simulate-failure-seed",
+    "--parent-id",
+    "--temp-in-memory",
+    "--download-buffer",
+    "--folders-file",
+    "--prefix-format",
+    "--rename",
+    "--transfer-log",
+    "--http-idle-timeout",
+    "--http-max-idle",
+    "--otlp-endpoint",
+    "--ca-cert",
+    "--since-object",
+    "--slowdown-retries",
+    "--slowdown-max-delay",
+    "--completion-marker",
+    "--failure-marker",
+];
+
+/// Minimal command-line parser: boolean flags, `--flag value` / `--flag=value`
+/// options, and positional arguments (the first of which may be a subcommand).
+pub struct Args {
+    flags: Vec<(String, Option<String>)>,
+    positionals: Vec<String>,
+}
+
+impl Args {
+    pub fn from_env() -> Result<Self> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut flags = Vec::new();
+        let mut positionals = Vec::new();
+        let mut iter = args.into_iter();
+
+        while let Some(arg) = iter.next() {
+            if !arg.starts_with("--") {
+                positionals.push(arg);
+                continue;
+            }
+            if let Some((name, value)) = arg.split_once('=') {
+                flags.push((name.to_string(), Some(value.to_string())));
+            } else if VALUE_FLAGS.contains(&arg.as_str()) {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("{arg} requires a value"))?;
+                flags.push((arg, Some(value)));
+            } else {
+                flags.push((arg, None));
+            }
+        }
+
+        Ok(Self { flags, positionals })
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|(n, _)| n == name)
+    }
+
+    /// Returns the value of the last occurrence of `name`, if any.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    pub fn subcommand(&self) -> Option<&str> {
+        self.positionals.first().map(String::as_str)
+    }
+
+    /// Positional arguments following the subcommand.
+    pub fn operands(&self) -> &[String] {
+        self.positionals.get(1..).unwrap_or_default()
+    }
+}
+
+/// What to do with Drive shortcut entries found in the backup folder.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ShortcutPolicy {
+    /// Leave shortcuts alone and report them.
+    Skip,
+    /// Back up the file each shortcut points to, then delete the shortcut.
+    Resolve,
+}
+
+impl FromStr for ShortcutPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "resolve" => Ok(Self::Resolve),
+            _ => anyhow::bail!("Invalid DRIVE_SHORTCUTS value \"{s}\" (expected skip or resolve)"),
+        }
+    }
+}
+
+/// Where the files to back up come from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The Drive folder Takeout exports land in.
+    Drive,
+    /// The Google Photos library itself, via the Photos Library API.
+    Photos,
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "drive" => Ok(Self::Drive),
+            "photos" => Ok(Self::Photos),
+            _ => anyhow::bail!("expected drive or photos"),
+        }
+    }
+}
+
+/// Which files to list with respect to Drive's trash.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrashFilter {
+    Exclude,
+    /// Trashed files as well as the rest (`--include-trashed`).
+    Include,
+    /// Only trashed files (`--only-trashed`).
+    Only,
+}
+
+impl TrashFilter {
+    /// The `trashed` clause for a Drive `q` query, if any.
+    pub fn query(self) -> Option<&'static str> {
+        match self {
+            TrashFilter::Exclude => Some("trashed=false"),
+            TrashFilter::Include => None,
+            TrashFilter::Only => Some("trashed=true"),
+        }
+    }
+}
+
+/// One line of a `--folders-file`.
+#[derive(Clone)]
+pub enum Folder {
+    /// Looked up by name, as `folder_name` is.
+    Name(String),
+    /// Used as is.
+    Id(String),
+}
+
+impl Folder {
+    /// The key prefix segment its backup goes under: the id, or the name
+    /// with anything but letters, digits, `-` and `_` replaced by `_`.
+    pub fn prefix(&self) -> String {
+        match self {
+            Folder::Id(id) => id.clone(),
+            Folder::Name(name) => name
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Display for Folder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Folder::Name(name) => write!(f, "\"{name}\""),
+            Folder::Id(id) => write!(f, "id {id}"),
+        }
+    }
+}
+
+/// What to do when more than one Drive folder has the backup folder's name.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AmbiguousFolders {
+    /// Stop and list the candidates.
+    Fail,
+    /// Use whichever Drive returns first.
+    First,
+    /// Use the most recently modified one.
+    Newest,
+    /// Back up the contents of every match.
+    All,
+}
+
+impl FromStr for AmbiguousFolders {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "first" => Ok(Self::First),
+            "newest" => Ok(Self::Newest),
+            "all" => Ok(Self::All),
+            _ => anyhow::bail!("expected fail, first, newest or all"),
+        }
+    }
+}
+
+/// `--prefix-format`: how a run's prefix below `output_prefix` is named.
+/// Pruning keeps the last 3 days whatever the format, all of a day's runs
+/// together.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PrefixFormat {
+    /// `2026-02-22`: every run of a day shares it.
+    Date,
+    /// `2026-02-22T093000`, to the second.
+    DateTime,
+    /// The date and a time-ordered UUID for the run, unique even for runs
+    /// started the same second.
+    RunId,
+}
+
+impl PrefixFormat {
+    /// The prefix for a run started at `now`.
+    pub fn prefix(self, now: DateTime<Utc>) -> String {
+        match self {
+            PrefixFormat::Date => now.format("%Y-%m-%d").to_string(),
+            PrefixFormat::DateTime => now.format("%Y-%m-%dT%H%M%S").to_string(),
+            PrefixFormat::RunId => format!("{}-{}", now.format("%Y-%m-%d"), uuid::Uuid::now_v7()),
+        }
+    }
+
+    /// The date a run prefix (`name`, without its trailing `/`) starts
+    /// with, if it has one of the shapes above. Anything else beside the
+    /// backups, another host's `S3_OUTPUT_PREFIX` say, isn't one.
+    pub fn date_of(name: &str) -> Option<&str> {
+        let date = name.get(..10)?;
+        let digits = date.bytes().enumerate().all(|(i, b)| {
+            if i == 4 || i == 7 {
+                b == b'-'
+            } else {
+                b.is_ascii_digit()
+            }
+        });
+        if !digits || chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").is_err() {
+            return None;
+        }
+        let shaped = match &name[10..] {
+            "" => true,
+            rest if rest.starts_with('T') => {
+                let time = &rest[1..];
+                time.len() == 6
+                    && time.bytes().all(|b| b.is_ascii_digit())
+                    && chrono::NaiveTime::parse_from_str(time, "%H%M%S").is_ok()
+            }
+            rest if rest.starts_with('-') => {
+                let id = &rest[1..];
+                id.len() == 36 && uuid::Uuid::try_parse(id).is_ok()
+            }
+            _ => false,
+        };
+        shaped.then_some(date)
+    }
+}
+
+impl FromStr for PrefixFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "date" => Ok(Self::Date),
+            "datetime" => Ok(Self::DateTime),
+            "run-id" => Ok(Self::RunId),
+            _ => anyhow::bail!("expected date, datetime or run-id"),
+        }
+    }
+}
+
+/// `--order`: which files a run starts on first.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// As Drive listed them.
+    Listing,
+    /// Largest first, so the riskiest transfers fail early.
+    SizeDesc,
+    /// Smallest first, freeing Drive space for the most files soonest.
+    SizeAsc,
+    Name,
+    /// Most recently modified first.
+    Newest,
+}
+
+impl FromStr for Order {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "listing" => Ok(Self::Listing),
+            "size-desc" => Ok(Self::SizeDesc),
+            "size-asc" => Ok(Self::SizeAsc),
+            "name" => Ok(Self::Name),
+            "newest" => Ok(Self::Newest),
+            _ => anyhow::bail!("expected size-desc, size-asc, name, newest or listing"),
+        }
+    }
+}
+
+impl Order {
+    /// Sorts `files` into this order. Files without a size or modification
+    /// time go last; ties keep their listing order.
+    pub fn sort(self, files: &mut [drive::DriveFile]) {
+        match self {
+            Order::Listing => {}
+            Order::SizeDesc => files.sort_by_key(|f| std::cmp::Reverse(f.size_bytes())),
+            Order::SizeAsc => files.sort_by_key(|f| (f.size_bytes().is_none(), f.size_bytes())),
+            Order::Name => files.sort_by(|a, b| a.name.cmp(&b.name)),
+            Order::Newest => files.sort_by_key(|f| std::cmp::Reverse(f.modified_time)),
+        }
+    }
+}
+
+/// Settings resolved from the environment (and `.env`) plus command-line flags.
+#[derive(Clone)]
+pub struct Config {
+    pub creds_file: String,
+    pub token_file: String,
+    /// Google accounts to back up one after another (`GOOGLE_ACCOUNTS`), or
+    /// empty for the single account in `token_file`. See `for_account`.
+    pub accounts: Vec<String>,
+    /// With a service account key, the Workspace user to act as via
+    /// domain-wide delegation. Unset means the service account's own Drive.
+    pub impersonate_user: Option<String>,
+    pub source: Source,
+    /// With the Photos source, back up only this album (id or title).
+    pub album: Option<String>,
+    /// HTML file to show in the browser after the OAuth redirect instead of
+    /// the built-in page.
+    pub oauth_page_template: Option<PathBuf>,
+    pub test_mode: bool,
+    /// List what would be backed up and estimate its storage cost, without
+    /// uploading, pruning or deleting anything.
+    pub dry_run: bool,
+    /// Storage class the dry-run cost estimate is for.
+    pub estimate_class: String,
+    /// JSON file of per-GB-month prices overriding the built-in table.
+    pub price_table: Option<PathBuf>,
+    /// S3 retrieval tier `restore` requests archived objects with.
+    pub restore_tier: String,
+    /// Run even if another run's lock is present for this run's prefix.
+    pub force: bool,
+    pub prune_only: bool,
+    pub shortcuts: ShortcutPolicy,
+    /// mimeTypes treated as Google Workspace files and skipped.
+    pub workspace_mimetypes: Vec<String>,
+    pub on_ambiguous: AmbiguousFolders,
+    /// Name of the Drive folder to back up.
+    pub folder_name: String,
+    /// The Drive folder to back up, by id instead of `folder_name`; only
+    /// set for one of `folders`.
+    pub folder_id: Option<String>,
+    /// Folders from `--folders-file`, backed up one after another, or empty
+    /// for the one `folder_name` names. See `for_folder`.
+    pub folders: Vec<Folder>,
+    /// Look for the backup folder only directly inside this Drive folder.
+    pub parent_id: Option<String>,
+    /// Back up the files shared with this account instead of a folder.
+    pub shared_with_me: bool,
+    pub trashed: TrashFilter,
+    /// Start backing up while the folder listing is still being fetched.
+    pub stream_listing: bool,
+    /// Descend into subfolders of the backup folder.
+    pub recursive: bool,
+    /// Trash subfolders the run left empty (requires `recursive`).
+    pub prune_empty_folders: bool,
+    /// Maximum number of folders listed at once with `recursive`.
+    pub listing_concurrency: usize,
+    /// Maximum number of files downloading from Drive at once.
+    pub download_concurrency: usize,
+    /// Maximum number of files uploading to S3 at once.
+    pub upload_concurrency: usize,
+    /// Downloaded files that may wait for an upload slot; the upload
+    /// concurrency when unset.
+    pub upload_queue: Option<usize>,
+    /// Maximum number of parts of one file's multipart upload sent at once.
+    pub part_concurrency: usize,
+    /// Tune both stages' concurrency between `concurrency_min` and their
+    /// limits, backing off when throttled.
+    pub adaptive_concurrency: bool,
+    pub concurrency_min: usize,
+    /// Stop starting new files once this many have been started.
+    pub max_files: Option<usize>,
+    /// S3 key, after `output_prefix`, whose time limits the listing to the
+    /// files modified since (`{date}/_COMPLETE`, say).
+    pub since_object: Option<String>,
+    /// Back up only this many files, the most recently modified.
+    pub newest: Option<usize>,
+    pub order: Order,
+    /// Stop starting new files once this many bytes have been started.
+    pub max_bytes: Option<u64>,
+    /// Most memory concurrent multipart uploads may hold between them.
+    pub memory_budget: Option<u64>,
+    /// `--max-runtime`: how long after `start_clock` new files may start.
+    pub max_runtime: Option<Duration>,
+    /// Stop starting new files at this point; set by `start_clock`.
+    pub deadline: Option<Instant>,
+    /// Failures CSV from an earlier run: back up only the files it lists.
+    pub retry_from: Option<PathBuf>,
+    /// Back up exactly these Drive files, fetched by id, instead of listing
+    /// the folder.
+    pub file_ids: Option<Vec<String>>,
+    /// Sleep a random 0..=N seconds before a backup run's first API call, so
+    /// hosts sharing a cron schedule don't all hit Google at once.
+    pub startup_jitter: u64,
+    /// Attempts per request S3 keeps answering `SlowDown`, and the most
+    /// `retry` waits between two of them.
+    pub slowdown_retries: u32,
+    pub slowdown_max_delay: Duration,
+    /// Move archived files to the Drive trash and delete them for good on
+    /// the first run at least this many days later.
+    pub delete_after: Option<u32>,
+    /// Pause on network loss and resume once Google answers again, instead
+    /// of failing each file in turn.
+    pub wait_for_network: bool,
+    /// Back up only files whose names end in one of these extensions
+    /// (lowercase, no leading dot). Empty means any.
+    pub only_ext: Vec<String>,
+    /// Leave files whose names end in one of these extensions in Drive.
+    pub skip_ext: Vec<String>,
+    /// After the run, also delete the Workspace files it skipped.
+    pub delete_workspace_files: bool,
+    /// Skip files whose Drive MD5 matches one already uploaded this run.
+    pub dedup: bool,
+    /// Insert a hash segment after the date prefix in every S3 key.
+    pub prefix_entropy: bool,
+    /// Keep each archived file under this directory (by backup prefix)
+    /// instead of deleting the local copy after upload.
+    pub keep_local: Option<PathBuf>,
+    /// Move the temp file of a failed transfer here instead of deleting it.
+    pub keep_failed_temp: Option<PathBuf>,
+    /// Copy Drive downloads straight into S3 instead of through a temp file.
+    pub stream_upload: bool,
+    /// Upload only to keys that hold no object yet (`If-None-Match: *`).
+    pub no_overwrite: bool,
+    /// Also archive each file's earlier Drive revisions, beside it.
+    pub include_revisions: bool,
+    /// Re-read each file's size and modifiedTime right before downloading
+    /// it, and leave it for the next run if they changed since the listing.
+    pub stable_check: bool,
+    /// Abort any download that runs past this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Fail a download whose byte count differs from Drive's reported size.
+    pub size_check: bool,
+    /// Hold Drive downloads up to this size in memory instead of a temp file.
+    pub temp_in_memory: Option<u64>,
+    /// Bytes gathered in memory before each write to a temp file.
+    pub download_buffer: usize,
+    /// Rewrites each file name for its S3 key; Drive keeps the original.
+    pub rename: Option<Rename>,
+    /// Archive files but never delete or trash anything in Drive.
+    pub keep_in_drive: bool,
+    /// Ask before a run deletes anything from Drive.
+    pub confirm_delete: bool,
+    /// Answers `--confirm-delete`'s question up front.
+    pub yes: bool,
+    /// Upload the run as one tar object instead of an object per file.
+    pub bundle: bool,
+    /// `zstd` or `none`: how the `--bundle` tar is compressed.
+    pub bundle_compression: String,
+    /// Fixed root for every key, before the date (`host-a/`), or empty.
+    /// Always ends in `/` when set.
+    pub output_prefix: String,
+    /// How each run's prefix after `output_prefix` is named.
+    pub prefix_format: PrefixFormat,
+    /// Directory holding one manifest per backup prefix.
+    pub manifest_dir: String,
+    /// Detect the real type of files Drive labels `application/octet-stream`.
+    pub sniff_content_type: bool,
+    /// Checksum S3 verifies each upload, and each multipart part, against.
+    pub checksum_algo: aws::ChecksumAlgo,
+    /// Unix socket or named pipe to stream NDJSON progress events to.
+    pub progress_socket: Option<PathBuf>,
+    /// Never negotiate HTTP/2 with Google.
+    pub http1: bool,
+    /// How long an idle connection to Google is kept for reuse.
+    pub http_idle_timeout: Duration,
+    /// Most idle connections kept per host.
+    pub http_max_idle: usize,
+    /// PEM bundle of extra root certificates to trust for Google, e.g. a
+    /// TLS-intercepting proxy's CA.
+    pub ca_cert: Option<PathBuf>,
+    /// Append-only NDJSON file logging each file's transfer, across runs.
+    pub transfer_log: Option<PathBuf>,
+    /// Object written under the backup prefix when a run fully succeeds
+    /// (`_COMPLETE`, say); `failure_marker` is written instead when it
+    /// doesn't. Neither is written unless this is set.
+    pub completion_marker: Option<String>,
+    pub failure_marker: String,
+    /// OTLP/HTTP collector to send each run's trace to.
+    pub otlp_endpoint: Option<String>,
+    /// `User-Agent` sent on every Google API request.
+    pub user_agent: String,
+    /// Log every HTTP request's method, URL, status and latency.
+    pub verbose_http: bool,
+    /// Status lines in ASCII on stdout instead of live progress bars
+    /// (`--plain`, or whenever stdout isn't a terminal).
+    pub plain: bool,
+    /// Failure probabilities to inject per stage (`chaos` builds only).
+    pub simulate_failure: Option<String>,
+    pub simulate_failure_seed: Option<u64>,
+}
+
+/// Identifies this tool in Google's logs (and any proxy's) as `name/version`.
+pub const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+impl Config {
+    pub fn load(args: &Args) -> Result<Self> {
+        let source = setting(args, "--source", "BACKUP_SOURCE", Source::Drive)?;
+        let album: Option<String> = optional(args, "--album", "PHOTOS_ALBUM")?;
+        if album.is_some() && source != Source::Photos {
+            anyhow::bail!("--album only applies with --source=photos");
+        }
+        let folder_name: String = setting(
+            args,
+            "--folder-name",
+            "DRIVE_FOLDER_NAME",
+            "Takeout".to_string(),
+        )?;
+        if folder_name.trim().is_empty() {
+            anyhow::bail!("--folder-name can't be empty");
+        }
+        let recursive = args.flag("--recursive");
+        let prune_empty_folders = args.flag("--prune-empty-folders");
+        let stream_listing = args.flag("--stream-listing");
+        if stream_listing && recursive {
+            anyhow::bail!("--stream-listing can't be combined with --recursive");
+        }
+        let newest: Option<usize> = optional(args, "--newest", "BACKUP_NEWEST")?;
+        let order = setting(args, "--order", "BACKUP_ORDER", Order::Listing)?;
+        if order != Order::Listing && stream_listing {
+            anyhow::bail!(
+                "--order can't be combined with --stream-listing (files start as they're listed)"
+            );
+        }
+        let file_ids = optional::<String>(args, "--file-ids", "FILE_IDS")?
+            .map(|spec| file_ids(&spec))
+            .transpose()?;
+        if file_ids.is_some() && source != Source::Drive {
+            anyhow::bail!("--file-ids only applies to the Drive source");
+        }
+        let retry_from: Option<PathBuf> = optional(args, "--retry-from", "RETRY_FROM")?;
+        if file_ids.is_some() && retry_from.is_some() {
+            anyhow::bail!("--file-ids can't be combined with --retry-from");
+        }
+        let parent_id: Option<String> = optional(args, "--parent-id", "DRIVE_PARENT_ID")?;
+        let shared_with_me = args.flag("--shared-with-me");
+        if shared_with_me {
+            if source != Source::Drive {
+                anyhow::bail!("--shared-with-me only applies to the Drive source");
+            }
+            if file_ids.is_some() || retry_from.is_some() {
+                anyhow::bail!("--shared-with-me can't be combined with --file-ids or --retry-from");
+            }
+            if parent_id.is_some() {
+                anyhow::bail!(
+                    "--shared-with-me doesn't look for a folder, so not with --parent-id"
+                );
+            }
+            if prune_empty_folders {
+                anyhow::bail!(
+                    "--prune-empty-folders can't be combined with --shared-with-me (the folders are someone else's)"
+                );
+            }
+        }
+        let folders = match optional::<PathBuf>(args, "--folders-file", "FOLDERS_FILE")? {
+            Some(path) => folders(&path)?,
+            None => Vec::new(),
+        };
+        if !folders.is_empty() {
+            if source != Source::Drive {
+                anyhow::bail!("--folders-file only applies to the Drive source");
+            }
+            if file_ids.is_some() || retry_from.is_some() || shared_with_me {
+                anyhow::bail!(
+                    "--folders-file can't be combined with --file-ids, --retry-from or --shared-with-me"
+                );
+            }
+        }
+        let since_object: Option<String> = optional(args, "--since-object", "SINCE_OBJECT")?;
+        if since_object.is_some() && source != Source::Drive {
+            anyhow::bail!("--since-object only applies to the Drive source");
+        }
+        if newest == Some(0) {
+            anyhow::bail!("--newest must be at least 1");
+        }
+        if newest.is_some() && (stream_listing || source != Source::Drive) {
+            anyhow::bail!("--newest needs the whole Drive listing, so not --stream-listing or --source=photos");
+        }
+        if prune_empty_folders && !recursive {
+            anyhow::bail!("--prune-empty-folders only applies with --recursive");
+        }
+        let delete_workspace_files = args.flag("--delete-workspace-files");
+        if delete_workspace_files && stream_listing {
+            anyhow::bail!("--delete-workspace-files can't be combined with --stream-listing");
+        }
+        let dry_run = args.flag("--dry-run");
+        if dry_run && stream_listing {
+            anyhow::bail!("--dry-run can't be combined with --stream-listing");
+        }
+        let keep_local: Option<PathBuf> = optional(args, "--keep-local", "KEEP_LOCAL_DIR")?;
+        let stream_upload = args.flag("--stream-upload");
+        if stream_upload && keep_local.is_some() {
+            anyhow::bail!(
+                "--stream-upload can't be combined with --keep-local (there's no local copy)"
+            );
+        }
+        let temp_in_memory =
+            optional::<ByteSize>(args, "--temp-in-memory", "TEMP_IN_MEMORY")?.map(|b| b.0);
+        if let Some(max) = temp_in_memory {
+            if keep_local.is_some() {
+                anyhow::bail!(
+                    "--temp-in-memory can't be combined with --keep-local (small files would have no local copy)"
+                );
+            }
+            if max > crate::aws::MULTIPART_THRESHOLD {
+                anyhow::bail!(
+                    "--temp-in-memory is at most {}M, the largest single-request upload",
+                    crate::aws::MULTIPART_THRESHOLD >> 20
+                );
+            }
+        }
+
+        let download_buffer = setting(
+            args,
+            "--download-buffer",
+            "DOWNLOAD_BUFFER",
+            ByteSize(1 << 20),
+        )?
+        .0;
+        if download_buffer == 0 || download_buffer > 64 << 20 {
+            anyhow::bail!("--download-buffer must be between 1 byte and 64M");
+        }
+
+        let bundle = args.flag("--bundle");
+        if bundle {
+            if source != Source::Drive {
+                anyhow::bail!("--bundle only applies to the Drive source");
+            }
+            if stream_listing || stream_upload || keep_local.is_some() || temp_in_memory.is_some() {
+                anyhow::bail!(
+                    "--bundle can't be combined with --stream-listing, --stream-upload, --keep-local \
+                     or --temp-in-memory"
+                );
+            }
+        }
+        let completion_marker = optional(args, "--completion-marker", "COMPLETION_MARKER")?
+            .map(|name| marker_name(name, "--completion-marker"))
+            .transpose()?;
+        let failure_marker = marker_name(
+            setting(
+                args,
+                "--failure-marker",
+                "FAILURE_MARKER",
+                "_FAILED".to_string(),
+            )?,
+            "--failure-marker",
+        )?;
+        if completion_marker.as_ref() == Some(&failure_marker) {
+            anyhow::bail!("--completion-marker and --failure-marker must differ");
+        }
+        let slowdown_retries: u32 = setting(
+            args,
+            "--slowdown-retries",
+            "S3_SLOWDOWN_RETRIES",
+            crate::retry::SLOW_DOWN_RETRIES,
+        )?;
+        if slowdown_retries == 0 {
+            anyhow::bail!("--slowdown-retries must be at least 1");
+        }
+        let include_revisions = args.flag("--include-revisions");
+        if include_revisions && (bundle || source != Source::Drive) {
+            anyhow::bail!("--include-revisions only applies to the Drive source, without --bundle");
+        }
+        let stable_check = args.flag("--stable-check");
+        if stable_check && (bundle || source != Source::Drive) {
+            anyhow::bail!("--stable-check only applies to the Drive source, without --bundle");
+        }
+        let bundle_compression = setting(
+            args,
+            "--bundle-compression",
+            "BUNDLE_COMPRESSION",
+            "zstd".to_string(),
+        )?;
+        if !matches!(bundle_compression.as_str(), "zstd" | "none") {
+            anyhow::bail!(
+                "Invalid --bundle-compression \"{bundle_compression}\" (expected zstd or none)"
+            );
+        }
+
+        let mut accounts = accounts(std::env::var("GOOGLE_ACCOUNTS").ok())?;
+        if let Some(account) = args.value("--account") {
+            if !accounts.iter().any(|a| a == account) {
+                anyhow::bail!("--account {account} is not listed in GOOGLE_ACCOUNTS");
+            }
+            accounts = vec![account.to_string()];
+        }
+
+        Ok(Self {
+            creds_file: env_or("GOOGLE_CREDENTIALS_FILE", "credentials.json"),
+            token_file: env_or("GOOGLE_TOKEN_FILE", "token.json"),
+            accounts,
+            impersonate_user: std::env::var("GOOGLE_IMPERSONATE_USER").ok(),
+            source,
+            album,
+            oauth_page_template: std::env::var("OAUTH_PAGE_TEMPLATE").ok().map(PathBuf::from),
+            test_mode: args.flag("--test"),
+            dry_run,
+            estimate_class: setting(
+                args,
+                "--estimate-class",
+                "ESTIMATE_STORAGE_CLASS",
+                "DEEP_ARCHIVE".to_string(),
+            )?,
+            price_table: optional(args, "--price-table", "S3_PRICE_TABLE")?,
+            restore_tier: env_or("S3_RESTORE_TIER", "Bulk"),
+            force: args.flag("--force"),
+            prune_only: args.flag("--prune-only"),
+            shortcuts: env_or("DRIVE_SHORTCUTS", "skip").parse()?,
+            workspace_mimetypes: workspace_mimetypes(std::env::var("DRIVE_SKIP_MIMETYPES").ok()),
+            on_ambiguous: setting(
+                args,
+                "--on-ambiguous",
+                "DRIVE_ON_AMBIGUOUS",
+                AmbiguousFolders::Fail,
+            )?,
+            folder_name,
+            folder_id: None,
+            folders,
+            parent_id,
+            shared_with_me,
+            trashed: match (args.flag("--include-trashed"), args.flag("--only-trashed")) {
+                (_, true) => TrashFilter::Only,
+                (true, false) => TrashFilter::Include,
+                (false, false) => TrashFilter::Exclude,
+            },
+            stream_listing,
+            recursive,
+            prune_empty_folders,
+            download_concurrency: positive(setting(
+                args,
+                "--concurrency-downloads",
+                "DOWNLOAD_CONCURRENCY",
+                2,
+            )?)?,
+            upload_concurrency: positive(setting(
+                args,
+                "--concurrency-uploads",
+                "UPLOAD_CONCURRENCY",
+                2,
+            )?)?,
+            upload_queue: optional(args, "--upload-queue", "UPLOAD_QUEUE")?
+                .map(|n: usize| {
+                    if n == 0 {
+                        anyhow::bail!("--upload-queue must be at least 1");
+                    }
+                    Ok(n)
+                })
+                .transpose()?,
+            part_concurrency: positive(setting(
+                args,
+                "--concurrency-parts",
+                "PART_CONCURRENCY",
+                2,
+            )?)?,
+            listing_concurrency: positive(setting(
+                args,
+                "--concurrency-listing",
+                "LISTING_CONCURRENCY",
+                4,
+            )?)?,
+            adaptive_concurrency: args.flag("--adaptive-concurrency"),
+            concurrency_min: positive(setting(args, "--concurrency-min", "CONCURRENCY_MIN", 1)?)?,
+            max_files: optional(args, "--max-files", "MAX_FILES")?,
+            since_object,
+            newest,
+            order,
+            max_bytes: optional::<ByteSize>(args, "--max-bytes", "MAX_BYTES")?.map(|b| b.0),
+            memory_budget: optional::<ByteSize>(args, "--memory-budget", "MEMORY_BUDGET")?
+                .map(|b| b.0),
+            max_runtime: optional::<Runtime>(args, "--max-runtime", "MAX_RUNTIME")?.map(|r| r.0),
+            deadline: None,
+            retry_from,
+            file_ids,
+            startup_jitter: setting(args, "--startup-jitter", "STARTUP_JITTER_SECS", 0)?,
+            slowdown_retries,
+            slowdown_max_delay: setting(
+                args,
+                "--slowdown-max-delay",
+                "S3_SLOWDOWN_MAX_DELAY",
+                Runtime(crate::retry::MAX_DELAY),
+            )?
+            .0,
+            delete_after: optional(args, "--delete-after", "DELETE_AFTER_DAYS")?,
+            wait_for_network: args.flag("--wait-for-network"),
+            only_ext: extensions(optional(args, "--only-ext", "ONLY_EXTENSIONS")?),
+            skip_ext: extensions(optional(args, "--skip-ext", "SKIP_EXTENSIONS")?),
+            delete_workspace_files,
+            dedup: args.flag("--dedup"),
+            prefix_entropy: args.flag("--prefix-entropy"),
+            keep_local,
+            keep_failed_temp: optional(args, "--keep-failed-temp", "KEEP_FAILED_TEMP_DIR")?,
+            stream_upload,
+            include_revisions,
+            stable_check,
+            no_overwrite: args.flag("--no-overwrite"),
+            max_file_size: optional::<ByteSize>(args, "--max-file-size", "MAX_FILE_SIZE")?
+                .map(|b| b.0),
+            size_check: !args.flag("--no-size-check"),
+            temp_in_memory,
+            download_buffer: download_buffer as usize,
+            rename: optional(args, "--rename", "S3_KEY_RENAME")?,
+            keep_in_drive: args.flag("--no-delete"),
+            confirm_delete: args.flag("--confirm-delete"),
+            yes: args.flag("--yes"),
+            bundle,
+            bundle_compression,
+            output_prefix: output_prefix(optional(args, "--output-prefix", "S3_OUTPUT_PREFIX")?)?,
+            prefix_format: setting(args, "--prefix-format", "PREFIX_FORMAT", PrefixFormat::Date)?,
+            manifest_dir: env_or("MANIFEST_DIR", "manifests"),
+            sniff_content_type: args.flag("--sniff-content-type"),
+            checksum_algo: setting(
+                args,
+                "--checksum-algo",
+                "S3_CHECKSUM_ALGORITHM",
+                aws::ChecksumAlgo::Crc32c,
+            )?,
+            progress_socket: optional(args, "--progress-socket", "PROGRESS_SOCKET")?,
+            transfer_log: optional(args, "--transfer-log", "TRANSFER_LOG")?,
+            completion_marker,
+            failure_marker,
+            http1: args.flag("--http1"),
+            http_idle_timeout: setting(
+                args,
+                "--http-idle-timeout",
+                "HTTP_IDLE_TIMEOUT",
+                Runtime(Duration::from_secs(90)),
+            )?
+            .0,
+            http_max_idle: setting(args, "--http-max-idle", "HTTP_MAX_IDLE_PER_HOST", 16)?,
+            ca_cert: optional(args, "--ca-cert", "SSL_CERT_FILE")?,
+            otlp_endpoint: optional(args, "--otlp-endpoint", "OTEL_EXPORTER_OTLP_ENDPOINT")?,
+            user_agent: env_or("HTTP_USER_AGENT", DEFAULT_USER_AGENT),
+            verbose_http: args.flag("--verbose-http"),
+            plain: args.flag("--plain")
+                || args.flag("--no-color")
+                || !std::io::stdout().is_terminal(),
+            simulate_failure: args.value("--simulate-failure").map(String::from),
+            simulate_failure_seed: optional(
+                args,
+                "--simulate-failure-seed",
+                "SIMULATE_FAILURE_SEED",
+            )?,
+        })
+    }
+
+    /// The settings for one of `accounts`: its own token file next to
+    /// `token_file` (`token.work.json`), and keys and manifests under
+    /// `<output prefix><account>/`.
+    pub fn for_account(&self, account: &str) -> Self {
+        let token = Path::new(&self.token_file);
+        let stem = token
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("token");
+        let name = match token.extension().and_then(|e| e.to_str()) {
+            Some(ext) => format!("{stem}.{account}.{ext}"),
+            None => format!("{stem}.{account}"),
+        };
+        Self {
+            token_file: token.with_file_name(name).to_string_lossy().into_owned(),
+            accounts: vec![account.to_string()],
+            output_prefix: format!("{}{account}/", self.output_prefix),
+            ..self.clone()
+        }
+    }
+
+    /// Fixes the `--max-runtime` deadline from now, once the run is about
+    /// to start on its first account (after any `--startup-jitter` wait).
+    pub fn start_clock(&mut self) {
+        self.deadline = self.max_runtime.map(|d| Instant::now() + d);
+    }
+
+    /// The settings for one of `folders`: that folder, with keys and
+    /// manifests under `<output prefix><folder prefix>/`.
+    pub fn for_folder(&self, folder: &Folder) -> Self {
+        let (folder_name, folder_id) = match folder {
+            Folder::Name(name) => (name.clone(), None),
+            Folder::Id(id) => (self.folder_name.clone(), Some(id.clone())),
+        };
+        Self {
+            folder_name,
+            folder_id,
+            folders: Vec::new(),
+            output_prefix: format!("{}{}/", self.output_prefix, folder.prefix()),
+            ..self.clone()
+        }
+    }
+
+    /// The settings for a command that works on one account: with
+    /// `GOOGLE_ACCOUNTS`, the one picked by `--account`.
+    pub fn single_account(&self) -> Result<Self> {
+        match self.accounts.as_slice() {
+            [] => Ok(self.clone()),
+            [account] => Ok(self.for_account(account)),
+            _ => anyhow::bail!("Pick an account from GOOGLE_ACCOUNTS with --account <name>"),
+        }
+    }
+
+    /// How many downloaded files may wait for an upload at once.
+    pub fn upload_queue(&self) -> usize {
+        self.upload_queue.unwrap_or(self.upload_concurrency)
+    }
+
+    /// Whether `name` passes `--only-ext` and `--skip-ext`. Extensions match
+    /// the end of the name case-insensitively, so `tar.gz` works too.
+    pub fn extension_allowed(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        let has = |ext: &String| name.ends_with(&format!(".{ext}"));
+        (self.only_ext.is_empty() || self.only_ext.iter().any(has))
+            && !self.skip_ext.iter().any(has)
+    }
+
+    /// The resolved settings as JSON, for `--print-config`. Only file paths
+    /// are shown for the Google credentials and token, never their content,
+    /// and an endpoint's user info and query are dropped.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string());
+        let google = json!({
+            "creds_file": self.creds_file,
+            "token_file": self.token_file,
+            "accounts": self.accounts,
+            "impersonate_user": self.impersonate_user,
+            "oauth_page_template": path(&self.oauth_page_template),
+            "user_agent": self.user_agent,
+        });
+        let listing = json!({
+            "source": match self.source {
+                Source::Drive => "drive",
+                Source::Photos => "photos",
+            },
+            "album": self.album,
+            "folder_name": self.folder_name,
+            "folder_id": self.folder_id,
+            "folders": self.folders.iter().map(Folder::to_string).collect::<Vec<_>>(),
+            "parent_id": self.parent_id,
+            "shared_with_me": self.shared_with_me,
+            "on_ambiguous": match self.on_ambiguous {
+                AmbiguousFolders::Fail => "fail",
+                AmbiguousFolders::First => "first",
+                AmbiguousFolders::Newest => "newest",
+                AmbiguousFolders::All => "all",
+            },
+            "trashed": match self.trashed {
+                TrashFilter::Exclude => "exclude",
+                TrashFilter::Include => "include",
+                TrashFilter::Only => "only",
+            },
+            "shortcuts": match self.shortcuts {
+                ShortcutPolicy::Skip => "skip",
+                ShortcutPolicy::Resolve => "resolve",
+            },
+            "workspace_mimetypes": self.workspace_mimetypes,
+            "only_ext": self.only_ext,
+            "skip_ext": self.skip_ext,
+            "recursive": self.recursive,
+            "stream_listing": self.stream_listing,
+            "retry_from": path(&self.retry_from),
+            "file_ids": self.file_ids,
+        });
+        let limits = json!({
+            "listing_concurrency": self.listing_concurrency,
+            "download_concurrency": self.download_concurrency,
+            "upload_concurrency": self.upload_concurrency,
+            "upload_queue": self.upload_queue(),
+            "part_concurrency": self.part_concurrency,
+            "adaptive_concurrency": self.adaptive_concurrency,
+            "concurrency_min": self.concurrency_min,
+            "max_files": self.max_files,
+            "since_object": self.since_object,
+            "newest": self.newest,
+            "order": match self.order {
+                Order::Listing => "listing",
+                Order::SizeDesc => "size-desc",
+                Order::SizeAsc => "size-asc",
+                Order::Name => "name",
+                Order::Newest => "newest",
+            },
+            "max_bytes": self.max_bytes,
+            "memory_budget": self.memory_budget,
+            "max_runtime_secs": self.max_runtime.map(|d| d.as_secs()),
+            "startup_jitter_secs": self.startup_jitter,
+            "slowdown_retries": self.slowdown_retries,
+            "slowdown_max_delay_secs": self.slowdown_max_delay.as_secs(),
+        });
+        let transfer = json!({
+            "dedup": self.dedup,
+            "stream_upload": self.stream_upload,
+            "include_revisions": self.include_revisions,
+            "stable_check": self.stable_check,
+            "no_overwrite": self.no_overwrite,
+            "temp_in_memory": self.temp_in_memory,
+            "download_buffer": self.download_buffer,
+            "size_check": self.size_check,
+            "max_file_size": self.max_file_size,
+            "sniff_content_type": self.sniff_content_type,
+            "checksum_algo": match self.checksum_algo {
+                aws::ChecksumAlgo::Crc32c => "crc32c",
+                aws::ChecksumAlgo::Sha256 => "sha256",
+                aws::ChecksumAlgo::Md5 => "md5",
+                aws::ChecksumAlgo::None => "none",
+            },
+            "bundle": self.bundle,
+            "bundle_compression": self.bundle_compression,
+            "keep_local": path(&self.keep_local),
+            "keep_failed_temp": path(&self.keep_failed_temp),
+            "wait_for_network": self.wait_for_network,
+        });
+        let keys = json!({
+            "output_prefix": self.output_prefix,
+            "prefix_format": match self.prefix_format {
+                PrefixFormat::Date => "date",
+                PrefixFormat::DateTime => "datetime",
+                PrefixFormat::RunId => "run-id",
+            },
+            "prefix_entropy": self.prefix_entropy,
+            "rename": self.rename.as_ref().map(|r| json!({
+                "pattern": r.pattern.as_str(),
+                "replacement": r.replacement,
+                "global": r.global,
+            })),
+        });
+        let drive_cleanup = json!({
+            "keep_in_drive": self.keep_in_drive,
+            "delete_after_days": self.delete_after,
+            "confirm_delete": self.confirm_delete,
+            "yes": self.yes,
+            "delete_workspace_files": self.delete_workspace_files,
+            "prune_empty_folders": self.prune_empty_folders,
+        });
+        let mode = json!({
+            "test_mode": self.test_mode,
+            "dry_run": self.dry_run,
+            "estimate_class": self.estimate_class,
+            "price_table": path(&self.price_table),
+            "restore_tier": self.restore_tier,
+            "force": self.force,
+            "prune_only": self.prune_only,
+        });
+        let reporting = json!({
+            "manifest_dir": self.manifest_dir,
+            "progress_socket": path(&self.progress_socket),
+            "transfer_log": path(&self.transfer_log),
+            "completion_marker": self.completion_marker,
+            "failure_marker": self.completion_marker.as_ref().map(|_| &self.failure_marker),
+            "otlp_endpoint": self.otlp_endpoint.as_deref().map(without_credentials),
+            "verbose_http": self.verbose_http,
+            "plain": self.plain,
+            "simulate_failure": self.simulate_failure,
+            "simulate_failure_seed": self.simulate_failure_seed,
+        });
+        let http = json!({
+            "http1": self.http1,
+            "idle_timeout_secs": self.http_idle_timeout.as_secs(),
+            "max_idle_per_host": self.http_max_idle,
+            "ca_cert": path(&self.ca_cert),
+        });
+        json!({
+            "google": google,
+            "listing": listing,
+            "limits": limits,
+            "transfer": transfer,
+            "keys": keys,
+            "drive_cleanup": drive_cleanup,
+            "mode": mode,
+            "reporting": reporting,
+            "http": http,
+        })
+    }
+}
+
+/// `url` without any user name, password or query, which may carry access.
+fn without_credentials(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(mut url) => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.set_query(None);
+            url.to_string()
+        }
+        Err(_) => "[unparsable, redacted]".to_string(),
+    }
+}
+
+/// `--file-ids`: comma-separated ids, or `@<path>` for a file of them, one
+/// per line (blank lines and `#` comments skipped). Repeats are dropped.
+fn file_ids(spec: &str) -> Result<Vec<String>> {
+    let listed = match spec.strip_prefix('@') {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read --file-ids file {path}"))?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().to_string())
+            .collect(),
+        None => spec.split(',').map(String::from).collect::<Vec<_>>(),
+    };
+    let mut ids: Vec<String> = Vec::new();
+    for id in listed
+        .iter()
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+    {
+        if !ids.iter().any(|known| known == id) {
+            ids.push(id.to_string());
+        }
+    }
+    if ids.is_empty() {
+        anyhow::bail!("--file-ids names no file ids");
+    }
+    Ok(ids)
+}
+
+/// Reads a `--folders-file`: a folder name, or `id:` and a folder id, per
+/// line. Blank lines and `#` comments are skipped.
+fn folders(path: &Path) -> Result<Vec<Folder>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read --folders-file {}", path.display()))?;
+    let mut folders: Vec<Folder> = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let folder = match line.strip_prefix("id:") {
+            Some(id) if id.trim().is_empty() => {
+                anyhow::bail!("--folders-file {}: \"{line}\" has no id", path.display())
+            }
+            Some(id) => Folder::Id(id.trim().to_string()),
+            None => Folder::Name(line.to_string()),
+        };
+        // Two folders must not share a key prefix.
+        if let Some(other) = folders.iter().find(|f| f.prefix() == folder.prefix()) {
+            anyhow::bail!(
+                "--folders-file {}: {folder} and {other} would both be backed up under {}/",
+                path.display(),
+                folder.prefix()
+            );
+        }
+        // Its root sits beside the backups of a plain run at the same
+        // output prefix, which would take it for one and prune it.
+        if PrefixFormat::date_of(&folder.prefix()).is_some() {
+            anyhow::bail!(
+                "--folders-file {}: {folder} would be backed up under {}/, which looks like a \
+                 backup prefix; list it by id instead",
+                path.display(),
+                folder.prefix()
+            );
+        }
+        folders.push(folder);
+    }
+    if folders.is_empty() {
+        anyhow::bail!("--folders-file {} names no folders", path.display());
+    }
+    Ok(folders)
+}
+
+/// The built-in Workspace mimeTypes adjusted by a comma-separated list:
+/// entries starting with `-` are removed, the rest added.
+fn workspace_mimetypes(changes: Option<String>) -> Vec<String> {
+    let mut types: Vec<String> = drive::WORKSPACE_MIMETYPES
+        .iter()
+        .map(|m| m.to_string())
+        .collect();
+    for change in changes.iter().flat_map(|c| c.split(',')).map(str::trim) {
+        match change.strip_prefix('-') {
+            Some(removed) => types.retain(|m| m != removed.trim()),
+            None if !change.is_empty() && !types.iter().any(|m| m == change) => {
+                types.push(change.to_string())
+            }
+            None => {}
+        }
+    }
+    types
+}
+
+/// Normalizes a root key prefix: no leading, doubled or missing trailing
+/// slashes, so `/host-a//x` becomes `host-a/x/`.
+fn output_prefix(raw: Option<String>) -> Result<String> {
+    let segments: Vec<&str> = raw
+        .iter()
+        .flat_map(|r| r.split('/'))
+        .filter(|s| !s.is_empty())
+        .collect();
+    // Each segment sits beside the backups of a run with a shorter prefix,
+    // which would take a date-shaped one for a backup and prune it.
+    if let Some(dated) = segments.iter().find(|s| PrefixFormat::date_of(s).is_some()) {
+        anyhow::bail!(
+            "S3_OUTPUT_PREFIX segment \"{dated}\" looks like a backup prefix; pick another"
+        );
+    }
+    if segments.is_empty() {
+        Ok(String::new())
+    } else {
+        Ok(format!("{}/", segments.join("/")))
+    }
+}
+
+/// Checks a marker object's name: a single key segment, not the run lock's.
+fn marker_name(name: String, flag: &str) -> Result<String> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        anyhow::bail!("Invalid {flag} \"{name}\" (expected a name like _COMPLETE, without /)");
+    }
+    if name == crate::lock::LOCK_NAME {
+        anyhow::bail!("{flag} can't be {name}, the run lock's name");
+    }
+    Ok(name)
+}
+
+/// Parses `GOOGLE_ACCOUNTS`: comma-separated names, each used as a key
+/// prefix segment and in a file name.
+fn accounts(list: Option<String>) -> Result<Vec<String>> {
+    let mut accounts: Vec<String> = Vec::new();
+    for name in list.iter().flat_map(|l| l.split(',')).map(str::trim) {
+        if name.is_empty() {
+            continue;
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!(
+                "Invalid account name \"{name}\" in GOOGLE_ACCOUNTS (use letters, digits, - and _)"
+            );
+        }
+        // Its root sits beside the backups of a run without GOOGLE_ACCOUNTS,
+        // which would take it for one and prune it.
+        if PrefixFormat::date_of(name).is_some() {
+            anyhow::bail!(
+                "Account name \"{name}\" in GOOGLE_ACCOUNTS looks like a backup prefix; pick another"
+            );
+        }
+        if accounts.iter().any(|a| a == name) {
+            anyhow::bail!("Account \"{name}\" is listed twice in GOOGLE_ACCOUNTS");
+        }
+        accounts.push(name.to_string());
+    }
+    Ok(accounts)
+}
+
+/// Parses a comma-separated extension list such as `.zip,tgz`.
+fn extensions(list: Option<String>) -> Vec<String> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect()
+}
+
+/// A byte count written as a plain number or with a binary suffix
+/// (`K`, `M`, `G`, `T`), e.g. `500M` or `2G`.
+#[derive(Clone, Copy)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (digits, shift) = match s.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => {
+                let shift = match c.to_ascii_uppercase() {
+                    'K' => 10,
+                    'M' => 20,
+                    'G' => 30,
+                    'T' => 40,
+                    _ => anyhow::bail!("unknown size suffix '{c}'"),
+                };
+                (&s[..i], shift)
+            }
+            _ => (s, 0),
+        };
+        let n: u64 = digits.trim().parse()?;
+        n.checked_mul(1 << shift)
+            .map(ByteSize)
+            .ok_or_else(|| anyhow::anyhow!("size is too large"))
+    }
+}
+
+/// A length of time as plain seconds or with a unit (`s`, `m`, `h`, `d`),
+/// e.g. `90m` or `2h`.
+#[derive(Clone, Copy)]
+pub struct Runtime(pub Duration);
+
+impl FromStr for Runtime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (digits, unit) = match s.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => {
+                let unit = match c.to_ascii_lowercase() {
+                    's' => 1,
+                    'm' => 60,
+                    'h' => 60 * 60,
+                    'd' => 24 * 60 * 60,
+                    _ => anyhow::bail!("unknown time unit '{c}'"),
+                };
+                (&s[..i], unit)
+            }
+            _ => (s, 1),
+        };
+        let n: u64 = digits.trim().parse()?;
+        n.checked_mul(unit)
+            .map(|secs| Runtime(Duration::from_secs(secs)))
+            .ok_or_else(|| anyhow::anyhow!("time is too long"))
+    }
+}
+
+/// `--rename`: a sed-style `s/<regex>/<replacement>/` (trailing `g` to
+/// replace every match, not just the first) applied to each file name on its
+/// way into an S3 key. Any character may stand in for `/`, and `\/` is a
+/// literal one inside either part. The replacement uses `$1` / `${name}`
+/// for groups.
+#[derive(Clone)]
+pub struct Rename {
+    pub pattern: regex::Regex,
+    pub replacement: String,
+    pub global: bool,
+}
+
+impl Rename {
+    pub fn apply(&self, name: &str) -> String {
+        if self.global {
+            self.pattern.replace_all(name, self.replacement.as_str())
+        } else {
+            self.pattern.replace(name, self.replacement.as_str())
+        }
+        .into_owned()
+    }
+}
+
+impl FromStr for Rename {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut chars = s.chars();
+        let (Some('s'), Some(delim)) = (chars.next(), chars.next()) else {
+            anyhow::bail!("expected s/<regex>/<replacement>/");
+        };
+        // The pattern, the replacement, then the flags.
+        let mut parts = vec![String::new()];
+        let mut escaped = false;
+        for c in chars {
+            match c {
+                c if escaped => {
+                    let part = parts.last_mut().unwrap();
+                    if c != delim {
+                        part.push('\\');
+                    }
+                    part.push(c);
+                    escaped = false;
+                }
+                '\\' => escaped = true,
+                c if c == delim && parts.len() < 3 => parts.push(String::new()),
+                c => parts.last_mut().unwrap().push(c),
+            }
+        }
+        let [pattern, replacement, flags] = <[String; 3]>::try_from(parts)
+            .map_err(|_| anyhow::anyhow!("expected s/<regex>/<replacement>/"))?;
+        if !flags.chars().all(|f| f == 'g') {
+            anyhow::bail!("unknown flag in \"{flags}\" (only g is supported)");
+        }
+        Ok(Rename {
+            pattern: regex::Regex::new(&pattern)?,
+            replacement,
+            global: !flags.is_empty(),
+        })
+    }
+}
+
+/// Resolves a setting from its command-line flag, then its environment
+/// variable, then `default`.
+fn setting<T>(args: &Args, flag: &str, env: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    Ok(optional(args, flag, env)?.unwrap_or(default))
+}
+
+/// Like `setting`, for settings that are off unless given.
+fn optional<T>(args: &Args, flag: &str, env: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let raw = args
+        .value(flag)
+        .map(String::from)
+        .or_else(|| std::env::var(env).ok());
+    raw.map(|v| {
+        v.parse()
+            .map_err(|e| anyhow::anyhow!("Invalid value \"{v}\" for {flag} / {env}: {e}"))
+    })
+    .transpose()
+}
+
+fn positive(n: usize) -> Result<usize> {
+    if n == 0 {
+        anyhow::bail!("Concurrency limits must be at least 1");
+    }
+    Ok(n)
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_of_accepts_each_prefix_format() {
+        let now = DateTime::parse_from_rfc3339("2026-02-22T09:30:00Z")
+            .unwrap()
+            .to_utc();
+        for format in [
+            PrefixFormat::Date,
+            PrefixFormat::DateTime,
+            PrefixFormat::RunId,
+        ] {
+            let prefix = format.prefix(now);
+            assert_eq!(
+                PrefixFormat::date_of(&prefix),
+                Some("2026-02-22"),
+                "{prefix}"
+            );
+        }
+    }
+
+    #[test]
+    fn date_of_rejects_other_prefixes() {
+        for name in [
+            "host-a",
+            "2026-02-30",
+            "2026-2-22",
+            "2026-02-22x",
+            "2026-02-22T0930",
+            "2026-02-22T256000",
+            "2026-02-22-not-a-uuid",
+            "",
+        ] {
+            assert_eq!(PrefixFormat::date_of(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn account_roots_are_not_backups() {
+        let names = accounts(Some("alice, bob_2,work-2026".into())).unwrap();
+        assert_eq!(names, ["alice", "bob_2", "work-2026"]);
+        for account in &names {
+            assert_eq!(PrefixFormat::date_of(account), None, "{account}");
+        }
+        assert!(accounts(Some("alice,2026-02-22".into())).is_err());
+    }
+
+    #[test]
+    fn output_roots_are_not_backups() {
+        assert_eq!(output_prefix(None).unwrap(), "");
+        assert_eq!(
+            output_prefix(Some("/host-a//x".into())).unwrap(),
+            "host-a/x/"
+        );
+        assert!(output_prefix(Some("2026-01-01/".into())).is_err());
+        assert!(output_prefix(Some("host-a/2026-01-01T093000".into())).is_err());
+    }
+
+    #[test]
+    fn folder_roots_are_not_backups() {
+        let path = std::env::temp_dir().join(format!("folders-{}.txt", std::process::id()));
+        std::fs::write(&path, "Exports # nightly\n\nid:1AbCdEf\nMy Photos\n").unwrap();
+        let listed = folders(&path);
+        std::fs::write(&path, "Exports\n2024-01-01\n").unwrap();
+        let dated = folders(&path);
+        let _ = std::fs::remove_file(&path);
+
+        let prefixes: Vec<String> = listed.unwrap().iter().map(Folder::prefix).collect();
+        assert_eq!(prefixes, ["Exports", "1AbCdEf", "My_Photos"]);
+        for prefix in &prefixes {
+            assert_eq!(PrefixFormat::date_of(prefix), None, "{prefix}");
+        }
+        assert!(dated.is_err());
+    }
+}
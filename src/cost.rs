@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// S3 storage prices in USD per GB-month (us-east-1). Prices change and
+/// vary by region, so `S3_PRICE_TABLE` can override any of them.
+const PRICES: &[(&str, f64)] = &[
+    ("STANDARD", 0.023),
+    ("INTELLIGENT_TIERING", 0.023),
+    ("STANDARD_IA", 0.0125),
+    ("ONEZONE_IA", 0.01),
+    ("GLACIER_IR", 0.004),
+    ("GLACIER", 0.0036),
+    ("DEEP_ARCHIVE", 0.00099),
+];
+
+/// The built-in price table, with entries from the JSON object at `path`
+/// (e.g. `{"DEEP_ARCHIVE": 0.0018}`) taking precedence.
+pub async fn load_prices(path: Option<&Path>) -> Result<HashMap<String, f64>> {
+    let mut prices: HashMap<String, f64> = PRICES
+        .iter()
+        .map(|&(class, price)| (class.to_string(), price))
+        .collect();
+    if let Some(path) = path {
+        let data = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Cannot read price table {}", path.display()))?;
+        let overrides: HashMap<String, f64> = serde_json::from_str(&data)
+            .with_context(|| format!("Cannot parse price table {}", path.display()))?;
+        prices.extend(overrides);
+    }
+    Ok(prices)
+}
+
+/// Prints the estimated monthly cost of storing `bytes` in `class`.
+pub fn print_estimate(prices: &HashMap<String, f64>, class: &str, bytes: u64) -> Result<()> {
+    let class = class.to_ascii_uppercase();
+    let Some(&per_gb) = prices.get(&class) else {
+        let mut known: Vec<&str> = prices.keys().map(String::as_str).collect();
+        known.sort();
+        anyhow::bail!(
+            "No price for storage class {class} (known: {})",
+            known.join(", ")
+        );
+    };
+    let gb = bytes as f64 / (1u64 << 30) as f64;
+    println!(
+        "Estimated storage cost: ~${:.2}/month in {class} (at ${per_gb}/GB-month; an estimate, \
+         excluding request and retrieval charges)",
+        gb * per_gb
+    );
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use crate::aws::{self, Probe, ProbeError, S3Settings, S3Uploader};
+use crate::config::Config;
+use anyhow::Result;
+use aws_sdk_sts::error::ProvideErrorMetadata;
+use aws_sdk_sts::operation::assume_role::AssumeRoleError;
+use indicatif::HumanDuration;
+
+/// `doctor`: checks the S3 side of the setup before a real run does. It
+/// assumes the upload role, then makes each kind of call a backup makes
+/// against the configured bucket, and explains every failure in terms of
+/// what to change: a missing IAM permission, a wrong bucket name or region,
+/// or bucket settings that don't fit the configuration.
+pub async fn run(config: &Config) -> Result<()> {
+    let settings = S3Settings::from_env()?;
+    println!("Bucket: {}", settings.bucket);
+    println!("Upload role: {}\n", settings.role_arn);
+
+    let s3 = match S3Uploader::new(
+        settings.bucket.clone(),
+        &settings.role_arn,
+        settings.acl.clone(),
+    )
+    .await
+    {
+        Ok(s3) => s3,
+        Err(e) => {
+            println!("  ✗ sts:AssumeRole — {}", assume_role_advice(&e));
+            anyhow::bail!("Could not assume the upload role");
+        }
+    };
+    match s3.credentials_remaining() {
+        Some(left) => println!(
+            "  ✓ sts:AssumeRole (credentials valid for {})",
+            HumanDuration(left)
+        ),
+        None => println!("  ✓ sts:AssumeRole"),
+    }
+
+    let probe_key = format!("{}.doctor-{}", config.output_prefix, uuid::Uuid::new_v4());
+    aws::validate_key(&probe_key)?;
+    let probes = s3.probe(&probe_key, settings.lock.is_some()).await;
+
+    let mut failed = 0;
+    for probe in &probes {
+        let label = format!("{} ({})", probe.action, probe.call);
+        match probe.result {
+            Ok(Some(ref detail)) => println!("  ✓ {label}: {detail}"),
+            Ok(None) => println!("  ✓ {label}"),
+            Err(ref e) if probe.optional => {
+                println!(
+                    "  – {label}: {} (optional)",
+                    advice(probe, e, &settings.bucket)
+                )
+            }
+            Err(ref e) => {
+                failed += 1;
+                println!("  ✗ {label}: {}", advice(probe, e, &settings.bucket));
+            }
+        }
+    }
+    let probe_left = probes
+        .iter()
+        .any(|p| p.call == "PutObject" && p.result.is_ok())
+        && !probes
+            .iter()
+            .any(|p| p.call == "DeleteObject" && p.result.is_ok());
+    if probe_left {
+        println!(
+            "\nThe probe object s3://{}/{probe_key} could not be deleted; remove it by hand.",
+            settings.bucket
+        );
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{failed} S3 check(s) failed");
+    }
+    println!("\nS3 setup looks good.");
+    Ok(())
+}
+
+/// What to do about a failed `AssumeRole`.
+fn assume_role_advice(e: &anyhow::Error) -> String {
+    let Some(sdk) = e.downcast_ref::<aws_sdk_sts::error::SdkError<AssumeRoleError>>() else {
+        return format!("{e:#}");
+    };
+    let message = sdk.message().unwrap_or_default();
+    match sdk.code() {
+        Some("InvalidClientTokenId" | "SignatureDoesNotMatch" | "UnrecognizedClientException") => {
+            format!("the IAM user's access keys were rejected; check AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY ({message})")
+        }
+        Some("ExpiredToken" | "ExpiredTokenException") => {
+            format!("the AWS session token in the environment has expired ({message})")
+        }
+        Some("AccessDenied") => format!(
+            "the IAM user may not assume this role: it needs sts:AssumeRole on the role, \
+             and the role's trust policy must allow the user ({message})"
+        ),
+        Some("ValidationError") => format!(
+            "AWS_UPLOAD_ROLE_ARN isn't a valid role ARN, or the role's maximum session \
+             duration is under the 12 hours requested ({message})"
+        ),
+        Some(code) => format!("{code}: {message}"),
+        // No AWS answer: no credentials at all, or no network.
+        None => {
+            let chain = aws::error_chain(sdk);
+            if chain.contains("credential") {
+                format!(
+                    "{chain} (set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY for the IAM user)"
+                )
+            } else {
+                format!("STS could not be reached: {chain}")
+            }
+        }
+    }
+}
+
+/// What a failed probe means and what to change.
+fn advice(probe: &Probe, e: &ProbeError, bucket: &str) -> String {
+    let resource = match probe.action {
+        "s3:ListAllMyBuckets" => "*".to_string(),
+        "s3:ListBucket" | "s3:GetBucketObjectLockConfiguration" => format!("arn:aws:s3:::{bucket}"),
+        _ => format!("arn:aws:s3:::{bucket}/*"),
+    };
+    let code = e.code.as_deref().unwrap_or_default();
+    match (code, e.status) {
+        (_, None) => format!("S3 could not be reached: {}", e.message),
+        ("AccessDenied", _) | ("", Some(403)) if probe.optional => "not permitted".to_string(),
+        ("AccessDenied", _) | ("", Some(403)) => format!(
+            "AccessDenied: your role is missing {} on {resource}, or a bucket policy denies it",
+            probe.action
+        ),
+        ("NoSuchBucket", _) | ("", Some(404)) if probe.call == "HeadBucket" => format!(
+            "bucket {bucket} doesn't exist; check S3_BUCKET_NAME, or deploy the infra stack"
+        ),
+        ("PermanentRedirect" | "AuthorizationHeaderMalformed" | "IllegalLocationConstraintException", _)
+        | ("", Some(301 | 400)) => format!(
+            "bucket {bucket} is in another region; set AWS_REGION to the bucket's region"
+        ),
+        ("AccessControlListNotSupported", _) => {
+            "the bucket has ACLs disabled (Object Ownership: bucket owner enforced); unset S3_OBJECT_ACL".to_string()
+        }
+        ("ObjectLockConfigurationNotFoundError", _) => {
+            "the bucket doesn't have Object Lock enabled; unset S3_OBJECT_LOCK_MODE and S3_OBJECT_LOCK_UNTIL".to_string()
+        }
+        ("InvalidAccessKeyId" | "SignatureDoesNotMatch" | "ExpiredToken" | "InvalidToken", _) => format!(
+            "{code}: the role's temporary credentials were rejected; check the system clock ({})",
+            e.message
+        ),
+        (code, _) if code.starts_with("KMS.") => format!(
+            "{code}: the bucket's KMS key doesn't let your role use it; grant kms:GenerateDataKey and kms:Decrypt ({})",
+            e.message
+        ),
+        ("", Some(status)) => format!("HTTP {status}: {}", e.message),
+        (code, _) => format!("{code}: {}", e.message),
+    }
+}
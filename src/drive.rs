@@ -1,14 +1,35 @@
-use anyhow::Result;
+use crate::auth::TokenHolder;
+use crate::aws::{self, ChecksumAlgo, Hasher};
+use crate::config::{AmbiguousFolders, TrashFilter};
+use crate::http_log::SendLogged;
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
 use indicatif::ProgressBar;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
 
 const DRIVE_API: &str = "https://www.googleapis.com/drive/v3";
+const DRIVE_UPLOAD_API: &str = "https://www.googleapis.com/upload/drive/v3";
 
-const WORKSPACE_MIMETYPES: &[&str] = &[
+/// Piece size for resumable uploads. Drive wants multiples of 256 KiB.
+const UPLOAD_CHUNK: u64 = 8 * 1024 * 1024;
+
+/// How many times in a row a resumable upload picks up after a failure.
+const UPLOAD_RESUMES: u32 = 5;
+
+/// How far past Drive's reported size a download may run before it's cut
+/// off; a smaller excess still fails the size check once it ends.
+const SIZE_MARGIN: u64 = 1024 * 1024;
+
+/// Google-native types with no binary content to download, skipped unless
+/// `DRIVE_SKIP_MIMETYPES` says otherwise.
+pub const WORKSPACE_MIMETYPES: &[&str] = &[
     "application/vnd.google-apps.document",
     "application/vnd.google-apps.spreadsheet",
     "application/vnd.google-apps.presentation",
@@ -18,6 +39,41 @@ const WORKSPACE_MIMETYPES: &[&str] = &[
     "application/vnd.google-apps.site",
 ];
 
+const FOLDER_MIMETYPE: &str = "application/vnd.google-apps.folder";
+
+/// Shortcuts are pointers to another file and can't be fetched with `alt=media`.
+const SHORTCUT_MIMETYPE: &str = "application/vnd.google-apps.shortcut";
+
+/// How much of a download is gathered before each write to its file, unless
+/// `--download-buffer` says otherwise.
+pub const DEFAULT_WRITE_BUFFER: usize = 1 << 20;
+
+/// Listed in place of a folder id, for `--shared-with-me`: the files others
+/// have shared with this account, wherever they live.
+pub const SHARED_WITH_ME: &str = "sharedWithMe";
+
+#[derive(Debug, Deserialize)]
+pub struct ShortcutDetails {
+    #[serde(rename = "targetId")]
+    pub target_id: String,
+}
+
+/// What the signed-in account may do with a file. Fields Drive leaves out
+/// count as allowed, so the operation is just tried.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Capabilities {
+    #[serde(rename = "canDownload", default = "allowed")]
+    pub can_download: bool,
+    #[serde(rename = "canDelete", default = "allowed")]
+    pub can_delete: bool,
+    #[serde(rename = "canTrash", default = "allowed")]
+    pub can_trash: bool,
+}
+
+fn allowed() -> bool {
+    true
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DriveFile {
     pub id: String,
@@ -27,163 +83,1720 @@ pub struct DriveFile {
     /// File size in bytes as a string, as returned by the Drive API.
     /// Absent for Google Workspace native files.
     pub size: Option<String>,
+    /// Hex MD5 of the content, as computed by Drive.
+    /// Absent for Google Workspace native files.
+    #[serde(rename = "md5Checksum")]
+    pub md5_checksum: Option<String>,
+    /// Whether the file is in Drive's trash.
+    #[serde(default)]
+    pub trashed: bool,
+    /// Last modification time.
+    #[serde(rename = "modifiedTime")]
+    pub modified_time: Option<DateTime<Utc>>,
+    /// Absent in test mode and for Photos media items.
+    pub capabilities: Option<Capabilities>,
+    /// Present only for shortcut entries.
+    #[serde(rename = "shortcutDetails")]
+    pub shortcut_details: Option<ShortcutDetails>,
+    /// Set when this file was reached by resolving a shortcut. The shortcut,
+    /// not the target it points to, is what gets deleted after backup.
+    #[serde(skip)]
+    pub shortcut_id: Option<String>,
+    /// Path of the subfolder holding this file, relative to the backup
+    /// folder (`""` at the top level). Filled in by `list_tree`.
+    #[serde(skip)]
+    pub folder_path: String,
     /// Set only in test mode — points to a pre-existing local file so the
     /// download step can be skipped entirely.
     #[serde(skip)]
     pub local_path: Option<PathBuf>,
 }
 
-pub fn is_workspace_file(f: &DriveFile) -> bool {
-    WORKSPACE_MIMETYPES.contains(&f.mime_type.as_str())
+/// A failed Drive API call, with the structured details Drive returns in the
+/// error body (e.g. reason `storageQuotaExceeded` or `userRateLimitExceeded`).
+#[derive(Debug)]
+pub struct DriveApiError {
+    pub status: StatusCode,
+    pub reason: Option<String>,
+    pub domain: Option<String>,
+    pub message: String,
+    /// Value of the `Retry-After` header, if Drive sent one.
+    pub retry_after: Option<String>,
+    /// The file (or folder) the request was about, when there was one.
+    pub file_id: Option<String>,
+}
+
+impl std::fmt::Display for DriveApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Drive API error {}", self.status)?;
+        match (&self.domain, &self.reason) {
+            (Some(domain), Some(reason)) => write!(f, " [{domain}/{reason}]")?,
+            (None, Some(reason)) => write!(f, " [{reason}]")?,
+            _ => {}
+        }
+        if let Some(ref id) = self.file_id {
+            write!(f, " for file {id}")?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(ref secs) = self.retry_after {
+            write!(f, " (retry after {secs}s)")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DriveApiError {}
+
+impl DriveApiError {
+    fn from_body(
+        status: StatusCode,
+        body: &str,
+        retry_after: Option<String>,
+        file_id: Option<&str>,
+    ) -> Self {
+        #[derive(Deserialize)]
+        struct Body {
+            error: Detail,
+        }
+        #[derive(Deserialize)]
+        struct Detail {
+            message: Option<String>,
+            #[serde(default)]
+            errors: Vec<Item>,
+        }
+        #[derive(Deserialize)]
+        struct Item {
+            reason: Option<String>,
+            domain: Option<String>,
+        }
+
+        let detail = serde_json::from_str::<Body>(body).ok().map(|b| b.error);
+        let first = detail.as_ref().and_then(|d| d.errors.first());
+        Self {
+            status,
+            reason: first.and_then(|i| i.reason.clone()),
+            domain: first.and_then(|i| i.domain.clone()),
+            message: detail
+                .as_ref()
+                .and_then(|d| d.message.clone())
+                .unwrap_or_else(|| {
+                    status
+                        .canonical_reason()
+                        .unwrap_or("unknown error")
+                        .to_string()
+                }),
+            retry_after,
+            file_id: file_id.map(String::from),
+        }
+    }
+}
+
+/// The most bytes one download may write: `--max-file-size`, and with the
+/// size check on, Drive's reported size plus a margin, whichever is less.
+/// Guards against a response that never ends filling the disk.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteCap {
+    limit: u64,
+    /// Drive's size, when that's what set `limit`.
+    reported: Option<u64>,
+}
+
+impl ByteCap {
+    /// Just `--max-file-size`, for content Drive reports no size for.
+    pub fn at_most(max_file_size: Option<u64>) -> Option<Self> {
+        max_file_size.map(|limit| Self {
+            limit,
+            reported: None,
+        })
+    }
+
+    /// Fails with `TooLarge` once `received` bytes are past the cap.
+    fn check(self, received: u64) -> Result<()> {
+        if received <= self.limit {
+            return Ok(());
+        }
+        Err(TooLarge {
+            limit: self.limit,
+            reported: self.reported,
+        }
+        .into())
+    }
+}
+
+/// A download cut off by its `ByteCap`. Not retried: the next attempt would
+/// only get as far.
+#[derive(Debug)]
+pub struct TooLarge {
+    pub limit: u64,
+    pub reported: Option<u64>,
+}
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reported {
+            Some(size) => write!(
+                f,
+                "Download aborted past {} bytes: Drive reported only {size}",
+                self.limit
+            ),
+            None => write!(
+                f,
+                "Download aborted past {} bytes: larger than --max-file-size",
+                self.limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// True if `e` is a download cut off by its `ByteCap`.
+pub fn is_too_large(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<TooLarge>().is_some()
+}
+
+/// True if `e` is Drive rejecting our access token (HTTP 401).
+pub fn is_unauthorized(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<DriveApiError>()
+        .is_some_and(|d| d.status == StatusCode::UNAUTHORIZED)
+}
+
+/// True if Drive is asking us to slow down: 429, 503, or a 403 rate-limit
+/// reason such as `userRateLimitExceeded`.
+pub fn is_throttled(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<DriveApiError>().is_some_and(|d| {
+        matches!(
+            d.status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        ) || d
+            .reason
+            .as_deref()
+            .is_some_and(|r| r.ends_with("ateLimitExceeded"))
+    })
+}
+
+/// Why Drive refused a change to a file: an HTTP 403 that isn't rate
+/// limiting. Waiting doesn't help with any of these.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// The token wasn't granted the full Drive scope.
+    Scope,
+    /// This account may not change the file (not its owner, say).
+    File,
+    Other,
+}
+
+impl Denied {
+    /// What to do about it, for the per-file message.
+    pub fn advice(self) -> &'static str {
+        match self {
+            Denied::Scope => {
+                "insufficient permission — re-auth with the full Drive scope? \
+                 (delete the token file and sign in again)"
+            }
+            Denied::File => {
+                "insufficient permission on the file (only its owner, or a shared drive's \
+                 managers, can delete it)"
+            }
+            Denied::Other => "Drive refused the request (403)",
+        }
+    }
+}
+
+/// `Some` if `e` is Drive refusing the request for lack of permission.
+pub fn permission_denied(e: &anyhow::Error) -> Option<Denied> {
+    let d = e.downcast_ref::<DriveApiError>()?;
+    if d.status != StatusCode::FORBIDDEN || is_throttled(e) {
+        return None;
+    }
+    Some(match d.reason.as_deref() {
+        Some("insufficientPermissions" | "insufficientScopes") => Denied::Scope,
+        _ if d.message.contains("insufficient authentication scopes") => Denied::Scope,
+        Some("insufficientFilePermissions" | "appNotAuthorizedToFile" | "cannotDeleteFile") => {
+            Denied::File
+        }
+        _ => Denied::Other,
+    })
+}
+
+/// True if `e` is Drive reporting no such file (HTTP 404).
+pub fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<DriveApiError>()
+        .is_some_and(|d| d.status == StatusCode::NOT_FOUND)
+}
+
+trait DriveResponseExt: Sized {
+    /// Like `error_for_status`, but turns a failure into a `DriveApiError`
+    /// carrying Drive's structured error details.
+    async fn check_drive(self, file_id: Option<&str>) -> Result<Self>;
+}
+
+impl DriveResponseExt for Response {
+    async fn check_drive(self, file_id: Option<&str>) -> Result<Self> {
+        let status = self.status();
+        if status.is_success() {
+            return Ok(self);
+        }
+        let retry_after = self
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let body = self.text().await.unwrap_or_default();
+        Err(DriveApiError::from_body(status, &body, retry_after, file_id).into())
+    }
+}
+
+/// Whether `f` is one of `workspace` (`Config::workspace_mimetypes`).
+pub fn is_workspace_file(f: &DriveFile, workspace: &[String]) -> bool {
+    workspace.contains(&f.mime_type)
+}
+
+pub fn is_shortcut(f: &DriveFile) -> bool {
+    f.mime_type == SHORTCUT_MIMETYPE
+}
+
+pub fn is_folder(f: &DriveFile) -> bool {
+    f.mime_type == FOLDER_MIMETYPE
+}
+
+/// The paths of the subfolders `folders` (id and name) of the folder at
+/// `parent`. A `/` in a name is replaced as in file names, so it can't read
+/// as another level. Drive allows sibling folders with the same name; those
+/// whose names then clash get their id appended so their files can't share
+/// keys, whatever order Drive lists them in.
+fn child_paths(parent: &str, folders: &[(&str, &str)]) -> Vec<String> {
+    let segments: Vec<String> = folders
+        .iter()
+        .map(|(_, name)| crate::transfer::sanitize_filename(name))
+        .collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for segment in &segments {
+        *counts.entry(segment).or_default() += 1;
+    }
+    folders
+        .iter()
+        .zip(&segments)
+        .map(|((id, _), segment)| {
+            let name = if counts[segment.as_str()] > 1 {
+                format!("{segment}_{id}")
+            } else {
+                segment.clone()
+            };
+            if parent.is_empty() {
+                name
+            } else {
+                format!("{parent}/{name}")
+            }
+        })
+        .collect()
+}
+
+/// A subfolder found while walking the backup folder with `list_tree`.
+pub struct Subfolder {
+    pub id: String,
+    /// Relative to the backup folder, e.g. `2024/trip`.
+    pub path: String,
+    /// Number of entries (files and folders) it held when listed.
+    pub entries: usize,
+}
+
+/// Storage usage across Drive, Gmail and Photos, in bytes. Drive sends the
+/// numbers as strings; `limit` is absent for unlimited plans.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageQuota {
+    pub limit: Option<String>,
+    pub usage: String,
+    pub usage_in_drive_trash: Option<String>,
+}
+
+impl StorageQuota {
+    pub fn used(&self) -> u64 {
+        self.usage.parse().unwrap_or(0)
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit.as_deref().and_then(|l| l.parse().ok())
+    }
+
+    /// Bytes held by the trash, which still count as used.
+    pub fn in_trash(&self) -> u64 {
+        self.usage_in_drive_trash
+            .as_deref()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+impl DriveFile {
+    /// Size in bytes as reported by Drive, if known.
+    pub fn size_bytes(&self) -> Option<u64> {
+        self.size.as_deref().and_then(|s| s.parse().ok())
+    }
+
+    pub fn can_download(&self) -> bool {
+        self.capabilities.as_ref().is_none_or(|c| c.can_download)
+    }
+
+    /// Whether the file (or the shortcut it was reached by) may be removed:
+    /// moved to the trash when `trash` is set, deleted outright otherwise.
+    pub fn can_remove(&self, trash: bool) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_none_or(|c| if trash { c.can_trash } else { c.can_delete })
+    }
+
+    /// The Drive id to delete once this file is safely archived.
+    pub fn delete_id(&self) -> &str {
+        self.shortcut_id.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// A stored version of a file's content, from `revisions.list`. Drive
+/// lists them oldest first; the last is the current content.
+#[derive(Deserialize)]
+pub struct Revision {
+    pub id: String,
+    pub size: Option<String>,
+    #[serde(rename = "md5Checksum")]
+    pub md5_checksum: Option<String>,
+}
+
+impl Revision {
+    pub fn size_bytes(&self) -> Option<u64> {
+        self.size.as_deref().and_then(|s| s.parse().ok())
+    }
+}
+
+/// One page of a `files.list` response.
+#[derive(Deserialize)]
+struct ListPage {
+    files: Vec<DriveFile>,
+    #[serde(rename = "nextPageToken")]
+    next_page_token: Option<String>,
+    /// Drive sets this when it couldn't search every corpus, meaning the
+    /// result set may be missing files.
+    #[serde(rename = "incompleteSearch", default)]
+    incomplete_search: bool,
 }
 
 pub struct DriveClient<'a> {
     http: &'a Client,
-    access_token: String,
+    tokens: TokenHolder,
+    /// Fail downloads whose byte count differs from Drive's `size`; off
+    /// with `--no-size-check`, which only warns.
+    size_check: bool,
+    /// List folders most recently modified first (`--newest`).
+    newest_first: bool,
+    /// `--max-file-size`.
+    max_file_size: Option<u64>,
+    /// `--since-object`: list only files modified after this.
+    modified_after: Option<DateTime<Utc>>,
+    /// `--download-buffer`.
+    write_buffer: usize,
+    /// `--checksum-algo`: computed as downloads are written, for the upload.
+    checksum: ChecksumAlgo,
 }
 
 impl<'a> DriveClient<'a> {
-    pub fn new(http: &'a Client, access_token: String) -> Self {
-        Self { http, access_token }
+    pub fn new(http: &'a Client, tokens: TokenHolder) -> Self {
+        Self {
+            http,
+            tokens,
+            size_check: true,
+            newest_first: false,
+            max_file_size: None,
+            modified_after: None,
+            write_buffer: DEFAULT_WRITE_BUFFER,
+            checksum: ChecksumAlgo::None,
+        }
+    }
+
+    pub fn with_size_check(mut self, on: bool) -> Self {
+        self.size_check = on;
+        self
+    }
+
+    pub fn with_newest_first(mut self, on: bool) -> Self {
+        self.newest_first = on;
+        self
+    }
+
+    pub fn with_max_file_size(mut self, max: Option<u64>) -> Self {
+        self.max_file_size = max;
+        self
+    }
+
+    pub fn with_modified_after(mut self, after: Option<DateTime<Utc>>) -> Self {
+        self.modified_after = after;
+        self
     }
 
-    pub fn set_token(&mut self, access_token: String) {
-        self.access_token = access_token;
+    pub fn with_write_buffer(mut self, size: usize) -> Self {
+        self.write_buffer = size;
+        self
     }
 
-    pub async fn find_folder(&self, name: &str) -> Result<String> {
+    pub fn with_checksum(mut self, checksum: ChecksumAlgo) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// The cap on a download of content Drive says is `reported` bytes.
+    pub fn byte_cap(&self, reported: Option<u64>) -> Option<ByteCap> {
+        let from_drive = reported.filter(|_| self.size_check).map(|size| ByteCap {
+            limit: size.saturating_add(SIZE_MARGIN),
+            reported: Some(size),
+        });
+        match (from_drive, ByteCap::at_most(self.max_file_size)) {
+            (Some(d), Some(f)) => Some(if f.limit < d.limit { f } else { d }),
+            (d, f) => d.or(f),
+        }
+    }
+
+    /// Refreshes the shared token if it's close to expiry.
+    pub async fn refresh(&self) -> Result<()> {
+        self.tokens.refresh(false, None).await
+    }
+
+    /// Sends the request built by `build` with the current access token. On a
+    /// 401 the token is refreshed (regardless of its recorded expiry, as the
+    /// local clock may be wrong) and the request is retried once.
+    async fn send(
+        &self,
+        file_id: Option<&str>,
+        build: impl Fn(&Client) -> RequestBuilder,
+    ) -> Result<Response> {
+        // Read per request: another task may have refreshed it since.
+        let sent_with = self.tokens.access_token();
+        let result = build(self.http)
+            .bearer_auth(&sent_with)
+            .send_logged()
+            .await?
+            .check_drive(file_id)
+            .await;
+        match result {
+            Err(e) if is_unauthorized(&e) => {
+                self.tokens.refresh(true, Some(&sent_with)).await?;
+                build(self.http)
+                    .bearer_auth(self.tokens.access_token())
+                    .send_logged()
+                    .await?
+                    .check_drive(file_id)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// The account's storage usage (`about.get`).
+    pub async fn storage_quota(&self) -> Result<StorageQuota> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct About {
+            storage_quota: StorageQuota,
+        }
+        let about: About = self
+            .send(None, |http| {
+                http.get(format!("{DRIVE_API}/about"))
+                    .query(&[("fields", "storageQuota")])
+            })
+            .await?
+            .json()
+            .await?;
+        Ok(about.storage_quota)
+    }
+
+    /// A cheap authenticated call (`about.get`), to check that Google is
+    /// reachable again.
+    pub async fn probe(&self) -> Result<()> {
+        self.send(None, |http| {
+            http.get(format!("{DRIVE_API}/about"))
+                .query(&[("fields", "kind")])
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// The ids of the folders named `name` — anywhere in Drive, or only
+    /// directly inside `parent` when given — narrowed down by `policy` when
+    /// there's more than one.
+    pub async fn find_folders(
+        &self,
+        name: &str,
+        parent: Option<&str>,
+        policy: AmbiguousFolders,
+    ) -> Result<Vec<String>> {
         #[derive(Deserialize)]
         struct FolderEntry {
             id: String,
+            #[serde(rename = "modifiedTime")]
+            modified_time: Option<DateTime<Utc>>,
         }
         #[derive(Deserialize)]
         struct Resp {
             files: Vec<FolderEntry>,
         }
 
+        let mut q = format!(
+            "name={} and mimeType='application/vnd.google-apps.folder' and trashed=false",
+            quote(name)
+        );
+        if let Some(parent) = parent {
+            q.push_str(&format!(" and {} in parents", quote(parent)));
+        }
+        let place = match parent {
+            Some(parent) => format!("in folder {parent}"),
+            None => "in Google Drive".to_string(),
+        };
+
         let resp: Resp = self
-            .http
-            .get(format!("{DRIVE_API}/files"))
-            .bearer_auth(&self.access_token)
-            .query(&[
-                (
-                    "q",
-                    format!("name='{name}' and mimeType='application/vnd.google-apps.folder' and trashed=false"),
-                ),
-                ("fields", "files(id,name)".to_string()),
-            ])
-            .send()
+            .send(parent, |http| {
+                http.get(format!("{DRIVE_API}/files")).query(&[
+                    ("q", q.clone()),
+                    ("fields", "files(id,name,modifiedTime)".to_string()),
+                ])
+            })
             .await?
-            .error_for_status()?
             .json()
             .await?;
 
-        if resp.files.is_empty() {
-            anyhow::bail!("No folder named \"{name}\" found in Google Drive");
+        let mut folders = resp.files;
+        if folders.is_empty() {
+            anyhow::bail!("No folder named \"{name}\" found {place}");
         }
-        if resp.files.len() > 1 {
-            eprintln!(
-                "Warning: {} folders named \"{name}\", using the first",
-                resp.files.len()
-            );
+        if folders.len() == 1 {
+            return Ok(vec![folders.remove(0).id]);
+        }
+
+        let count = folders.len();
+        match policy {
+            AmbiguousFolders::Fail => {
+                let ids: Vec<String> = folders
+                    .iter()
+                    .map(|f| match f.modified_time {
+                        Some(t) => format!("  {} (modified {})", f.id, t.format("%Y-%m-%d %H:%M")),
+                        None => format!("  {}", f.id),
+                    })
+                    .collect();
+                anyhow::bail!(
+                    "{count} folders named \"{name}\" found {place}:\n{}\n\
+                     Rename the extra ones, or pass --on-ambiguous=first|newest|all",
+                    ids.join("\n")
+                );
+            }
+            AmbiguousFolders::First => {
+                eprintln!("Warning: {count} folders named \"{name}\", using the first");
+                Ok(vec![folders.remove(0).id])
+            }
+            AmbiguousFolders::Newest => {
+                let newest = folders.into_iter().max_by_key(|f| f.modified_time).unwrap();
+                eprintln!(
+                    "Warning: {count} folders named \"{name}\", using the newest ({})",
+                    newest.id
+                );
+                Ok(vec![newest.id])
+            }
+            AmbiguousFolders::All => {
+                eprintln!("Warning: {count} folders named \"{name}\", backing up all of them");
+                Ok(folders.into_iter().map(|f| f.id).collect())
+            }
+        }
+    }
+
+    pub async fn list_files(
+        &self,
+        folder_id: &str,
+        trashed: TrashFilter,
+    ) -> Result<Vec<DriveFile>> {
+        let mut all = Vec::new();
+        let mut pages = std::pin::pin!(self.list_pages(folder_id, trashed));
+        while let Some(page) = pages.next().await {
+            all.extend(page?);
+        }
+        Ok(all)
+    }
+
+    /// The listing of `folder_id` one page at a time, fetched as the stream
+    /// is polled, so a caller can start on the first page while the rest
+    /// are still to come. Ends after the first error.
+    pub fn list_pages<'s>(
+        &'s self,
+        folder_id: &'s str,
+        trashed: TrashFilter,
+    ) -> impl Stream<Item = Result<Vec<DriveFile>>> + 's {
+        // State: `Some(token)` for the next page to fetch (`token` is `None`
+        // for the first) or `None` once done, and whether Drive has reported
+        // the search incomplete yet.
+        stream::unfold(
+            (Some(None), false),
+            move |(next, warned): (Option<Option<String>>, bool)| async move {
+                let page_token = next?;
+                match self.list_page(folder_id, trashed, page_token).await {
+                    Ok(page) => {
+                        if page.incomplete_search && !warned {
+                            eprintln!(
+                                "\n*** WARNING: Drive reported an incomplete search for folder {folder_id}. ***\n\
+                                 *** The listing may be missing files, so this run may not back up everything. ***\n\
+                                 Consider narrowing the query or targeting a specific folder id, then run again.\n"
+                            );
+                        }
+                        let state = (
+                            page.next_page_token.map(Some),
+                            warned || page.incomplete_search,
+                        );
+                        Some((Ok(page.files), state))
+                    }
+                    Err(e) => Some((Err(e), (None, warned))),
+                }
+            },
+        )
+    }
+
+    /// Fetches one page of `folder_id`'s listing.
+    async fn list_page(
+        &self,
+        folder_id: &str,
+        trashed: TrashFilter,
+        page_token: Option<String>,
+    ) -> Result<ListPage> {
+        let scope = if folder_id == SHARED_WITH_ME {
+            "sharedWithMe = true".to_string()
+        } else {
+            format!("'{folder_id}' in parents")
+        };
+        let mut q = match trashed.query() {
+            Some(clause) => format!("{scope} and {clause}"),
+            None => scope,
+        };
+        if let Some(after) = self.modified_after {
+            // Folders always, so a recursive listing still finds what
+            // changed inside older ones.
+            q.push_str(&format!(
+                " and (modifiedTime > '{}' or mimeType = '{FOLDER_MIMETYPE}')",
+                after.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            ));
+        }
+        let mut params = vec![
+            ("q".to_string(), q),
+            (
+                "fields".to_string(),
+                // Include size so we can verify completeness after download.
+                "nextPageToken,incompleteSearch,files(id,name,mimeType,size,md5Checksum,trashed,modifiedTime,shortcutDetails(targetId),capabilities(canDownload,canDelete,canTrash))".to_string(),
+            ),
+            ("pageSize".to_string(), "1000".to_string()),
+        ];
+        if self.newest_first {
+            params.push(("orderBy".to_string(), "modifiedTime desc".to_string()));
+        }
+        if let Some(t) = page_token {
+            params.push(("pageToken".to_string(), t));
+        }
+
+        Ok(self
+            .send(Some(folder_id), |http| {
+                http.get(format!("{DRIVE_API}/files")).query(&params)
+            })
+            .await?
+            .json()
+            .await?)
+    }
+
+    /// Lists every file under `root_id`, descending into subfolders. Files
+    /// come back with `folder_path` set; the subfolders themselves (never
+    /// the root) are returned alongside. With `TrashFilter::Only`, untrashed
+    /// folders are still walked to find trashed files inside them. Up to
+    /// `concurrency` folders are listed at once.
+    pub async fn list_tree(
+        &self,
+        root_id: &str,
+        trashed: TrashFilter,
+        concurrency: usize,
+    ) -> Result<(Vec<DriveFile>, Vec<Subfolder>)> {
+        let query = match trashed {
+            TrashFilter::Exclude => TrashFilter::Exclude,
+            TrashFilter::Include | TrashFilter::Only => TrashFilter::Include,
+        };
+        let mut files = Vec::new();
+        let mut subfolders = Vec::new();
+        let mut pending = vec![(root_id.to_string(), String::new())];
+        let mut listing = FuturesUnordered::new();
+        let mut walked = 0;
+
+        loop {
+            while listing.len() < concurrency {
+                let Some((folder_id, path)) = pending.pop() else {
+                    break;
+                };
+                listing.push(async move {
+                    let entries = self.list_files(&folder_id, query).await;
+                    (folder_id, path, entries)
+                });
+            }
+            let Some((folder_id, path, entries)) = listing.next().await else {
+                break;
+            };
+            let entries = entries?;
+            walked += 1;
+            let count = entries.len();
+            let folders: Vec<(&str, &str)> = entries
+                .iter()
+                .filter(|e| is_folder(e))
+                .map(|e| (e.id.as_str(), e.name.as_str()))
+                .collect();
+            let children: Vec<(String, String)> = folders
+                .iter()
+                .map(|(id, _)| id.to_string())
+                .zip(child_paths(&path, &folders))
+                .collect();
+            pending.extend(children);
+            for mut entry in entries {
+                if !is_folder(&entry) && (trashed != TrashFilter::Only || entry.trashed) {
+                    entry.folder_path = path.clone();
+                    files.push(entry);
+                }
+            }
+            if folder_id != root_id {
+                subfolders.push(Subfolder {
+                    id: folder_id,
+                    path,
+                    entries: count,
+                });
+            }
+        }
+        // Folders finish in any order; keep the backup order stable.
+        files.sort_by(|a, b| a.folder_path.cmp(&b.folder_path));
+        println!("  Walked {walked} folder(s), found {} file(s)", files.len());
+        Ok((files, subfolders))
+    }
+
+    /// Trashes the subfolders this run emptied, deepest first so a parent
+    /// left empty by pruning its children goes too. Folders that were
+    /// already empty when listed are left alone. Returns how many were
+    /// trashed.
+    pub async fn prune_emptied(&self, folders: &[Subfolder]) -> usize {
+        let mut by_depth: Vec<&Subfolder> = folders.iter().filter(|f| f.entries > 0).collect();
+        by_depth.sort_by_key(|f| std::cmp::Reverse(f.path.matches('/').count()));
+
+        let mut pruned = 0;
+        for folder in by_depth {
+            let result = async {
+                if !self.is_empty_folder(&folder.id).await? {
+                    return Ok(false);
+                }
+                self.trash(&folder.id).await?;
+                anyhow::Ok(true)
+            };
+            match result.await {
+                Ok(true) => {
+                    println!("  Trashed empty folder {}", folder.path);
+                    pruned += 1;
+                }
+                Ok(false) => {}
+                Err(e) => eprintln!("  Warning: could not prune folder {}: {e:#}", folder.path),
+            }
+        }
+        pruned
+    }
+
+    async fn is_empty_folder(&self, folder_id: &str) -> Result<bool> {
+        #[derive(Deserialize)]
+        struct Resp {
+            files: Vec<serde_json::Value>,
+        }
+        let resp: Resp = self
+            .send(Some(folder_id), |http| {
+                http.get(format!("{DRIVE_API}/files")).query(&[
+                    ("q", format!("'{folder_id}' in parents and trashed=false")),
+                    ("fields", "files(id)".to_string()),
+                    ("pageSize", "1".to_string()),
+                ])
+            })
+            .await?
+            .json()
+            .await?;
+        Ok(resp.files.is_empty())
+    }
+
+    /// The id of an untrashed item named `name` directly in `parent`, a
+    /// folder or otherwise as `folder` says.
+    pub async fn find_child(
+        &self,
+        parent: &str,
+        name: &str,
+        folder: bool,
+    ) -> Result<Option<String>> {
+        #[derive(Deserialize)]
+        struct Entry {
+            id: String,
+        }
+        #[derive(Deserialize)]
+        struct Resp {
+            files: Vec<Entry>,
+        }
+        let kind = if folder { "=" } else { "!=" };
+        let query = format!(
+            "{} in parents and name={} and mimeType{kind}'{FOLDER_MIMETYPE}' and trashed=false",
+            quote(parent),
+            quote(name)
+        );
+        let resp: Resp = self
+            .send(Some(parent), |http| {
+                http.get(format!("{DRIVE_API}/files")).query(&[
+                    ("q", query.as_str()),
+                    ("fields", "files(id)"),
+                    ("pageSize", "1"),
+                ])
+            })
+            .await?
+            .json()
+            .await?;
+        Ok(resp.files.into_iter().next().map(|f| f.id))
+    }
+
+    /// Creates a folder named `name` in `parent`, returning its id.
+    pub async fn create_folder(&self, parent: &str, name: &str) -> Result<String> {
+        let created: Created = self
+            .send(Some(parent), |http| {
+                http.post(format!("{DRIVE_API}/files"))
+                    .query(&[("fields", "id")])
+                    .json(&serde_json::json!({
+                        "name": name,
+                        "mimeType": FOLDER_MIMETYPE,
+                        "parents": [parent],
+                    }))
+            })
+            .await?
+            .json()
+            .await?;
+        Ok(created.id)
+    }
+
+    /// Uploads `path` as a new file `name` in `parent` with Drive's resumable
+    /// protocol: opens an upload session, then sends the content in
+    /// `UPLOAD_CHUNK` pieces. When a piece fails, Drive is asked how much it
+    /// already has and the upload carries on from there, so a dropped
+    /// connection costs at most one piece. Returns the new file's id.
+    pub async fn upload_resumable(
+        &self,
+        parent: &str,
+        name: &str,
+        path: &Path,
+        mime_type: Option<&str>,
+    ) -> Result<String> {
+        let len = tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("Cannot read {}", path.display()))?
+            .len();
+        let mime_type = mime_type.unwrap_or("application/octet-stream");
+        let session = self
+            .send(Some(parent), |http| {
+                http.post(format!("{DRIVE_UPLOAD_API}/files"))
+                    .query(&[("uploadType", "resumable"), ("fields", "id")])
+                    .header("X-Upload-Content-Type", mime_type)
+                    .header("X-Upload-Content-Length", len)
+                    .json(&serde_json::json!({ "name": name, "parents": [parent] }))
+            })
+            .await?;
+        let session = session
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .context("Drive opened no upload session (no Location header)")?
+            .to_string();
+
+        let mut file = File::open(path).await?;
+        let mut offset = 0u64;
+        let mut failures = 0;
+        loop {
+            let end = (offset + UPLOAD_CHUNK).min(len);
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+            let mut chunk = vec![0u8; (end - offset) as usize];
+            file.read_exact(&mut chunk).await?;
+            let range = if len == 0 {
+                "bytes */0".to_string()
+            } else {
+                format!("bytes {offset}-{}/{len}", end - 1)
+            };
+
+            // The session URL itself authorizes the upload.
+            let sent = self
+                .http
+                .put(&session)
+                .header(reqwest::header::CONTENT_RANGE, range)
+                .body(chunk)
+                .send_logged()
+                .await;
+            let progress = match sent {
+                Ok(response) => upload_progress(response).await,
+                Err(e) => Err(e.into()),
+            };
+            match progress {
+                Ok(UploadProgress::Done(id)) => return Ok(id),
+                Ok(UploadProgress::Received(n)) => {
+                    offset = n;
+                    failures = 0;
+                }
+                Err(e) if is_resumable(&e) && failures < UPLOAD_RESUMES => {
+                    failures += 1;
+                    eprintln!(
+                        "  upload of {name} interrupted at {offset}/{len} bytes: {e:#} — resuming ..."
+                    );
+                    tokio::time::sleep(std::time::Duration::from_secs(5 * failures as u64)).await;
+                    // An empty PUT with `bytes */len` asks where the session is.
+                    let status = self
+                        .http
+                        .put(&session)
+                        .header(reqwest::header::CONTENT_RANGE, format!("bytes */{len}"))
+                        .send_logged()
+                        .await;
+                    // If still offline, the piece is tried again after the
+                    // next wait.
+                    if let Ok(response) = status {
+                        match upload_progress(response).await {
+                            Ok(UploadProgress::Done(id)) => return Ok(id),
+                            Ok(UploadProgress::Received(n)) => offset = n,
+                            Err(e) if is_resumable(&e) => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Moves a file or folder to the Drive trash.
+    pub async fn trash(&self, file_id: &str) -> Result<()> {
+        self.send(Some(file_id), |http| {
+            http.patch(format!("{DRIVE_API}/files/{file_id}"))
+                .json(&serde_json::json!({ "trashed": true }))
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Fetches metadata for a single file by id.
+    pub async fn get_file(&self, file_id: &str) -> Result<DriveFile> {
+        let response = self
+            .send(Some(file_id), |http| {
+                http.get(format!("{DRIVE_API}/files/{file_id}")).query(&[(
+                    "fields",
+                    "id,name,mimeType,size,md5Checksum,trashed,modifiedTime,shortcutDetails(targetId),capabilities(canDownload,canDelete,canTrash)",
+                )])
+            })
+            .await
+            .map_err(|e| {
+                // Drive answers 404 both for missing files and for ones this
+                // account can't see.
+                if is_not_found(&e) {
+                    e.context(format!("File {file_id} not found or no access"))
+                } else {
+                    e
+                }
+            })?;
+        Ok(response.json().await?)
+    }
+
+    /// Looks up the file a shortcut points to.
+    pub async fn resolve_shortcut(&self, shortcut: &DriveFile) -> Result<DriveFile> {
+        let target_id = shortcut
+            .shortcut_details
+            .as_ref()
+            .map(|d| d.target_id.as_str())
+            .context("Shortcut has no target")?;
+        let mut target = self.get_file(target_id).await?;
+        if is_folder(&target) {
+            anyhow::bail!("Shortcut points to a folder");
         }
-        Ok(resp.files.into_iter().next().unwrap().id)
+        target.shortcut_id = Some(shortcut.id.clone());
+        // The shortcut is what gets removed, so its permissions apply there.
+        if let (Some(t), Some(s)) = (&mut target.capabilities, &shortcut.capabilities) {
+            t.can_delete = s.can_delete;
+            t.can_trash = s.can_trash;
+        }
+        // Archive it where the shortcut was, not where the target lives.
+        target.folder_path = shortcut.folder_path.clone();
+        Ok(target)
+    }
+
+    /// Starts fetching a file's content; the body is read by the caller.
+    pub async fn open_media(&self, file: &DriveFile) -> Result<Response> {
+        self.media(file, None).await
     }
 
-    pub async fn list_files(&self, folder_id: &str) -> Result<Vec<DriveFile>> {
+    /// Every revision Drive keeps of `file_id`, oldest first. Drive answers
+    /// 403 or 404 when the account may not see a file's history.
+    pub async fn list_revisions(&self, file_id: &str) -> Result<Vec<Revision>> {
         #[derive(Deserialize)]
         struct Resp {
-            files: Vec<DriveFile>,
+            #[serde(default)]
+            revisions: Vec<Revision>,
             #[serde(rename = "nextPageToken")]
             next_page_token: Option<String>,
         }
-
-        let mut all = Vec::new();
+        let mut revisions = Vec::new();
         let mut page_token: Option<String> = None;
-
         loop {
             let mut params = vec![
                 (
-                    "q".to_string(),
-                    format!("'{folder_id}' in parents and trashed=false"),
+                    "fields",
+                    "nextPageToken,revisions(id,size,md5Checksum)".to_string(),
                 ),
-                (
-                    "fields".to_string(),
-                    // Include size so we can verify completeness after download.
-                    "nextPageToken,files(id,name,mimeType,size)".to_string(),
-                ),
-                ("pageSize".to_string(), "1000".to_string()),
+                ("pageSize", "200".to_string()),
             ];
-            if let Some(ref t) = page_token {
-                params.push(("pageToken".to_string(), t.clone()));
+            if let Some(ref token) = page_token {
+                params.push(("pageToken", token.clone()));
             }
-
             let resp: Resp = self
-                .http
-                .get(format!("{DRIVE_API}/files"))
-                .bearer_auth(&self.access_token)
-                .query(&params)
-                .send()
+                .send(Some(file_id), |http| {
+                    http.get(format!("{DRIVE_API}/files/{file_id}/revisions"))
+                        .query(&params)
+                })
                 .await?
-                .error_for_status()?
                 .json()
                 .await?;
-
-            all.extend(resp.files);
-            page_token = resp.next_page_token;
-            if page_token.is_none() {
-                break;
+            revisions.extend(resp.revisions);
+            match resp.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(revisions),
             }
         }
-
-        Ok(all)
     }
 
-    pub async fn download(&self, file: &DriveFile, dest: &Path, bar: &ProgressBar) -> Result<()> {
-        let mut response = self
-            .http
-            .get(format!("{DRIVE_API}/files/{}", file.id))
-            .bearer_auth(&self.access_token)
+    /// Starts fetching one revision's content, like `open_media`.
+    pub async fn open_revision(&self, file_id: &str, revision_id: &str) -> Result<Response> {
+        self.send(Some(file_id), |http| {
+            http.get(format!(
+                "{DRIVE_API}/files/{file_id}/revisions/{revision_id}"
+            ))
             .query(&[("alt", "media")])
-            .send()
-            .await?
-            .error_for_status()?;
+        })
+        .await
+    }
 
-        if let Some(expected) = file.size.as_deref().and_then(|s| s.parse::<u64>().ok()) {
-            bar.set_length(expected);
+    /// Like `open_media`, but asks for the content from byte `offset` on.
+    async fn open_media_from(&self, file: &DriveFile, offset: u64) -> Result<Response> {
+        self.media(file, Some(offset)).await
+    }
+
+    /// Requests `file`'s content, from `offset` on if given. Drive may
+    /// answer a big file with its "can't scan for viruses" page instead;
+    /// the download is then asked for again with the page's confirm token,
+    /// and fails rather than pass the page off as the file.
+    async fn media(&self, file: &DriveFile, offset: Option<u64>) -> Result<Response> {
+        let response = self.media_request(file, offset, &[]).await?;
+        if !is_html_page(content_type(&response), &file.mime_type) {
+            return Ok(response);
         }
+        let page = read_page(response).await?;
+        let Some(confirm) = confirm_params(&page) else {
+            anyhow::bail!(
+                "Drive sent an HTML page instead of the content of {} ({})",
+                file.name,
+                file.id
+            );
+        };
+        let response = self.media_request(file, offset, &confirm).await?;
+        if is_html_page(content_type(&response), &file.mime_type) {
+            anyhow::bail!(
+                "Drive sent its download confirmation page again for {} ({})",
+                file.name,
+                file.id
+            );
+        }
+        Ok(response)
+    }
+
+    /// One request for `file`'s content, with any `confirm` parameters.
+    async fn media_request(
+        &self,
+        file: &DriveFile,
+        offset: Option<u64>,
+        confirm: &[(&str, String)],
+    ) -> Result<Response> {
+        self.send(Some(&file.id), |http| {
+            let request = http
+                .get(format!("{DRIVE_API}/files/{}", file.id))
+                .query(&[("alt", "media")])
+                .query(confirm);
+            match offset {
+                Some(offset) => request.header(reqwest::header::RANGE, format!("bytes={offset}-")),
+                None => request,
+            }
+        })
+        .await
+    }
 
-        let mut f = File::create(dest).await?;
-        let mut bytes_written: u64 = 0;
-        while let Some(chunk) = response.chunk().await? {
-            bytes_written += chunk.len() as u64;
-            bar.set_position(bytes_written);
-            f.write_all(&chunk).await?;
+    /// Downloads `file` into `dest`. Whatever an earlier, interrupted attempt
+    /// left at `dest` is resumed with a `Range` request rather than fetched
+    /// again, so callers remove stale files before their first attempt and
+    /// failed ones after their last. Returns the file's `--checksum-algo`
+    /// checksum, computed on the way, when it goes up in a single PUT.
+    pub async fn download(
+        &self,
+        file: &DriveFile,
+        dest: &Path,
+        bar: &ProgressBar,
+    ) -> Result<Option<String>> {
+        let expected = file.size_bytes();
+        if let Some(expected) = expected {
+            bar.set_length(expected);
         }
-        f.flush().await?;
 
-        // Verify the downloaded byte count against the size reported by Drive.
-        // This catches truncated downloads before we attempt to upload them.
-        if let Some(expected) = file.size.as_deref().and_then(|s| s.parse::<u64>().ok()) {
-            if bytes_written != expected {
-                // Remove the incomplete file so we don't leave garbage behind.
-                let _ = tokio::fs::remove_file(dest).await;
-                anyhow::bail!(
-                    "Incomplete download: expected {expected} bytes, received {bytes_written} bytes"
-                );
+        let cap = self.byte_cap(expected);
+        let have = match tokio::fs::metadata(dest).await {
+            Ok(m) if expected.is_some_and(|e| m.len() > 0 && m.len() <= e) => m.len(),
+            _ => 0,
+        };
+        // A multipart upload checksums each part as it reads it instead.
+        let mut hasher = match expected {
+            Some(size) if size <= aws::MULTIPART_THRESHOLD => self.checksum.hasher(),
+            _ => Hasher::None,
+        };
+        let bytes_written = if have == 0 {
+            write_response(
+                self.open_media(file).await?,
+                dest,
+                bar,
+                cap,
+                self.write_buffer,
+                &mut hasher,
+            )
+            .await?
+        } else {
+            let (status, response) = match self.open_media_from(file, have).await {
+                Ok(response) => (response.status(), Some(response)),
+                Err(e) if is_range_not_satisfiable(&e) => (StatusCode::RANGE_NOT_SATISFIABLE, None),
+                Err(e) => return Err(e),
+            };
+            match (range_outcome(status, have, expected), response) {
+                (RangeOutcome::Append, Some(response)) => {
+                    bar.set_position(have);
+                    hash_prefix(dest, have, &mut hasher).await?;
+                    have + append_response(
+                        response,
+                        dest,
+                        have,
+                        bar,
+                        cap,
+                        self.write_buffer,
+                        &mut hasher,
+                    )
+                    .await?
+                }
+                (RangeOutcome::Complete, _) => {
+                    bar.set_position(have);
+                    hash_prefix(dest, have, &mut hasher).await?;
+                    have
+                }
+                // Start over, on the full response if that's what came.
+                (_, response) => {
+                    let response = match response {
+                        Some(response) => response,
+                        None => self.open_media(file).await?,
+                    };
+                    write_response(response, dest, bar, cap, self.write_buffer, &mut hasher).await?
+                }
             }
+        };
+
+        // The file is left for a retry to resume (or start over, if it's too
+        // long); callers remove it when they give up.
+        self.check_size(file, bytes_written)?;
+        Ok(hasher.finish())
+    }
+
+    /// Verifies the downloaded byte count against the size reported by
+    /// Drive. This catches truncated downloads before we attempt to upload
+    /// them. With `--no-size-check` a mismatch is only reported.
+    pub fn check_size(&self, file: &DriveFile, received: u64) -> Result<()> {
+        let Some(expected) = file.size_bytes() else {
+            return Ok(());
+        };
+        if received == expected {
+            return Ok(());
         }
+        if !self.size_check {
+            eprintln!(
+                "Warning: \"{}\" ({}): Drive reported {expected} bytes but sent {received}; \
+                 keeping it anyway (--no-size-check)",
+                file.name, file.id
+            );
+            return Ok(());
+        }
+        anyhow::bail!("Incomplete download: expected {expected} bytes, received {received} bytes")
+    }
+
+    /// Drive's reported size when downloads are checked against it, for a
+    /// streamed upload to verify.
+    pub fn expected_size(&self, file: &DriveFile) -> Option<u64> {
+        file.size_bytes().filter(|_| self.size_check)
+    }
+
+    /// Downloads `file` into memory, for small files with `--temp-in-memory`.
+    /// Checked against the Drive-reported size like `download`; a retry
+    /// starts over.
+    pub async fn download_bytes(&self, file: &DriveFile, bar: &ProgressBar) -> Result<Bytes> {
+        let expected = file.size_bytes();
+        if let Some(expected) = expected {
+            bar.set_length(expected);
+        }
+        let response = self.open_media(file).await?;
+        let mut buf = BytesMut::with_capacity(expected.unwrap_or(0) as usize);
+        let mut chunks = std::pin::pin!(body_chunks(response, bar, self.byte_cap(expected)));
+        while let Some(chunk) = chunks.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        self.check_size(file, buf.len() as u64)?;
+        Ok(buf.freeze())
+    }
 
+    /// Exports a Google Workspace file to `mime_type` (e.g. `application/pdf`).
+    /// Drive doesn't report a size for exports, so the bar shows bytes only.
+    pub async fn export(
+        &self,
+        file: &DriveFile,
+        mime_type: &str,
+        dest: &Path,
+        bar: &ProgressBar,
+    ) -> Result<()> {
+        let response = self
+            .send(Some(&file.id), |http| {
+                http.get(format!("{DRIVE_API}/files/{}/export", file.id))
+                    .query(&[("mimeType", mime_type)])
+            })
+            .await?;
+
+        write_response(
+            response,
+            dest,
+            bar,
+            self.byte_cap(None),
+            self.write_buffer,
+            &mut Hasher::None,
+        )
+        .await?;
         Ok(())
     }
 
     pub async fn delete(&self, file_id: &str) -> Result<()> {
-        self.http
-            .delete(format!("{DRIVE_API}/files/{file_id}"))
-            .bearer_auth(&self.access_token)
-            .send()
-            .await?
-            .error_for_status()?;
+        self.send(Some(file_id), |http| {
+            http.delete(format!("{DRIVE_API}/files/{file_id}"))
+        })
+        .await?;
         Ok(())
     }
 }
+
+/// Where a resumable upload stands after a request.
+enum UploadProgress {
+    /// `308 Resume Incomplete`: Drive holds this many bytes so far.
+    Received(u64),
+    /// The upload finished and created the file with this id.
+    Done(String),
+}
+
+async fn upload_progress(response: Response) -> Result<UploadProgress> {
+    if response.status() == StatusCode::PERMANENT_REDIRECT {
+        // `Range: bytes=0-N` once anything has arrived.
+        let received = response
+            .headers()
+            .get(reqwest::header::RANGE)
+            .and_then(|r| r.to_str().ok())
+            .and_then(|r| r.rsplit('-').next())
+            .and_then(|n| n.parse::<u64>().ok())
+            .map_or(0, |last| last + 1);
+        return Ok(UploadProgress::Received(received));
+    }
+    let created: Created = response.check_drive(None).await?.json().await?;
+    Ok(UploadProgress::Done(created.id))
+}
+
+/// Failures worth resuming an upload session after: the connection dropped,
+/// or Drive answered with a server error.
+fn is_resumable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>().is_some()
+        || e.downcast_ref::<DriveApiError>()
+            .is_some_and(|d| d.status.is_server_error())
+}
+
+/// The media type of `response`, without parameters.
+fn content_type(response: &Response) -> Option<&str> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)?
+        .to_str()
+        .ok()?
+        .split(';')
+        .next()
+        .map(str::trim)
+}
+
+/// Whether a download answered with `content_type` is an HTML page rather
+/// than the content of a file Drive lists as `mime_type`.
+fn is_html_page(content_type: Option<&str>, mime_type: &str) -> bool {
+    content_type.is_some_and(|t| t.eq_ignore_ascii_case("text/html"))
+        && !mime_type.eq_ignore_ascii_case("text/html")
+}
+
+/// Reading more than this of an HTML page answering a download means
+/// it's not Drive's confirmation page.
+const MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+async fn read_page(mut response: Response) -> Result<String> {
+    let mut page = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        page.extend_from_slice(&chunk);
+        if page.len() > MAX_PAGE_BYTES {
+            anyhow::bail!("Drive sent a large HTML page instead of the file's content");
+        }
+    }
+    Ok(String::from_utf8_lossy(&page).into_owned())
+}
+
+/// The query parameters Drive's virus-scan warning page confirms a download
+/// with: its `confirm` token, and the `uuid` newer pages pair it with. Found
+/// in the page's download link or in its form's hidden inputs.
+fn confirm_params(page: &str) -> Option<Vec<(&'static str, String)>> {
+    let confirm = page_value(page, "confirm")?;
+    let mut params = vec![("confirm", confirm)];
+    if let Some(uuid) = page_value(page, "uuid") {
+        params.push(("uuid", uuid));
+    }
+    Some(params)
+}
+
+/// The value of `name` in `page`, as a `name=value` query parameter or a
+/// `name="name" value="value"` input.
+fn page_value(page: &str, name: &str) -> Option<String> {
+    let token = |rest: &str| -> Option<String> {
+        let value: String = rest
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+            .collect();
+        (!value.is_empty()).then_some(value)
+    };
+    let param = [
+        format!("?{name}="),
+        format!("&{name}="),
+        format!("&amp;{name}="),
+    ];
+    let input = format!("name=\"{name}\" value=\"");
+    param
+        .iter()
+        .chain(std::iter::once(&input))
+        .find_map(|marker| {
+            page.match_indices(marker.as_str())
+                .find_map(|(i, _)| token(&page[i + marker.len()..]))
+        })
+}
+
+/// What a resumed download does with Drive's answer to its `Range` request
+/// for the bytes past `have`.
+#[derive(Debug, PartialEq, Eq)]
+enum RangeOutcome {
+    /// `206`: the rest of the file follows what's there.
+    Append,
+    /// The whole file is coming (a `200` that ignored the range), or what's
+    /// there can't be its start: write the file over.
+    Restart,
+    /// `416`, nothing past `have`: the earlier attempt got every byte, which
+    /// the size check then confirms.
+    Complete,
+}
+
+fn range_outcome(status: StatusCode, have: u64, expected: Option<u64>) -> RangeOutcome {
+    if expected.is_some_and(|e| have > e) {
+        return RangeOutcome::Restart;
+    }
+    match status {
+        StatusCode::PARTIAL_CONTENT => RangeOutcome::Append,
+        StatusCode::RANGE_NOT_SATISFIABLE => RangeOutcome::Complete,
+        _ => RangeOutcome::Restart,
+    }
+}
+
+fn is_range_not_satisfiable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<DriveApiError>()
+        .is_some_and(|d| d.status == StatusCode::RANGE_NOT_SATISFIABLE)
+}
+
+/// Quotes `value` as a string literal for a Drive `q` query.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// The `id` Drive returns for a created file or folder.
+#[derive(Deserialize)]
+struct Created {
+    id: String,
+}
+
+/// A response body as a stream of chunks, advancing `bar` as they arrive.
+/// Ends with an error once more than `cap` allows has arrived.
+pub fn body_chunks(
+    response: Response,
+    bar: &ProgressBar,
+    cap: Option<ByteCap>,
+) -> impl Stream<Item = Result<Bytes>> + '_ {
+    let declared = response.content_length();
+    stream::unfold(Some((response, 0u64)), move |state| async move {
+        let (mut response, received) = state?;
+        if received == 0 {
+            // Refuse up front what the server already says is too long.
+            if let Some(Err(e)) = cap.zip(declared).map(|(cap, len)| cap.check(len)) {
+                return Some((Err(e), None));
+            }
+        }
+        match response.chunk().await {
+            Ok(None) => None,
+            Err(e) => Some((Err(e.into()), None)),
+            Ok(Some(chunk)) => {
+                let received = received + chunk.len() as u64;
+                if let Some(Err(e)) = cap.map(|cap| cap.check(received)) {
+                    return Some((Err(e), None));
+                }
+                bar.inc(chunk.len() as u64);
+                Some((Ok(chunk), Some((response, received))))
+            }
+        }
+    })
+}
+
+/// Streams a response body into `dest`, advancing `bar` as bytes arrive.
+/// Returns the number of bytes written. Writes go through a buffer of
+/// `buffer` bytes, and what's written is fed to `hasher`. A body that runs
+/// past `cap` is abandoned and `dest` removed.
+pub async fn write_response(
+    response: Response,
+    dest: &Path,
+    bar: &ProgressBar,
+    cap: Option<ByteCap>,
+    buffer: usize,
+    hasher: &mut Hasher,
+) -> Result<u64> {
+    let f = File::create(dest).await?;
+    let copied = copy_response(response, f, 0, bar, cap, buffer, hasher).await;
+    remove_if_too_large(copied, dest).await
+}
+
+/// Appends a response body to the first `offset` bytes already in `dest`.
+/// Returns the number of bytes appended.
+async fn append_response(
+    response: Response,
+    dest: &Path,
+    offset: u64,
+    bar: &ProgressBar,
+    cap: Option<ByteCap>,
+    buffer: usize,
+    hasher: &mut Hasher,
+) -> Result<u64> {
+    let mut f = File::options().write(true).open(dest).await?;
+    f.set_len(offset).await?;
+    f.seek(std::io::SeekFrom::Start(offset)).await?;
+    let copied = copy_response(response, f, offset, bar, cap, buffer, hasher).await;
+    remove_if_too_large(copied, dest).await
+}
+
+/// Feeds the first `len` bytes of `path`, what an earlier attempt left, to
+/// `hasher` before a resumed download carries on from there.
+async fn hash_prefix(path: &Path, len: u64, hasher: &mut Hasher) -> Result<()> {
+    if !hasher.is_active() {
+        return Ok(());
+    }
+    let mut f = File::open(path).await?.take(len);
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Passes `copied` through, first deleting `dest` if it's a `TooLarge`: the
+/// partial file is of no use to a retry or to `--keep-failed-temp`.
+async fn remove_if_too_large(copied: Result<u64>, dest: &Path) -> Result<u64> {
+    if let Err(ref e) = copied {
+        if is_too_large(e) {
+            let _ = tokio::fs::remove_file(dest).await;
+        }
+    }
+    copied
+}
+
+async fn copy_response(
+    mut response: Response,
+    f: File,
+    offset: u64,
+    bar: &ProgressBar,
+    cap: Option<ByteCap>,
+    buffer: usize,
+    hasher: &mut Hasher,
+) -> Result<u64> {
+    let mut f = BufWriter::with_capacity(buffer, f);
+    // Refuse up front what the server already says is too long.
+    if let (Some(cap), Some(len)) = (cap, response.content_length()) {
+        cap.check(offset + len)?;
+    }
+    let mut bytes_written: u64 = 0;
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            // What arrived is kept for a retry to resume from.
+            Err(e) => {
+                f.flush().await?;
+                return Err(e.into());
+            }
+        };
+        bytes_written += chunk.len() as u64;
+        if let Some(cap) = cap {
+            cap.check(offset + bytes_written)?;
+        }
+        bar.set_position(offset + bytes_written);
+        f.write_all(&chunk).await?;
+        hasher.update(&chunk);
+    }
+    f.flush().await?;
+    Ok(bytes_written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_name_siblings_get_their_ids() {
+        let paths = child_paths("", &[("id1", "Photos"), ("id2", "Photos"), ("id3", "Docs")]);
+        assert_eq!(paths, ["Photos_id1", "Photos_id2", "Docs"]);
+    }
+
+    #[test]
+    fn same_name_under_different_parents_keeps_it() {
+        let top = child_paths("", &[("a", "2024"), ("b", "2025")]);
+        assert_eq!(top, ["2024", "2025"]);
+        for parent in &top {
+            let paths = child_paths(parent, &[("c", "Photos")]);
+            assert_eq!(paths, [format!("{parent}/Photos")]);
+        }
+        // A clash one level down is told apart there only.
+        let paths = child_paths("2024", &[("d", "Photos"), ("e", "Photos")]);
+        assert_eq!(paths, ["2024/Photos_d", "2024/Photos_e"]);
+    }
+
+    #[test]
+    fn paths_are_stable_whatever_the_listing_order() {
+        let folders = [("id1", "Photos"), ("id2", "Docs"), ("id3", "Photos")];
+        let mut reversed = folders;
+        reversed.reverse();
+        let mut forward: Vec<_> = folders.iter().zip(child_paths("x", &folders)).collect();
+        let mut backward: Vec<_> = reversed.iter().zip(child_paths("x", &reversed)).collect();
+        forward.sort();
+        backward.sort();
+        assert_eq!(forward, backward);
+        assert_eq!(child_paths("x", &folders), child_paths("x", &folders));
+    }
+
+    #[test]
+    fn slash_in_a_folder_name_is_not_a_level() {
+        assert_eq!(child_paths("", &[("id1", "a/b")]), ["a_b"]);
+        // And can't collide with a sibling it now reads the same as.
+        assert_eq!(
+            child_paths("", &[("id1", "a/b"), ("id2", "a_b")]),
+            ["a_b_id1", "a_b_id2"]
+        );
+    }
+
+    #[test]
+    fn partial_content_is_appended() {
+        assert_eq!(
+            range_outcome(StatusCode::PARTIAL_CONTENT, 400, Some(1000)),
+            RangeOutcome::Append
+        );
+    }
+
+    #[test]
+    fn full_response_after_a_partial_starts_over() {
+        assert_eq!(
+            range_outcome(StatusCode::OK, 400, Some(1000)),
+            RangeOutcome::Restart
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_range_on_a_complete_partial_is_done() {
+        assert_eq!(
+            range_outcome(StatusCode::RANGE_NOT_SATISFIABLE, 1000, Some(1000)),
+            RangeOutcome::Complete
+        );
+    }
+
+    #[test]
+    fn partial_longer_than_the_file_starts_over() {
+        for status in [
+            StatusCode::PARTIAL_CONTENT,
+            StatusCode::OK,
+            StatusCode::RANGE_NOT_SATISFIABLE,
+        ] {
+            assert_eq!(
+                range_outcome(status, 1200, Some(1000)),
+                RangeOutcome::Restart
+            );
+        }
+    }
+
+    #[test]
+    fn html_answer_for_a_binary_file_is_a_page() {
+        assert!(is_html_page(Some("text/html"), "application/zip"));
+        assert!(is_html_page(Some("TEXT/HTML"), "video/mp4"));
+        assert!(!is_html_page(Some("application/zip"), "application/zip"));
+        assert!(!is_html_page(None, "application/zip"));
+    }
+
+    #[test]
+    fn html_file_is_not_flagged() {
+        assert!(!is_html_page(Some("text/html"), "text/html"));
+    }
+
+    #[test]
+    fn confirm_token_from_a_download_link() {
+        let page = r#"<a href="/uc?export=download&amp;confirm=t_Ab-9&amp;id=1x">Download</a>"#;
+        assert_eq!(
+            confirm_params(page),
+            Some(vec![("confirm", "t_Ab-9".to_string())])
+        );
+        let page = r#"<a href="/uc?confirm=XyZ1&id=1x">Download anyway</a>"#;
+        assert_eq!(
+            confirm_params(page),
+            Some(vec![("confirm", "XyZ1".to_string())])
+        );
+    }
+
+    #[test]
+    fn confirm_token_and_uuid_from_hidden_inputs() {
+        let page = r#"<form action="https://drive.usercontent.google.com/download">
+            <input type="hidden" name="id" value="1x">
+            <input type="hidden" name="confirm" value="t">
+            <input type="hidden" name="uuid" value="0a1b2c3d-4e5f-6789-abcd-ef0123456789">
+        </form>"#;
+        assert_eq!(
+            confirm_params(page),
+            Some(vec![
+                ("confirm", "t".to_string()),
+                ("uuid", "0a1b2c3d-4e5f-6789-abcd-ef0123456789".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn page_without_a_token() {
+        let page = "<html><body>Sorry, you can't view or download this file.</body></html>";
+        assert_eq!(confirm_params(page), None);
+        // An empty value isn't a token either.
+        assert_eq!(confirm_params(r#"<input name="confirm" value="">"#), None);
+    }
+}
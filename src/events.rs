@@ -0,0 +1,162 @@
+use crate::transfer::FileTiming;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Serialize;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::unix::pipe;
+use tokio::net::UnixListener;
+use tokio::sync::broadcast;
+
+/// One progress event, written as a line of JSON with an `event` tag.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Started {
+        file_id: &'a str,
+        name: &'a str,
+        size: Option<u64>,
+    },
+    /// Bytes moved so far in `stage`, sent about once a second.
+    Progress {
+        file_id: &'a str,
+        stage: &'static str,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    Uploading {
+        file_id: &'a str,
+        key: &'a str,
+    },
+    Done {
+        file_id: &'a str,
+        key: &'a str,
+        deleted_from_drive: bool,
+    },
+    Duplicate {
+        file_id: &'a str,
+        duplicate_of: &'a str,
+    },
+    Failed {
+        file_id: &'a str,
+        stage: &'static str,
+        error: String,
+    },
+    /// End of the run, with its throughput.
+    Finished {
+        uploaded: usize,
+        failed: usize,
+        bytes: u64,
+        seconds: f64,
+        average_bytes_per_sec: Option<f64>,
+        peak_bytes_per_sec: Option<f64>,
+        fastest: Option<&'a FileTiming>,
+        slowest: Option<&'a FileTiming>,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: chrono::DateTime<Utc>,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+/// Where `--progress-socket` events go. Every reader connected at the time
+/// gets each event; a reader that falls too far behind misses some rather
+/// than slowing the backup down. Off unless opened.
+#[derive(Default)]
+pub struct Events {
+    tx: Option<broadcast::Sender<Arc<str>>>,
+    /// The socket we created, removed again by `close`.
+    socket: Option<PathBuf>,
+}
+
+impl Events {
+    /// Starts serving events at `path`: writes into it if it's a named pipe,
+    /// otherwise listens there as a Unix domain socket (replacing a stale
+    /// socket left by an earlier run).
+    pub fn open(path: &Path) -> Result<Self> {
+        let (tx, _) = broadcast::channel::<Arc<str>>(1024);
+        let existing = std::fs::metadata(path).ok().map(|m| m.file_type());
+
+        if existing.is_some_and(|t| t.is_fifo()) {
+            // Opened read-write so it doesn't fail while nobody is reading.
+            let fifo = pipe::OpenOptions::new()
+                .read_write(true)
+                .open_sender(path)
+                .with_context(|| format!("Cannot open named pipe {}", path.display()))?;
+            let rx = tx.subscribe();
+            tokio::spawn(forward(rx, fifo));
+            return Ok(Self {
+                tx: Some(tx),
+                socket: None,
+            });
+        }
+
+        match existing {
+            Some(t) if t.is_socket() => std::fs::remove_file(path)?,
+            Some(_) => anyhow::bail!(
+                "--progress-socket {} exists and is neither a socket nor a named pipe",
+                path.display()
+            ),
+            None => {}
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Cannot listen on {}", path.display()))?;
+        let accept_tx = tx.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(forward(accept_tx.subscribe(), stream));
+            }
+        });
+        Ok(Self {
+            tx: Some(tx),
+            socket: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub fn emit(&self, event: Event<'_>) {
+        let Some(ref tx) = self.tx else {
+            return;
+        };
+        let line = Line {
+            time: Utc::now(),
+            event,
+        };
+        if let Ok(mut json) = serde_json::to_string(&line) {
+            json.push('\n');
+            // Fails only while nobody is connected.
+            let _ = tx.send(json.into());
+        }
+    }
+
+    /// Stops serving and removes the socket.
+    pub fn close(self) {
+        if let Some(path) = self.socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Writes each event from `rx` to `out` until a write fails or the sender
+/// is gone.
+async fn forward(mut rx: broadcast::Receiver<Arc<str>>, mut out: impl AsyncWrite + Unpin) {
+    loop {
+        match rx.recv().await {
+            Ok(line) => {
+                if out.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// The step a file failed at.
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Download,
+    Upload,
+    /// Archived to S3, but still in Drive.
+    Delete,
+}
+
+impl Stage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Download => "download",
+            Stage::Upload => "upload",
+            Stage::Delete => "delete",
+        }
+    }
+}
+
+/// One row of the failures CSV.
+pub struct Failure {
+    pub file_id: String,
+    pub name: String,
+    pub stage: Stage,
+    pub error: String,
+}
+
+/// Where the failures CSV for `prefix` lives, next to its manifest.
+pub fn path_for(dir: &str, prefix: &str) -> PathBuf {
+    Path::new(dir).join(format!("{}.failures.csv", prefix.trim_end_matches('/')))
+}
+
+/// Writes `failures` as CSV with a `file_id,name,stage,error` header,
+/// replacing the file left by any earlier run.
+pub async fn write(path: &Path, failures: &[Failure]) -> Result<()> {
+    let mut csv = String::from("file_id,name,stage,error\n");
+    for f in failures {
+        let row = [
+            f.file_id.as_str(),
+            f.name.as_str(),
+            f.stage.as_str(),
+            f.error.as_str(),
+        ];
+        let row: Vec<String> = row.iter().map(|v| field(v)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(path, csv)
+        .await
+        .with_context(|| format!("Cannot write {}", path.display()))
+}
+
+/// The distinct file ids listed in a failures CSV written by `write`.
+pub async fn read_ids(path: &Path) -> Result<Vec<String>> {
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+    let mut rows = parse(&data).into_iter();
+    if rows.next().and_then(|h| h.into_iter().next()).as_deref() != Some("file_id") {
+        anyhow::bail!(
+            "{} is not a failures CSV (no file_id column)",
+            path.display()
+        );
+    }
+    let mut ids: Vec<String> = Vec::new();
+    for id in rows.filter_map(|r| r.into_iter().next()) {
+        if !id.is_empty() && !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// Splits CSV text into rows of fields, honouring quoted fields.
+fn parse(data: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            '\r' if !quoted => {}
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+/// Quotes a value when it contains a delimiter, quote or line break.
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
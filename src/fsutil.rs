@@ -0,0 +1,22 @@
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+/// Moves `from` to `to`, replacing `to`. A rename can't cross filesystems
+/// (a temp dir on another volume than `--keep-local`, say), so then the file
+/// is copied beside `to`, renamed into place and the original removed: `to`
+/// is still never seen half-written.
+pub async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+        done => return done,
+    }
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = Path::new(&partial);
+    if let Err(e) = tokio::fs::copy(from, partial).await {
+        let _ = tokio::fs::remove_file(partial).await;
+        return Err(e);
+    }
+    tokio::fs::rename(partial, to).await?;
+    tokio::fs::remove_file(from).await
+}
@@ -0,0 +1,136 @@
+use aws_sdk_s3::config::interceptors::{
+    BeforeTransmitInterceptorContextRef, FinalizerInterceptorContextRef,
+};
+use aws_sdk_s3::config::{ConfigBag, Intercept, RuntimeComponents};
+use aws_smithy_types::config_bag::{Storable, StoreReplace};
+use reqwest::{RequestBuilder, Response};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// Set once at startup by `--verbose-http`.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Query parameters whose values grant access and are never logged.
+const SECRET_PARAMS: &[&str] = &["access_token", "code", "key", "token", "upload_id"];
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Prints one line per request: method, URL, status and latency. Headers,
+/// `Authorization` included, are never printed.
+fn log(method: &str, url: &str, outcome: &str, started: Instant) {
+    eprintln!(
+        "http method={method} url={url} {outcome} ms={}",
+        started.elapsed().as_millis()
+    );
+}
+
+/// `url` with secret query values replaced. Google Photos download URLs are
+/// themselves credentials, so only their host is kept.
+fn redact(url: &reqwest::Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    if !host.ends_with("googleapis.com") && !host.ends_with("amazonaws.com") {
+        return format!("{}://{host}/[redacted]", url.scheme());
+    }
+    let mut url = url.clone();
+    if url.query().is_some() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(k, v)| {
+                let v = if SECRET_PARAMS.contains(&k.as_ref()) {
+                    "[redacted]".to_string()
+                } else {
+                    v.into_owned()
+                };
+                (k.into_owned(), v)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+pub trait SendLogged {
+    /// `send`, logged with `--verbose-http`.
+    async fn send_logged(self) -> reqwest::Result<Response>;
+}
+
+impl SendLogged for RequestBuilder {
+    async fn send_logged(self) -> reqwest::Result<Response> {
+        if !enabled() {
+            return self.send().await;
+        }
+        let (client, request) = self.build_split();
+        let request = request?;
+        let method = request.method().to_string();
+        let url = redact(request.url());
+        let started = Instant::now();
+        let result = client.execute(request).await;
+        let outcome = match result {
+            Ok(ref response) => format!(
+                "status={} version={:?}",
+                response.status().as_u16(),
+                response.version()
+            ),
+            Err(ref e) => format!("status=error error=\"{e}\""),
+        };
+        log(&method, &url, &outcome, started);
+        result
+    }
+}
+
+/// Logs every S3 and STS request attempt, added to those clients with
+/// `--verbose-http`.
+#[derive(Debug)]
+pub struct SdkLogger;
+
+#[derive(Debug, Clone)]
+struct AttemptStart(Instant);
+
+impl Storable for AttemptStart {
+    type Storer = StoreReplace<Self>;
+}
+
+impl Intercept for SdkLogger {
+    fn name(&self) -> &'static str {
+        "VerboseHttp"
+    }
+
+    fn read_before_transmit(
+        &self,
+        _context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        cfg.interceptor_state()
+            .store_put(AttemptStart(Instant::now()));
+        Ok(())
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (Some(request), Some(AttemptStart(started))) =
+            (context.request(), cfg.load::<AttemptStart>())
+        else {
+            return Ok(());
+        };
+        let url = reqwest::Url::parse(request.uri())
+            .map(|u| redact(&u))
+            .unwrap_or_else(|_| request.uri().to_string());
+        let outcome = match context.response() {
+            Some(response) => format!("status={}", response.status().as_u16()),
+            None => "status=error".to_string(),
+        };
+        log(request.method(), &url, &outcome, *started);
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use crate::drive::DriveFile;
+use crate::failures::Stage;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A point in a file's transfer, stamped as the pipeline passes it.
+pub enum Milestone {
+    /// When the listing found it; for a streamed listing, when the pipeline
+    /// took it.
+    Listed(DateTime<Utc>),
+    DownloadStart,
+    DownloadEnd,
+    UploadEnd,
+    DeleteEnd,
+}
+
+/// How a file's transfer ended.
+pub enum Status<'a> {
+    Archived,
+    /// Skipped by `--dedup`: its content was already uploaded.
+    Duplicate,
+    /// Left alone because S3 already holds other content at its key
+    /// (`--no-overwrite`).
+    Skipped,
+    Failed(Stage, &'a anyhow::Error),
+}
+
+#[derive(Default, Serialize)]
+struct Lifecycle {
+    listed_at: Option<DateTime<Utc>>,
+    download_start: Option<DateTime<Utc>>,
+    download_end: Option<DateTime<Utc>>,
+    upload_end: Option<DateTime<Utc>>,
+    delete_end: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    file_id: &'a str,
+    name: &'a str,
+    prefix: &'a str,
+    key: &'a str,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    bytes: Option<u64>,
+    #[serde(flatten)]
+    times: Lifecycle,
+}
+
+/// `--transfer-log`: an append-only NDJSON file with one line per file,
+/// written once its transfer ends, carrying the time of each milestone.
+/// Kept across runs and flushed to disk line by line, so a crash loses at
+/// most the files still in flight. Off unless opened.
+#[derive(Default)]
+pub struct Journal {
+    file: Option<Mutex<File>>,
+    /// The backup prefix every line is tagged with.
+    prefix: String,
+    /// Milestones so far of the files in flight, by Drive id.
+    open: Mutex<HashMap<String, Lifecycle>>,
+}
+
+impl Journal {
+    pub fn open(path: &Path, prefix: &str) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Cannot create {}", parent.display()))?;
+        }
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open transfer log {}", path.display()))?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+            prefix: prefix.to_string(),
+            open: Mutex::default(),
+        })
+    }
+
+    pub fn stamp(&self, file_id: &str, milestone: Milestone) {
+        if self.file.is_none() {
+            return;
+        }
+        let now = Utc::now();
+        let mut open = self.open.lock().unwrap();
+        let times = open.entry(file_id.to_string()).or_default();
+        match milestone {
+            Milestone::Listed(at) => times.listed_at = Some(at),
+            Milestone::DownloadStart => times.download_start = Some(now),
+            Milestone::DownloadEnd => times.download_end = Some(now),
+            Milestone::UploadEnd => times.upload_end = Some(now),
+            Milestone::DeleteEnd => times.delete_end = Some(now),
+        }
+    }
+
+    /// Appends `file`'s line and syncs it to disk. A write error is only
+    /// warned about: the backup itself goes on.
+    pub fn finish(&self, file: &DriveFile, key: &str, status: Status<'_>) {
+        let Some(ref out) = self.file else {
+            return;
+        };
+        let times = self
+            .open
+            .lock()
+            .unwrap()
+            .remove(&file.id)
+            .unwrap_or_default();
+        let (status, stage, error) = match status {
+            Status::Archived => ("archived", None, None),
+            Status::Duplicate => ("duplicate", None, None),
+            Status::Skipped => ("skipped", None, None),
+            Status::Failed(stage, e) => ("failed", Some(stage.as_str()), Some(format!("{e:#}"))),
+        };
+        let line = Line {
+            file_id: &file.id,
+            name: &file.name,
+            prefix: &self.prefix,
+            key,
+            status,
+            stage,
+            error,
+            bytes: file.size_bytes(),
+            times,
+        };
+        let written = serde_json::to_string(&line)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut json| {
+                json.push('\n');
+                let mut out = out.lock().unwrap();
+                out.write_all(json.as_bytes())?;
+                out.sync_data()?;
+                Ok(())
+            });
+        if let Err(e) = written {
+            eprintln!("Warning: could not write the transfer log: {e:#}");
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::aws::S3Uploader;
+use crate::config::{Config, PrefixFormat};
+use anyhow::Result;
+use chrono::{Duration, Utc};
+
+/// Name of the lock object under a backup prefix while a run is active.
+pub const LOCK_NAME: &str = ".lock";
+
+/// A lock older than this is taken to be left behind by a run that crashed.
+const STALE_AFTER: Duration = Duration::hours(24);
+
+/// The key of the run lock for a backup under `prefix`: `{prefix}/.lock`.
+/// With `--prefix-format` datetime or run-id every run has a prefix of its
+/// own, so they share one lock beside their prefixes instead,
+/// `<output prefix>.lock`.
+pub fn key(config: &Config, prefix: &str) -> String {
+    match config.prefix_format {
+        PrefixFormat::Date => format!("{prefix}/{LOCK_NAME}"),
+        PrefixFormat::DateTime | PrefixFormat::RunId => {
+            format!("{}{LOCK_NAME}", config.output_prefix)
+        }
+    }
+}
+
+/// Takes the run lock by creating the object at `key`, so two runs never
+/// race on the same downloads and deletes. Fails if another run holds a
+/// fresh lock, unless `force`. Returns the lock's key.
+pub async fn acquire(s3: &S3Uploader, key: &str, force: bool) -> Result<String> {
+    let key = key.to_string();
+    let body = format!(
+        "host={}\npid={}\nstarted={}\n",
+        hostname(),
+        std::process::id(),
+        Utc::now().to_rfc3339()
+    );
+
+    if s3
+        .put_small(&key, body.clone(), "text/plain", &[], true)
+        .await?
+    {
+        return Ok(key);
+    }
+    let since = s3.last_modified(&key).await?;
+    let stale = since.is_some_and(|t| Utc::now() - t > STALE_AFTER);
+    let since = since.map_or_else(|| "just now".to_string(), |t| t.to_rfc3339());
+    if !force && !stale {
+        anyhow::bail!(
+            "Another run holds the lock {key} (since {since}). \
+             Pass --force if it's no longer running."
+        );
+    }
+    println!("Taking over the lock at {key} (held since {since}) ...");
+    s3.put_small(&key, body, "text/plain", &[], false).await?;
+    Ok(key)
+}
+
+/// Removes the lock taken by `acquire`. A failure is only reported: the
+/// next run treats the lock as stale after a day, or can pass `--force`.
+pub async fn release(s3: &S3Uploader, key: &str) {
+    if let Err(e) = s3.delete_object(key).await {
+        eprintln!("Warning: could not remove the run lock {key}: {e:#}");
+    }
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
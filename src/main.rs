@@ -1,78 +1,762 @@
+mod aimd;
 mod auth;
 mod aws;
+mod bundle;
+mod chaos;
+mod config;
+mod cost;
+mod doctor;
 mod drive;
+mod events;
+mod failures;
+mod fsutil;
+mod http_log;
+mod journal;
+mod lock;
+mod manifest;
+mod marker;
+mod otel;
+mod photos;
+mod progress;
+mod prune;
+mod restore;
+mod retry;
+mod transfer;
+mod verify;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::Utc;
 use drive::DriveFile;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar};
 use reqwest::Client;
-use std::time::Duration;
-
-const DRIVE_FOLDER_NAME: &str = "Takeout";
-const MAX_RETRIES: u32 = 3;
-
-async fn retry<F, Fut, T>(op: F) -> Result<T>
-where
-    F: Fn() -> Fut,
-    Fut: std::future::Future<Output = Result<T>>,
-{
-    let mut delay = Duration::from_secs(5);
-    for attempt in 1..=MAX_RETRIES {
-        match op().await {
-            Ok(v) => return Ok(v),
+use retry::retry;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use transfer::{download_style, sanitize_filename};
+
+/// Exit status of a run stopped early by `--max-runtime`, so a scheduler can
+/// tell it from success (0) and failure (1).
+const EXIT_OUT_OF_TIME: i32 = 3;
+
+/// `download <file-id> <dest>` — fetch a single Drive file for a spot check or
+/// manual restore. Never touches S3 and never deletes anything.
+async fn download_one(http: &Client, args: &config::Args, config: &config::Config) -> Result<()> {
+    let [file_id, dest] = args.operands() else {
+        anyhow::bail!("Usage: backup download <file-id> <dest> [--export-as <mime-type>]");
+    };
+
+    println!("Authenticating with Google Drive ...");
+    let token = auth::load_or_authenticate(http, config).await?;
+    let drive = drive::DriveClient::new(http, auth::TokenHolder::new(http, config, token))
+        .with_size_check(config.size_check)
+        .with_max_file_size(config.max_file_size)
+        .with_write_buffer(config.download_buffer);
+    let mut file = drive.get_file(file_id).await?;
+    if drive::is_shortcut(&file) {
+        file = drive.resolve_shortcut(&file).await?;
+    }
+
+    // A directory destination keeps the file's Drive name.
+    let mut dest = PathBuf::from(dest);
+    if dest.is_dir() {
+        dest.push(sanitize_filename(&file.name));
+    }
+
+    let export_as = args.value("--export-as");
+    let is_workspace = drive::is_workspace_file(&file, &config.workspace_mimetypes);
+    if is_workspace && export_as.is_none() {
+        anyhow::bail!(
+            "\"{}\" is a Google Workspace file ({}); pass --export-as <mime-type> to export it",
+            file.name,
+            file.mime_type
+        );
+    }
+    if !is_workspace && export_as.is_some() {
+        anyhow::bail!(
+            "--export-as only applies to Google Workspace files; \"{}\" is {}",
+            file.name,
+            file.mime_type
+        );
+    }
+
+    // Retries resume what an earlier attempt fetched; an existing file is
+    // replaced rather than resumed, since it may be something else entirely.
+    if export_as.is_none() {
+        let _ = std::fs::remove_file(&dest);
+    }
+    let bar = ProgressBar::with_draw_target(Some(0), progress::draw_target());
+    bar.set_style(download_style()?);
+    let result = retry(|| async {
+        bar.reset();
+        match export_as {
+            Some(mime) => drive.export(&file, mime, &dest, &bar).await,
+            None => drive.download(&file, &dest, &bar).await.map(|_| ()),
+        }
+    })
+    .await;
+    bar.finish_and_clear();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&dest);
+    }
+    result?;
+
+    // Keep Drive's modification time on the local copy, as a browser
+    // download would. Best effort: the content is what matters.
+    if let Some(modified) = file.modified_time {
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(&dest)
+            .and_then(|f| f.set_modified(modified.into()));
+    }
+
+    println!("Downloaded \"{}\" to {}", file.name, dest.display());
+    Ok(())
+}
+
+/// Fetches current metadata for each file listed in a failures CSV from an
+/// earlier run, skipping any Drive no longer has. Returns the files and how
+/// many couldn't be fetched.
+async fn retry_candidates(
+    drive: &drive::DriveClient<'_>,
+    report: &std::path::Path,
+) -> Result<(Vec<DriveFile>, usize)> {
+    let ids = failures::read_ids(report).await?;
+    println!(
+        "Retrying {} file(s) from {} ...",
+        ids.len(),
+        report.display()
+    );
+    Ok(fetch_files(drive, &ids).await)
+}
+
+/// Fetches current metadata for each of `ids`, warning about any Drive
+/// doesn't have or won't show this account. Returns the files found and
+/// how many weren't.
+async fn fetch_files(drive: &drive::DriveClient<'_>, ids: &[String]) -> (Vec<DriveFile>, usize) {
+    let mut files = Vec::new();
+    let mut unavailable = 0;
+    for id in ids {
+        match drive.get_file(id).await {
+            Ok(file) => files.push(file),
             Err(e) => {
-                if attempt == MAX_RETRIES {
-                    let msg = e.to_string();
-                    if msg.contains("RequestTimeTooSkewed") {
-                        return Err(e.context(
-                            "AWS clock skew — sync your system clock with: sudo sntp -sS time.apple.com",
-                        ));
+                eprintln!("  Warning: cannot fetch {id}: {e:#}");
+                unavailable += 1;
+            }
+        }
+    }
+    (files, unavailable)
+}
+
+/// Permanently deletes what earlier runs trashed with `--delete-after`, once
+/// it has sat in the trash for `days`. Items someone restored in the
+/// meantime are left alone and no longer tracked. Returns how many were
+/// purged and the space that freed.
+async fn purge_trashed(
+    drive: &drive::DriveClient<'_>,
+    manifest_dir: &std::path::Path,
+    days: u32,
+) -> Result<(usize, u64)> {
+    let cutoff = Utc::now() - chrono::Duration::days(days.into());
+    let (mut purged, mut freed) = (0, 0);
+    let mut dir = match tokio::fs::read_dir(manifest_dir).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e).with_context(|| format!("Cannot read {}", manifest_dir.display())),
+    };
+    while let Some(item) = dir.next_entry().await? {
+        let path = item.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(mut manifest) = manifest::Manifest::load(&path).await? else {
+            continue;
+        };
+        let mut changed = false;
+        for entry in &mut manifest.files {
+            let Some(ref mut trashed) = entry.trashed else {
+                continue;
+            };
+            if trashed.purged_at.is_some() || trashed.at > cutoff {
+                continue;
+            }
+            let result = match drive.get_file(&trashed.id).await {
+                Ok(current) if !current.trashed => {
+                    println!("  {} was restored from the trash — leaving it.", entry.name);
+                    entry.trashed = None;
+                    entry.deleted_from_drive = false;
+                    changed = true;
+                    continue;
+                }
+                Ok(_) => drive.delete(&trashed.id).await,
+                // Drive empties its trash after 30 days on its own.
+                Err(e) if drive::is_not_found(&e) => Ok(()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => {
+                    trashed.purged_at = Some(Utc::now());
+                    changed = true;
+                    purged += 1;
+                    // A trashed shortcut took up no space.
+                    if entry.shortcut_id.is_none() {
+                        freed += entry.size.unwrap_or(0);
                     }
-                    return Err(e);
                 }
-                eprintln!("  attempt {attempt}/{MAX_RETRIES} failed: {e:#} — retrying in {}s ...", delay.as_secs());
-                tokio::time::sleep(delay).await;
-                delay *= 2;
+                Err(e) => eprintln!("  Warning: could not purge {}: {e:#}", entry.name),
             }
         }
+        if changed {
+            manifest.save(&path).await?;
+        }
     }
-    unreachable!()
+    Ok((purged, freed))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenvy::dotenv().ok();
+/// With `--delete-after`, deletes for good what earlier runs trashed that
+/// long ago. Nothing with `--dry-run` or `--no-delete`.
+async fn purge(drive: &drive::DriveClient<'_>, config: &config::Config) -> Result<()> {
+    let Some(days) = config.delete_after else {
+        return Ok(());
+    };
+    if config.dry_run || config.keep_in_drive {
+        return Ok(());
+    }
+    println!("Purging files trashed more than {days} day(s) ago ...");
+    // This host's manifests only, with S3_OUTPUT_PREFIX.
+    let manifests = std::path::Path::new(&config.manifest_dir).join(&config.output_prefix);
+    let (purged, freed) = purge_trashed(drive, &manifests, days).await?;
+    println!(
+        "  {purged} file(s) deleted from the Drive trash, {} freed.",
+        HumanBytes(freed)
+    );
+    Ok(())
+}
+
+/// `--confirm-delete`: says what the run will remove from Drive and asks
+/// before it does. Without a terminal to ask on, only `--yes` confirms.
+fn confirm_delete(config: &config::Config, files: &[DriveFile], streamed: bool) -> Result<bool> {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    let how = if config.delete_after.is_some() {
+        "moved to the Drive trash"
+    } else {
+        "deleted from Google Drive"
+    };
+    if streamed {
+        println!("Every file archived by this run will be {how}.");
+    } else {
+        let bytes = files.iter().filter_map(DriveFile::size_bytes).sum();
+        println!(
+            "{} file(s) ({}) will be {how} once archived.",
+            files.len(),
+            HumanBytes(bytes)
+        );
+    }
+    if config.yes {
+        println!("Confirmed by --yes.\n");
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        println!("No terminal to confirm on (pass --yes to confirm unattended).");
+        return Ok(false);
+    }
+    print!("Delete them from Drive? [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Prints the run's throughput after the backup summary. MB/s are decimal
+/// megabytes, as network speeds usually are.
+fn print_throughput(stats: &transfer::RunStats) {
+    let Some(average) = stats.average_rate() else {
+        return;
+    };
+    let rate = |bytes_per_sec: f64| format!("{:.1} MB/s", bytes_per_sec / 1e6);
+    let mut line = format!(
+        "Throughput: {} in {}, average {}",
+        HumanBytes(stats.bytes_uploaded),
+        HumanDuration(stats.elapsed),
+        rate(average)
+    );
+    if let Some(peak) = stats.peak_rate() {
+        line += &format!(", peak {}", rate(peak));
+    }
+    println!("{line}.");
+    for (which, timing) in [("Fastest", &stats.fastest), ("Slowest", &stats.slowest)] {
+        if let Some(t) = timing {
+            println!(
+                "  {which}: {} — {} in {} ({})",
+                t.name,
+                HumanBytes(t.bytes),
+                HumanDuration(Duration::from_secs_f64(t.seconds)),
+                rate(t.rate())
+            );
+        }
+    }
+}
 
-    let test_mode = std::env::args().any(|a| a == "--test");
-    let prune_only = std::env::args().any(|a| a == "--prune-only");
+/// Removes the Workspace files a run skipped, with `--delete-workspace-files`:
+/// trashed with `--delete-after`, deleted otherwise. Nothing of them is
+/// archived. Returns how many were removed.
+async fn delete_workspace_files(
+    drive: &drive::DriveClient<'_>,
+    config: &config::Config,
+    files: &[DriveFile],
+) -> usize {
+    let mut removed = 0;
+    for f in files {
+        if !f.can_remove(config.delete_after.is_some()) {
+            eprintln!("  Warning: no permission to remove \"{}\"", f.name);
+            continue;
+        }
+        let result = match config.delete_after {
+            Some(_) => drive.trash(f.delete_id()).await,
+            None => drive.delete(f.delete_id()).await,
+        };
+        match result {
+            Ok(()) => removed += 1,
+            Err(e) => eprintln!("  Warning: could not remove \"{}\": {e:#}", f.name),
+        }
+    }
+    removed
+}
 
-    let creds_file = std::env::var("GOOGLE_CREDENTIALS_FILE")
-        .unwrap_or_else(|_| "credentials.json".to_string());
-    let token_file = std::env::var("GOOGLE_TOKEN_FILE")
-        .unwrap_or_else(|_| "token.json".to_string());
-    let bucket = std::env::var("S3_BUCKET_NAME").expect("S3_BUCKET_NAME must be set");
-    let role_arn = std::env::var("AWS_UPLOAD_ROLE_ARN").expect("AWS_UPLOAD_ROLE_ARN must be set");
+/// The backup candidates in `folder_ids`, listed a page at a time as the
+/// pipeline pulls them. Entries the collected listing would skip and report
+/// (subfolders, Workspace files, shortcuts unless resolving) are dropped
+/// quietly; a listing error ends the stream and is reported.
+fn streamed_files<'a>(
+    drive: &'a drive::DriveClient<'a>,
+    folder_ids: &'a [String],
+    config: &'a config::Config,
+) -> impl Stream<Item = DriveFile> + 'a {
+    stream::iter(folder_ids)
+        .flat_map(move |id| drive.list_pages(id, config.trashed))
+        .filter_map(|page| {
+            future::ready(match page {
+                Ok(files) => Some(stream::iter(files)),
+                Err(e) => {
+                    eprintln!("Warning: listing stopped early: {e:#}");
+                    None
+                }
+            })
+        })
+        .flatten()
+        .filter_map(move |f| async move {
+            if drive::is_folder(&f) || drive::is_workspace_file(&f, &config.workspace_mimetypes) {
+                return None;
+            }
+            if !f.can_download() {
+                eprintln!("Warning: skipping \"{}\": no download permission", f.name);
+                return None;
+            }
+            if !drive::is_shortcut(&f) {
+                return Some(f);
+            }
+            match config.shortcuts {
+                config::ShortcutPolicy::Skip => None,
+                config::ShortcutPolicy::Resolve => match drive.resolve_shortcut(&f).await {
+                    Ok(target) => Some(target),
+                    Err(e) => {
+                        eprintln!("Warning: skipping shortcut \"{}\": {e:#}", f.name);
+                        None
+                    }
+                },
+            }
+        })
+        .filter(move |f| future::ready(config.extension_allowed(&f.name)))
+}
 
-    let http = Client::builder()
+/// Prints how long the upload role's credentials last, and warns when
+/// `--max-runtime` allows the run to go on past that: they're assumed once
+/// and not refreshed, so uploads started later would fail.
+fn check_credential_lifetime(config: &config::Config, s3: &aws::S3Uploader) {
+    let (Some(expires_at), Some(remaining)) = (s3.expires_at(), s3.credentials_remaining()) else {
+        return;
+    };
+    println!(
+        "Upload credentials valid until {} ({})",
+        chrono::DateTime::<chrono::Local>::from(expires_at).format("%Y-%m-%d %H:%M"),
+        HumanDuration(remaining)
+    );
+    if let Some(deadline) = config.deadline {
+        let runtime = deadline.saturating_duration_since(Instant::now());
+        if runtime > remaining {
+            eprintln!(
+                "Warning: --max-runtime ({}) outlasts the upload credentials ({}), which \
+                 aren't refreshed during a run; uploads after they expire will fail.",
+                HumanDuration(runtime),
+                HumanDuration(remaining)
+            );
+        }
+    }
+}
+
+/// The client for every Google request. Connections are pooled and reused
+/// from file to file, and unless `--http1` is given ALPN negotiates HTTP/2,
+/// so concurrent transfers to a host share one connection as multiplexed
+/// streams instead of each paying for its own TLS handshake.
+fn http_client(config: &config::Config) -> Result<Client> {
+    // Google's client libraries identify themselves with x-goog-api-client;
+    // do the same so throttling on our project's quota is easy to attribute.
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        "x-goog-api-client",
+        reqwest::header::HeaderValue::from_static(config::DEFAULT_USER_AGENT),
+    );
+
+    let mut builder = Client::builder()
+        .user_agent(&config.user_agent)
+        .default_headers(headers)
         .connect_timeout(Duration::from_secs(30))
         // Allow up to 30 minutes per request to accommodate large file downloads.
         .timeout(Duration::from_secs(1800))
-        .build()?;
+        .pool_idle_timeout(config.http_idle_timeout)
+        .pool_max_idle_per_host(config.http_max_idle)
+        .tcp_keepalive(Duration::from_secs(60));
+    builder = if config.http1 {
+        builder.http1_only()
+    } else {
+        // Pings spot a dead connection long before the request timeout, and
+        // adaptive windows let one stream use the whole link.
+        builder
+            .http2_adaptive_window(true)
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_timeout(Duration::from_secs(20))
+            .http2_keep_alive_while_idle(true)
+    };
+    if let Some(ref path) = config.ca_cert {
+        for cert in root_certificates(path)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    Ok(builder.build()?)
+}
 
-    let date_prefix = Utc::now().format("%Y-%m-%d").to_string();
+/// `--print-config`: the settings a run would use, as JSON on stdout,
+/// without contacting Google or AWS. AWS credentials in the environment are
+/// only reported as set or not.
+fn print_config(config: &config::Config) -> Result<()> {
+    let env = |name: &str| std::env::var(name).ok();
+    let secret = |name: &str| env(name).map(|_| "[redacted]");
+    let report = serde_json::json!({
+        "settings": config.to_json(),
+        "s3": {
+            "bucket": env("S3_BUCKET_NAME"),
+            "upload_role_arn": env("AWS_UPLOAD_ROLE_ARN"),
+            "object_acl": env("S3_OBJECT_ACL"),
+            "object_lock_mode": env("S3_OBJECT_LOCK_MODE"),
+            "object_lock_until": env("S3_OBJECT_LOCK_UNTIL"),
+        },
+        "aws": {
+            "region": env("AWS_REGION").or_else(|| env("AWS_DEFAULT_REGION")),
+            "profile": env("AWS_PROFILE"),
+            "access_key_id": secret("AWS_ACCESS_KEY_ID"),
+            "secret_access_key": secret("AWS_SECRET_ACCESS_KEY"),
+            "session_token": secret("AWS_SESSION_TOKEN"),
+        },
+    });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
 
+/// Reads the bucket and role from the environment and assumes the upload role.
+async fn connect_s3() -> Result<(String, aws::S3Uploader)> {
+    let settings = aws::S3Settings::from_env()?;
     println!("Assuming upload role ...");
-    let s3 = aws::S3Uploader::new(bucket.clone(), &role_arn).await?;
+    let mut s3 =
+        aws::S3Uploader::new(settings.bucket.clone(), &settings.role_arn, settings.acl).await?;
+    if let Some(lock) = settings.lock {
+        s3 = s3.with_object_lock(lock).await?;
+    }
+    Ok((
+        settings.bucket,
+        s3.with_storage_classes(settings.storage_classes),
+    ))
+}
+
+/// Reads the PEM bundle at `path` (`--ca-cert`): one or more certificates,
+/// trusted on top of the system's.
+fn root_certificates(path: &std::path::Path) -> Result<Vec<reqwest::Certificate>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Cannot read CA certificate {}", path.display()))?;
+    let certs = reqwest::Certificate::from_pem_bundle(&pem)
+        .with_context(|| format!("Invalid PEM in CA certificate {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenvy::dotenv().ok();
+
+    let args = config::Args::from_env()?;
+    let mut config = config::Config::load(&args)?;
+    if args.flag("--print-config") {
+        print_config(&config)?;
+        return Ok(());
+    }
+    if config.verbose_http {
+        http_log::enable();
+    }
+    if config.plain {
+        progress::set_plain();
+    }
+    if let Some(ref spec) = config.simulate_failure {
+        chaos::init(spec, config.simulate_failure_seed)?;
+    }
+
+    let http = http_client(&config)?;
+    retry::set_slow_down_policy(retry::SlowDownPolicy {
+        attempts: config.slowdown_retries,
+        max_delay: config.slowdown_max_delay,
+    });
+
+    match args.subcommand() {
+        None => {}
+        Some("download") => return download_one(&http, &args, &config.single_account()?).await,
+        Some("verify") => {
+            let [prefix] = args.operands() else {
+                anyhow::bail!("Usage: backup verify <date-prefix>");
+            };
+            let config = config.single_account()?;
+            let (_, s3) = connect_s3().await?;
+            let prefix = format!("{}{prefix}", config.output_prefix);
+            return verify::run(&http, &config, &s3, &prefix).await;
+        }
+        Some("restore") => {
+            let [prefix, folder_id] = args.operands() else {
+                anyhow::bail!("Usage: backup restore <date-prefix> <drive-folder-id>");
+            };
+            let config = config.single_account()?;
+            let (_, s3) = connect_s3().await?;
+            let prefix = format!("{}{prefix}", config.output_prefix);
+            return restore::run(&http, &config, &s3, &prefix, folder_id).await;
+        }
+        Some("prune") => {
+            let [prefix] = args.operands() else {
+                anyhow::bail!("Usage: backup prune <date-prefix>");
+            };
+            let config = config.single_account()?;
+            let (_, s3) = connect_s3().await?;
+            let prefix = format!("{}{prefix}", config.output_prefix);
+            return prune::run(&http, &config, &s3, &prefix).await;
+        }
+        Some("doctor") => return doctor::run(&config).await,
+        Some(other) => anyhow::bail!("Unknown subcommand: {other}"),
+    }
+
+    limit_memory(&mut config)?;
+
+    if config.startup_jitter > 0 {
+        let secs = rand::random_range(0..=config.startup_jitter);
+        println!("Startup jitter: waiting {secs}s before starting ...");
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+    }
+    config.start_clock();
+
+    if config.checksum_algo == aws::ChecksumAlgo::None
+        && std::env::var_os("S3_OBJECT_LOCK_MODE").is_some()
+    {
+        anyhow::bail!(
+            "--checksum-algo none can't be used with S3_OBJECT_LOCK_MODE: S3 rejects \
+             Object Lock uploads sent without a checksum"
+        );
+    }
+
+    if let Some(ref endpoint) = config.otlp_endpoint {
+        otel::init(&http, endpoint)?;
+        otel::start_run();
+    }
+    let result = backup_all(&http, &config).await;
+    otel::finish_run(result.as_ref().err()).await;
+    if result? {
+        std::process::exit(EXIT_OUT_OF_TIME);
+    }
+    Ok(())
+}
+
+/// Backs up the configured account, or each of `--accounts` in turn.
+/// Returns whether `--max-runtime` cut the run short.
+async fn backup_all(http: &Client, config: &config::Config) -> Result<bool> {
+    let (bucket, s3) = connect_s3().await?;
+    let s3 = s3
+        .with_checksum(config.checksum_algo)
+        .with_part_concurrency(config.part_concurrency)
+        .with_no_overwrite(config.no_overwrite);
+    check_credential_lifetime(config, &s3);
+
+    if config.accounts.is_empty() {
+        let stats = run_folders(http, config, &s3, &bucket).await?;
+        return Ok(stats.out_of_time);
+    }
+
+    // Accounts are backed up one after another; one failing doesn't stop
+    // the rest.
+    let mut results = Vec::new();
+    let mut out_of_time = false;
+    for account in &config.accounts {
+        if config.deadline.is_some_and(|d| Instant::now() >= d) {
+            println!("\nTime limit reached (--max-runtime): account {account} not started.");
+            out_of_time = true;
+            continue;
+        }
+        println!("\n=== Account {account} ===\n");
+        let result = run_folders(http, &config.for_account(account), &s3, &bucket).await;
+        match result {
+            Ok(ref stats) => out_of_time |= stats.out_of_time,
+            Err(ref e) => eprintln!("Error: account {account}: {e:#}"),
+        }
+        results.push((account.clone(), result));
+    }
+
+    if results.len() > 1 {
+        println!("\nAccounts:");
+        print_totals(&results);
+    }
+    let failed = incomplete(&results);
+    if !failed.is_empty() {
+        anyhow::bail!("Backup did not complete for account(s): {failed}");
+    }
+    Ok(out_of_time)
+}
+
+/// Backs up the account's folder, or each folder of `--folders-file` in
+/// turn, under its own prefix. One folder failing doesn't stop the rest,
+/// but fails the account once they've all had their turn.
+async fn run_folders(
+    http: &Client,
+    config: &config::Config,
+    s3: &aws::S3Uploader,
+    bucket: &str,
+) -> Result<transfer::RunStats> {
+    if config.folders.is_empty() {
+        return run_account(http, config, s3, bucket).await;
+    }
+    let mut results = Vec::new();
+    let mut out_of_time = false;
+    for folder in &config.folders {
+        if config.deadline.is_some_and(|d| Instant::now() >= d) {
+            println!("\nTime limit reached (--max-runtime): folder {folder} not started.");
+            out_of_time = true;
+            continue;
+        }
+        println!("\n=== Folder {folder} ===\n");
+        let result = run_account(http, &config.for_folder(folder), s3, bucket).await;
+        match result {
+            Ok(ref stats) => out_of_time |= stats.out_of_time,
+            Err(ref e) => eprintln!("Error: folder {folder}: {e:#}"),
+        }
+        results.push((folder.to_string(), result));
+    }
+
+    println!("\nFolders:");
+    let mut total = print_totals(&results);
+    total.out_of_time = out_of_time;
+    let failed = incomplete(&results);
+    if !failed.is_empty() {
+        anyhow::bail!("Backup did not complete for folder(s): {failed}");
+    }
+    Ok(total)
+}
+
+/// Prints a line for each of several runs and their total, and returns
+/// the total.
+fn print_totals(results: &[(String, Result<transfer::RunStats>)]) -> transfer::RunStats {
+    let mut total = transfer::RunStats::default();
+    for (name, result) in results {
+        match result {
+            Ok(stats) => {
+                println!(
+                    "  {name}: {} uploaded ({}), {} failed",
+                    stats.uploaded,
+                    HumanBytes(stats.bytes_uploaded),
+                    stats.failed
+                );
+                total.uploaded += stats.uploaded;
+                total.bytes_uploaded += stats.bytes_uploaded;
+                total.failed += stats.failed;
+            }
+            Err(_) => println!("  {name}: did not complete"),
+        }
+    }
+    println!(
+        "  total: {} uploaded ({}), {} failed",
+        total.uploaded,
+        HumanBytes(total.bytes_uploaded),
+        total.failed
+    );
+    total
+}
+
+/// The names of the runs among `results` that didn't complete.
+fn incomplete(results: &[(String, Result<transfer::RunStats>)]) -> String {
+    results
+        .iter()
+        .filter(|(_, r)| r.is_err())
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// How many days' backups pruning keeps.
+const KEEP_DAYS: usize = 3;
+
+/// The backup prefixes under `root` (sorted, as `list_backup_prefixes`
+/// returns them) from before the `keep` most recent days that have any.
+/// With `--prefix-format` datetime or run-id a day may have several, which
+/// are kept or pruned together.
+fn old_backups<'a>(prefixes: &'a [String], root: &str, keep: usize) -> Vec<&'a String> {
+    let date = |prefix: &'a String| {
+        prefix
+            .strip_prefix(root)
+            .and_then(|name| name.strip_suffix('/'))
+            .and_then(config::PrefixFormat::date_of)
+    };
+    let mut dates: Vec<&str> = prefixes.iter().filter_map(date).collect();
+    dates.dedup();
+    let Some(&oldest_kept) = dates.get(dates.len().saturating_sub(keep)) else {
+        return Vec::new();
+    };
+    prefixes
+        .iter()
+        .filter(|p| date(p).is_some_and(|d| d < oldest_kept))
+        .collect()
+}
+
+/// Prunes and backs up one account (the only one without `GOOGLE_ACCOUNTS`)
+/// under a new prefix for this run, named per `--prefix-format`.
+async fn run_account(
+    http: &Client,
+    config: &config::Config,
+    s3: &aws::S3Uploader,
+    bucket: &str,
+) -> Result<transfer::RunStats> {
+    let date_prefix = format!(
+        "{}{}",
+        config.output_prefix,
+        config.prefix_format.prefix(Utc::now())
+    );
+    println!("Backup prefix: {date_prefix}/");
+
+    if config.dry_run {
+        println!("Dry run: nothing will be uploaded, pruned or deleted.");
+        return backup(http, config, s3, bucket, &date_prefix).await;
+    }
 
-    // Prune old backups — keep only the 3 most recent date prefixes.
+    // Prune old backups — keep only those of the 3 most recent days.
     println!("Checking for old backups to prune ...");
-    match s3.list_backup_prefixes().await {
+    match s3.list_backup_prefixes(&config.output_prefix).await {
         Err(e) => eprintln!("Warning: could not list backup prefixes: {e:#}"),
         Ok(prefixes) => {
-            let keep = 3usize;
-            if prefixes.len() > keep {
-                let to_delete = &prefixes[..prefixes.len() - keep];
+            let to_delete = old_backups(&prefixes, &config.output_prefix, KEEP_DAYS);
+            if !to_delete.is_empty() {
                 for prefix in to_delete {
                     print!("  Deleting old backup {prefix} ... ");
                     match s3.delete_prefix(prefix).await {
@@ -86,14 +770,41 @@ async fn main() -> Result<()> {
         }
     }
 
-    if prune_only {
-        return Ok(());
+    if config.prune_only {
+        return Ok(transfer::RunStats::default());
     }
 
+    let lock = lock::acquire(s3, &lock::key(config, &date_prefix), config.force).await?;
+    let started_at = Utc::now();
+    let result = backup(http, config, s3, bucket, &date_prefix).await;
+    marker::write(s3, config, &date_prefix, started_at, &result).await;
+    lock::release(s3, &lock).await;
+    result
+}
+
+/// One backup run under `date_prefix`: lists the source, archives what it
+/// found and reports on it.
+async fn backup(
+    http: &Client,
+    config: &config::Config,
+    s3: &aws::S3Uploader,
+    bucket: &str,
+    date_prefix: &str,
+) -> Result<transfer::RunStats> {
     // In test mode skip Google Drive entirely and generate a small local file.
     // In normal mode authenticate, find the folder, and list files from Drive.
     let tmp_dir = tempfile::tempdir()?;
-    let (files, mut drive_client, mut google_token) = if test_mode {
+    // Subfolders walked with --recursive, for --prune-empty-folders.
+    let mut subfolders = Vec::new();
+    // --retry-from or --file-ids ids that could not be fetched from Drive.
+    let mut retry_unavailable = 0;
+    // Skipped Workspace files, for --delete-workspace-files.
+    let mut workspace_files = Vec::new();
+    // Drive usage before the run, to report the change at the end.
+    let mut quota_before = None;
+    // With --stream-listing, the folders to list while the backup runs.
+    let mut streamed_folders: Option<Vec<String>> = None;
+    let (files, drive_client, photos_client) = if config.test_mode {
         println!("Test mode: skipping Google Drive, using local test file.");
 
         let path = tmp_dir.path().join("test-upload.txt");
@@ -112,23 +823,196 @@ async fn main() -> Result<()> {
             name: "test-upload.txt".to_string(),
             mime_type: "text/plain".to_string(),
             size: Some(size.to_string()),
+            md5_checksum: None,
+            trashed: false,
+            modified_time: None,
+            capabilities: None,
+            shortcut_details: None,
+            shortcut_id: None,
+            folder_path: String::new(),
             local_path: Some(path),
         };
 
         (vec![fake_file], None, None)
+    } else if config.source == config::Source::Photos {
+        println!(
+            "Note: the Photos Library API only lists media created by this app's OAuth \
+             client; back up an existing library from a Takeout with --source=drive."
+        );
+        println!("Authenticating with Google Photos ...");
+        let token = auth::load_or_authenticate(http, config).await?;
+        let photos = photos::PhotosClient::new(http, auth::TokenHolder::new(http, config, token))
+            .with_max_file_size(config.max_file_size)
+            .with_write_buffer(config.download_buffer);
+
+        let files = match config.album {
+            Some(ref wanted) => {
+                let album = photos.find_album(wanted).await?;
+                let title = album.title.as_deref().unwrap_or("(untitled)");
+                println!("Listing album \"{title}\" ({}) ...", album.kind());
+                let files = photos.list_album_items(&album.id).await?;
+                match album.media_items_count {
+                    Some(ref count) if *count != files.len().to_string() => println!(
+                        "  {} item(s) listed; the album reports {count}.",
+                        files.len()
+                    ),
+                    _ => println!("  {} item(s).", files.len()),
+                }
+                files
+            }
+            None => {
+                println!("Listing media items ...");
+                photos.list_media_items().await?
+            }
+        };
+        (files, None, Some(photos))
     } else {
+        let modified_after = match config.since_object {
+            Some(ref key) => {
+                let key = format!("{}{key}", config.output_prefix);
+                match marker::since(s3, &key).await? {
+                    Some((after, from)) => {
+                        println!(
+                            "Backing up files modified after {} ({from} of {key}).",
+                            after.to_rfc3339()
+                        );
+                        Some(after)
+                    }
+                    None => {
+                        println!("{key} isn't in S3: running a full backup.");
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
         println!("Authenticating with Google Drive ...");
-        let token = auth::load_or_authenticate(&http, &creds_file, &token_file).await?;
-        let drive = drive::DriveClient::new(&http, token.access_token.clone());
+        let token = auth::load_or_authenticate(http, config).await?;
+        let drive = drive::DriveClient::new(http, auth::TokenHolder::new(http, config, token))
+            .with_size_check(config.size_check)
+            .with_newest_first(config.newest.is_some())
+            .with_max_file_size(config.max_file_size)
+            .with_modified_after(modified_after)
+            .with_write_buffer(config.download_buffer)
+            .with_checksum(config.checksum_algo);
+
+        match drive.storage_quota().await {
+            Ok(quota) => {
+                print_quota("Drive storage", &quota);
+                quota_before = Some(quota);
+            }
+            Err(e) => eprintln!("Warning: could not read Drive storage quota: {e:#}"),
+        }
+
+        // With --confirm-delete, only once deleting is confirmed.
+        if !config.confirm_delete {
+            purge(&drive, config).await?;
+        }
 
-        println!("Looking up folder \"{DRIVE_FOLDER_NAME}\" ...");
-        let folder_id = drive.find_folder(DRIVE_FOLDER_NAME).await?;
+        let all_files = match (&config.retry_from, &config.file_ids) {
+            (Some(report), _) => {
+                let (found, unavailable) = retry_candidates(&drive, report).await?;
+                retry_unavailable = unavailable;
+                found
+            }
+            (None, Some(ids)) => {
+                println!("Fetching {} file(s) by id ...", ids.len());
+                let (found, unavailable) = fetch_files(&drive, ids).await;
+                retry_unavailable = unavailable;
+                found
+            }
+            (None, None) => {
+                let folder_ids = if config.shared_with_me {
+                    println!("Looking for files shared with you ...");
+                    vec![drive::SHARED_WITH_ME.to_string()]
+                } else if let Some(ref id) = config.folder_id {
+                    let folder = drive
+                        .get_file(id)
+                        .await
+                        .with_context(|| format!("Cannot find Drive folder {id}"))?;
+                    if !drive::is_folder(&folder) {
+                        anyhow::bail!("{id} (\"{}\") is not a Drive folder", folder.name);
+                    }
+                    println!("Using folder \"{}\" ({id}) ...", folder.name);
+                    vec![id.clone()]
+                } else {
+                    println!("Looking up folder \"{}\" ...", config.folder_name);
+                    drive
+                        .find_folders(
+                            &config.folder_name,
+                            config.parent_id.as_deref(),
+                            config.on_ambiguous,
+                        )
+                        .await?
+                };
 
-        println!("Listing files ...");
-        let all_files = drive.list_files(&folder_id).await?;
+                let mut all_files = Vec::new();
+                if config.stream_listing {
+                    // Listed page by page as the pipeline asks for more.
+                    streamed_folders = Some(folder_ids);
+                } else {
+                    println!("Listing files ...");
+                    for folder_id in &folder_ids {
+                        if config.recursive {
+                            let (found, folders) = drive
+                                .list_tree(folder_id, config.trashed, config.listing_concurrency)
+                                .await?;
+                            all_files.extend(found);
+                            subfolders.extend(folders);
+                        } else {
+                            all_files.extend(drive.list_files(folder_id, config.trashed).await?);
+                        }
+                    }
+                }
+                all_files
+            }
+        };
+
+        let (folders, all_files): (Vec<_>, Vec<_>) =
+            all_files.into_iter().partition(drive::is_folder);
+        if !folders.is_empty() {
+            println!(
+                "Skipping {} subfolder(s) (pass --recursive to back up their contents):",
+                folders.len()
+            );
+            for f in &folders {
+                println!("  - {}/", f.name);
+            }
+            println!();
+        }
+
+        let (shortcuts, mut all_files): (Vec<_>, Vec<_>) =
+            all_files.into_iter().partition(drive::is_shortcut);
+
+        if !shortcuts.is_empty() {
+            match config.shortcuts {
+                config::ShortcutPolicy::Skip => {
+                    println!(
+                        "Skipping {} Drive shortcut(s) (set DRIVE_SHORTCUTS=resolve to back up their targets):",
+                        shortcuts.len()
+                    );
+                    for f in &shortcuts {
+                        println!("  - {}", f.name);
+                    }
+                    println!();
+                }
+                config::ShortcutPolicy::Resolve => {
+                    println!("Resolving {} Drive shortcut(s) ...", shortcuts.len());
+                    for f in &shortcuts {
+                        match drive.resolve_shortcut(f).await {
+                            Ok(target) => all_files.push(target),
+                            Err(e) => {
+                                eprintln!("  Warning: skipping shortcut \"{}\": {e:#}", f.name)
+                            }
+                        }
+                    }
+                }
+            }
+        }
 
-        let (workspace, files): (Vec<_>, Vec<_>) =
-            all_files.into_iter().partition(drive::is_workspace_file);
+        let (workspace, files): (Vec<DriveFile>, Vec<_>) = all_files
+            .into_iter()
+            .partition(|f| drive::is_workspace_file(f, &config.workspace_mimetypes));
 
         if !workspace.is_empty() {
             println!(
@@ -140,164 +1024,407 @@ async fn main() -> Result<()> {
             }
             println!();
         }
+        workspace_files = workspace;
 
-        (files, Some(drive), Some(token))
-    };
-
-    println!(
-        "Found {} file(s) to back up under s3://{bucket}/{date_prefix}/\n",
-        files.len()
-    );
-
-    let total = files.len();
-    let (mut uploaded, mut failed, mut not_deleted) = (0usize, 0usize, 0usize);
+        // Shared files whose owner disabled downloading for viewers.
+        let (files, locked): (Vec<DriveFile>, Vec<_>) =
+            files.into_iter().partition(DriveFile::can_download);
+        if !locked.is_empty() {
+            println!(
+                "Skipping {} file(s) without download permission:",
+                locked.len()
+            );
+            for f in &locked {
+                println!("  - {}", f.name);
+            }
+            println!();
+        }
 
-    let mp = MultiProgress::new();
+        (files, Some(drive), None)
+    };
 
-    let overall = mp.add(ProgressBar::new(total as u64));
-    overall.set_style(
-        ProgressStyle::with_template("[{pos}/{len}] {bar:40.green/white} {msg}")?
-            .progress_chars("█▉▊▋▌▍▎▏ "),
-    );
-    overall.set_message("starting ...");
+    // --only-ext / --skip-ext, on top of the mimeType-based skips above.
+    let (files, by_extension): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|f| config.test_mode || config.extension_allowed(&f.name));
+    if !by_extension.is_empty() {
+        println!(
+            "Skipping {} file(s) by extension (--only-ext / --skip-ext):",
+            by_extension.len()
+        );
+        for f in &by_extension {
+            println!("  - {}", f.name);
+        }
+        println!();
+    }
 
-    let dl_style = ProgressStyle::with_template(
-        "  ↓  {bar:30.cyan/white} {bytes}/{total_bytes} at {bytes_per_sec} eta {eta}",
-    )?
-    .progress_chars("█▉▊▋▌▍▎▏ ");
+    // --newest: the listing came newest first, but several folders (or
+    // --recursive) still need merging.
+    let mut files = match config.newest {
+        Some(n) if files.len() > n => {
+            let mut files = files;
+            files.sort_by_key(|f| std::cmp::Reverse(f.modified_time));
+            files.truncate(n);
+            println!("Backing up only the {n} most recently modified file(s) (--newest)\n");
+            files
+        }
+        _ => files,
+    };
+    config.order.sort(&mut files);
 
-    let spinner_style =
-        ProgressStyle::with_template("  {spinner:.yellow}  {msg}")?.tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ");
+    if streamed_folders.is_some() {
+        println!("Backing up files as they are listed, under s3://{bucket}/{date_prefix}/\n");
+    } else {
+        println!(
+            "Found {} file(s) to back up under s3://{bucket}/{date_prefix}/\n",
+            files.len()
+        );
+    }
 
-    for (i, file) in files.iter().enumerate() {
-        overall.set_message(file.name.clone());
+    if config.dry_run {
+        let bytes = files.iter().filter_map(DriveFile::size_bytes).sum();
+        let no_size = files.iter().filter(|f| f.size_bytes().is_none()).count();
+        println!("Total: {} in {} file(s)", HumanBytes(bytes), files.len());
+        if no_size > 0 {
+            println!("  ({no_size} file(s) without a reported size not counted)");
+        }
+        let prices = cost::load_prices(config.price_table.as_deref()).await?;
+        cost::print_estimate(&prices, &config.estimate_class, bytes)?;
+        return Ok(transfer::RunStats::default());
+    }
 
-        // Refresh the Google token before each file in case it expired mid-run.
-        if let Some(token) = google_token.take() {
-            match auth::ensure_fresh(&http, &creds_file, &token_file, token).await {
-                Ok(fresh) => {
-                    if let Some(ref mut drive) = drive_client {
-                        drive.set_token(fresh.access_token.clone());
-                    }
-                    google_token = Some(fresh);
-                }
-                Err(e) => {
-                    overall.println(format!("Warning: token refresh failed: {e:#}"));
-                }
+    let listed_at = streamed_folders.is_none().then(Utc::now);
+    let kept_in_drive;
+    let config = if config.confirm_delete && !config.keep_in_drive && drive_client.is_some() {
+        if confirm_delete(config, &files, streamed_folders.is_some())? {
+            if let Some(ref drive) = drive_client {
+                purge(drive, config).await?;
             }
+            config
+        } else {
+            println!("Not confirmed: files will be archived but kept in Drive.\n");
+            kept_in_drive = config::Config {
+                keep_in_drive: true,
+                ..config.clone()
+            };
+            &kept_in_drive
         }
+    } else {
+        config
+    };
 
-        // Sanitize the filename to prevent path traversal when writing to the
-        // temp directory. Replace any path separator or null byte with '_'.
-        let safe_name: String = file
-            .name
-            .chars()
-            .map(|c| if matches!(c, '/' | '\\' | '\0') { '_' } else { c })
-            .collect();
+    // With --keep-local, download straight into the kept directory so the
+    // final move into place is a rename on the same filesystem.
+    let work_dir = match config.keep_local {
+        Some(ref dir) => {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("Cannot create {}", dir.display()))?;
+            println!("Keeping local copies under {}", dir.display());
+            dir.as_path()
+        }
+        None => tmp_dir.path(),
+    };
 
-        let s3_key = format!("{date_prefix}/{safe_name}");
+    let ctx = transfer::Context {
+        config,
+        s3,
+        drive: drive_client.as_ref(),
+        photos: photos_client.as_ref(),
+        tmp_dir: work_dir,
+        bucket,
+        date_prefix,
+        listed_at,
+    };
+    let stats = match (&streamed_folders, drive_client.as_ref()) {
+        (Some(folder_ids), Some(drive)) => {
+            transfer::run_stream(&ctx, streamed_files(drive, folder_ids, config), None).await?
+        }
+        _ if config.bundle => bundle::run(&ctx, &files).await?,
+        _ => transfer::run(&ctx, &files).await?,
+    };
+    let total = stats.started + stats.not_started;
 
-        // In test mode the file is already local; in normal mode download from Drive.
-        let tmp_path = if let Some(ref local) = file.local_path {
-            local.clone()
-        } else {
-            let path = tmp_dir.path().join(&safe_name);
-            let dl_bar = mp.insert_after(&overall, ProgressBar::new(0));
-            dl_bar.set_style(dl_style.clone());
-
-            let dl_result = retry(|| async {
-                dl_bar.reset();
-                drive_client.as_ref().unwrap().download(file, &path, &dl_bar).await
-            }).await;
-            match dl_result {
-                Err(e) => {
-                    dl_bar.finish_and_clear();
-                    overall.println(format!(
-                        "[{}/{}] ✗ {} — download error: {e:#}",
-                        i + 1,
-                        total,
-                        file.name
-                    ));
-                    failed += 1;
-                    continue;
-                }
-                Ok(()) => dl_bar.finish_and_clear(),
+    println!(
+        "\nBackup complete: {}/{total} uploaded ({}), {} failed.",
+        stats.uploaded,
+        HumanBytes(stats.bytes_uploaded),
+        stats.failed
+    );
+    print_throughput(&stats);
+    if stats.deduplicated > 0 {
+        println!(
+            "Dedup: {} duplicate(s) not stored again, {} saved.",
+            stats.deduplicated,
+            HumanBytes(stats.bytes_deduplicated)
+        );
+    }
+    if stats.slow_downs > 0 {
+        println!(
+            "S3 SlowDown: {} throttled request(s), retried with backoff; uploads slowed down meanwhile.",
+            stats.slow_downs
+        );
+    }
+    if stats.revisions > 0 {
+        println!(
+            "Revisions: {} earlier revision(s) archived ({}).",
+            stats.revisions,
+            HumanBytes(stats.bytes_revisions)
+        );
+    }
+    if stats.not_overwritten > 0 {
+        println!(
+            "Not overwritten: {} file(s) whose S3 key already held other content \
+             (--no-overwrite) — they remain in Drive.",
+            stats.not_overwritten
+        );
+    }
+    if stats.unstable > 0 {
+        println!(
+            "Still changing: {} file(s) differed from the listing when their download was \
+             due (--stable-check) — they remain in Drive for the next run.",
+            stats.unstable
+        );
+    }
+    if stats.out_of_time {
+        println!(
+            "Time limit reached (--max-runtime): {} file(s) not started — they remain in Drive \
+             for the next run.",
+            stats.not_started
+        );
+    } else if stats.not_started > 0 {
+        println!(
+            "Run cap reached: {} file(s) not started — they remain in Drive for the next run.",
+            stats.not_started
+        );
+    }
+    if stats.not_deleted > 0 {
+        eprintln!(
+            "Warning: {} file(s) were archived to S3 but could not be \
+             deleted from Google Drive. Check Drive manually.",
+            stats.not_deleted
+        );
+    }
+    if stats.delete_denied > 0 {
+        eprintln!(
+            "  {} of them for lack of permission, which a re-run won't fix{}",
+            stats.delete_denied,
+            if stats.delete_denied_scope {
+                format!(
+                    ": the token lacks the full Drive scope — delete {} and sign in again.",
+                    config.token_file
+                )
+            } else {
+                " (owned by someone else, or in a shared drive you don't manage).".to_string()
             }
-            path
-        };
+        );
+    }
 
-        // Upload with a spinner (S3 SDK doesn't expose byte-level progress).
-        let spinner = mp.insert_after(&overall, ProgressBar::new_spinner());
-        spinner.set_style(spinner_style.clone());
-        spinner.set_message(format!("Uploading to s3://{bucket}/{s3_key}"));
-        spinner.enable_steady_tick(Duration::from_millis(80));
-        match retry(|| s3.upload(&s3_key, &tmp_path)).await {
-            Err(e) => {
-                spinner.finish_and_clear();
-                overall.println(format!(
-                    "[{}/{}] ✗ {} — upload error: {e:#}",
-                    i + 1,
-                    total,
-                    file.name
-                ));
-                failed += 1;
-                if file.local_path.is_none() {
-                    let _ = tokio::fs::remove_file(&tmp_path).await;
-                }
-                continue;
+    if stats.removed > 0 {
+        match config.delete_after {
+            Some(days) => println!(
+                "Drive: {} file(s) moved to the trash ({}), freed once purged after {days} day(s).",
+                stats.removed,
+                HumanBytes(stats.bytes_removed)
+            ),
+            None => println!(
+                "Drive: {} file(s) deleted, freed {} from Google Drive.",
+                stats.removed,
+                HumanBytes(stats.bytes_removed)
+            ),
+        }
+    }
+    if config.retry_from.is_some() {
+        println!(
+            "Retry: {} recovered, {} still failing.",
+            stats.uploaded,
+            stats.failed + retry_unavailable
+        );
+    }
+    if config.file_ids.is_some() && retry_unavailable > 0 {
+        println!(
+            "File ids: {retry_unavailable} not found or not accessible (see the warnings above)."
+        );
+    }
+    if config.delete_workspace_files && !config.keep_in_drive && !workspace_files.is_empty() {
+        if let Some(ref drive) = drive_client {
+            let removed = delete_workspace_files(drive, config, &workspace_files).await;
+            let how = if config.delete_after.is_some() {
+                "moved to the Drive trash"
+            } else {
+                "deleted from Drive"
+            };
+            println!(
+                "{removed}/{} Workspace file(s) {how} (not archived).",
+                workspace_files.len()
+            );
+        }
+    }
+    if config.prune_empty_folders && !config.keep_in_drive {
+        if let Some(ref drive) = drive_client {
+            println!("Pruning emptied folders ...");
+            let pruned = drive.prune_emptied(&subfolders).await;
+            println!("{pruned} emptied folder(s) moved to the Drive trash.");
+        }
+    }
+    if let (Some(before), Some(drive)) = (quota_before, drive_client.as_ref()) {
+        match drive.storage_quota().await {
+            Ok(after) => {
+                print_quota("Drive storage now", &after);
+                let change = after.used() as i64 - before.used() as i64;
+                let change = if change <= 0 {
+                    format!("{} freed", HumanBytes(change.unsigned_abs()))
+                } else {
+                    format!("{} more used", HumanBytes(change as u64))
+                };
+                // Drive updates usage lazily, and trashed files still count.
+                println!("  {change} (usage can take a while to update; the trash counts too)");
             }
-            Ok(()) => spinner.finish_and_clear(),
+            Err(e) => eprintln!("Warning: could not read Drive storage quota: {e:#}"),
         }
+    }
+    Ok(stats)
+}
 
-        // Only delete from Drive after a confirmed successful S3 upload.
-        // In test mode there is no Drive file to delete.
-        if let Some(token) = google_token.take() {
-            match auth::ensure_fresh(&http, &creds_file, &token_file, token).await {
-                Ok(fresh) => {
-                    if let Some(ref mut drive) = drive_client {
-                        drive.set_token(fresh.access_token.clone());
-                    }
-                    google_token = Some(fresh);
-                }
-                Err(e) => {
-                    overall.println(format!("Warning: token refresh failed before delete: {e:#}"));
+/// Keeps concurrent multipart uploads (the streams of `--stream-upload`,
+/// otherwise the upload workers, each with `--concurrency-parts` parts in
+/// flight) within `--memory-budget` and the memory available, lowering the
+/// concurrency if they wouldn't fit.
+fn limit_memory(config: &mut config::Config) -> Result<()> {
+    let per_upload = if config.bundle || config.stream_upload {
+        aws::UPLOAD_MEMORY
+    } else {
+        aws::file_upload_memory(config.part_concurrency)
+    };
+    let streams = if config.bundle {
+        1
+    } else if config.stream_upload {
+        config.download_concurrency
+    } else {
+        config.upload_concurrency
+    };
+    let available = available_memory();
+
+    if let Some(budget) = config.memory_budget {
+        if budget < per_upload {
+            anyhow::bail!(
+                "--memory-budget {} is below what a single upload needs ({}{})",
+                HumanBytes(budget),
+                HumanBytes(per_upload),
+                if config.bundle || config.stream_upload {
+                    ""
+                } else {
+                    "; fewer --concurrency-parts need less"
                 }
-            }
+            );
         }
-        if let Some(drive) = &drive_client {
-            match drive.delete(&file.id).await {
-                Ok(()) => {
-                    overall.println(format!("[{}/{}] ✓ {}", i + 1, total, file.name));
-                }
-                Err(e) => {
-                    overall.println(format!(
-                        "[{}/{}] ✓ {} (uploaded) — warning: Drive delete failed: {e}",
-                        i + 1,
-                        total,
-                        file.name
-                    ));
-                    not_deleted += 1;
-                }
-            }
+    }
+    let Some(limit) = config.memory_budget.into_iter().chain(available).min() else {
+        return Ok(());
+    };
+    // Always allow one, even when the system reports less available.
+    let fits = ((limit / per_upload) as usize).max(1);
+    let allowed = streams.min(fits);
+    if allowed < streams {
+        let what = if config.stream_upload {
+            "download"
         } else {
-            overall.println(format!("[{}/{}] ✓ {}", i + 1, total, file.name));
+            "upload"
+        };
+        eprintln!(
+            "Memory guard: {what} concurrency lowered from {streams} to {allowed} to fit {}",
+            HumanBytes(limit)
+        );
+        if config.stream_upload {
+            config.download_concurrency = allowed;
+        } else {
+            config.upload_concurrency = allowed;
         }
+        config.concurrency_min = config.concurrency_min.min(allowed);
+    }
+    if config.stream_upload || config.memory_budget.is_some() || allowed < streams {
+        println!(
+            "Memory: up to {allowed} upload(s) × {} ≈ {} (limit {}{})",
+            HumanBytes(per_upload),
+            HumanBytes(per_upload * allowed as u64),
+            HumanBytes(limit),
+            match available {
+                Some(a) => format!(", {} available", HumanBytes(a)),
+                None => String::new(),
+            }
+        );
+    }
+    if let Some(max) = config.temp_in_memory {
+        // Each download, queue slot and upload may hold one.
+        let held = config.download_concurrency + config.upload_queue() + config.upload_concurrency;
+        println!(
+            "Memory: files up to {} held in memory, at most {held} at once ≈ {}",
+            HumanBytes(max),
+            HumanBytes(max * held as u64)
+        );
+    }
+    Ok(())
+}
 
-        if file.local_path.is_none() {
-            let _ = tokio::fs::remove_file(&tmp_path).await;
-        }
-        uploaded += 1;
-        overall.inc(1);
+/// `MemAvailable` from `/proc/meminfo`; `None` where there's no such file
+/// (e.g. macOS).
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+fn print_quota(label: &str, quota: &drive::StorageQuota) {
+    let limit = quota
+        .limit()
+        .map_or_else(|| "unlimited".to_string(), |l| HumanBytes(l).to_string());
+    println!(
+        "{label}: {} of {limit} used ({} in the trash)",
+        HumanBytes(quota.used()),
+        HumanBytes(quota.in_trash())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(prefixes: &[&str]) -> Vec<String> {
+        prefixes.iter().map(|p| format!("host-a/{p}/")).collect()
     }
 
-    overall.finish_and_clear();
+    #[test]
+    fn keeps_the_last_days_of_daily_prefixes() {
+        let prefixes = names(&["2026-01-01", "2026-01-02", "2026-01-03", "2026-01-04"]);
+        assert_eq!(old_backups(&prefixes, "host-a/", 3), [&prefixes[0]]);
+        assert!(old_backups(&prefixes[1..], "host-a/", 3).is_empty());
+    }
 
-    println!("\nBackup complete: {uploaded}/{total} uploaded, {failed} failed.");
-    if not_deleted > 0 {
-        eprintln!(
-            "Warning: {not_deleted} file(s) were archived to S3 but could not be \
-             deleted from Google Drive. Check Drive manually."
+    #[test]
+    fn runs_of_one_day_count_as_one() {
+        let prefixes = names(&[
+            "2026-01-01T020000",
+            "2026-01-02T020000",
+            "2026-01-03T020000",
+            "2026-01-04T020000",
+            "2026-01-04T080000",
+            "2026-01-04T140000",
+        ]);
+        // Three runs on the 4th still leave the 2nd and 3rd.
+        assert_eq!(old_backups(&prefixes, "host-a/", 3), [&prefixes[0]]);
+    }
+
+    #[test]
+    fn run_ids_group_by_their_date() {
+        let prefixes = names(&[
+            "2026-01-01-0194b2a0-0000-7000-8000-000000000000",
+            "2026-01-01-0194b2a0-0001-7000-8000-000000000000",
+            "2026-01-02-0194b7c6-0000-7000-8000-000000000000",
+        ]);
+        assert_eq!(
+            old_backups(&prefixes, "host-a/", 1),
+            [&prefixes[0], &prefixes[1]]
         );
     }
-    Ok(())
 }
@@ -0,0 +1,132 @@
+use crate::fsutil;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Record of every file archived under one S3 prefix. Kept on local disk
+/// because the bucket moves objects to Deep Archive immediately, so anything
+/// we uploaded can't be read back without a restore.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub prefix: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ManifestEntry {
+    pub file_id: String,
+    /// Set when the file was reached through this shortcut, which is what
+    /// gets deleted rather than the file itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shortcut_id: Option<String>,
+    pub name: String,
+    pub key: String,
+    pub size: Option<u64>,
+    /// MD5 reported by Drive, when it provided one.
+    pub md5: Option<String>,
+    pub uploaded_at: DateTime<Utc>,
+    pub deleted_from_drive: bool,
+    /// Set when the content was already archived at this other key in the
+    /// same run (`--dedup`), so nothing was stored at `key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    /// Set when the Drive copy was moved to the trash (`--delete-after`)
+    /// rather than deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trashed: Option<Trashed>,
+    /// Set when the file was stored inside this `--bundle` tar, so `key`
+    /// names its entry in the tar (`<tar key>/<entry>`) rather than an object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<String>,
+    /// The Drive revision id, for an earlier revision archived with
+    /// `--include-revisions` below its file's key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+}
+
+impl ManifestEntry {
+    /// The S3 object holding this file's content: the bundle or the earlier
+    /// upload it duplicates, if either, else `key`.
+    pub fn object_key(&self) -> &str {
+        self.bundle
+            .as_deref()
+            .or(self.duplicate_of.as_deref())
+            .unwrap_or(&self.key)
+    }
+}
+
+/// A Drive item this tool trashed, awaiting permanent deletion.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Trashed {
+    /// The item trashed: the shortcut, for a resolved shortcut.
+    pub id: String,
+    pub at: DateTime<Utc>,
+    /// When a later run deleted it for good (or found Drive already had).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purged_at: Option<DateTime<Utc>>,
+}
+
+/// Where the manifest for `prefix` lives under `dir`.
+pub fn path_for(dir: &str, prefix: &str) -> PathBuf {
+    Path::new(dir).join(format!("{}.json", prefix.trim_end_matches('/')))
+}
+
+impl Manifest {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Loads the manifest at `path`, or `None` if it doesn't exist yet.
+    pub async fn load(path: &Path) -> Result<Option<Self>> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(data) => Ok(Some(serde_json::from_str(&data).with_context(|| {
+                format!("Cannot parse manifest: {}", path.display())
+            })?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Cannot read manifest: {}", path.display())),
+        }
+    }
+
+    /// Adds `entry`, replacing any earlier entry for the same key (a later
+    /// upload to the same key overwrote the object).
+    pub fn record(&mut self, entry: ManifestEntry) {
+        self.files.retain(|e| e.key != entry.key);
+        self.files.push(entry);
+    }
+
+    /// How many files earlier runs finished with, archived and out of Drive
+    /// so a new listing leaves them out, and the bytes stored for them.
+    pub fn completed(&self) -> (u64, u64) {
+        let done: HashSet<&str> = self
+            .files
+            .iter()
+            .filter(|e| e.deleted_from_drive || e.trashed.is_some())
+            .map(|e| e.file_id.as_str())
+            .collect();
+        let bytes = self
+            .files
+            .iter()
+            .filter(|e| e.duplicate_of.is_none() && done.contains(e.file_id.as_str()))
+            .filter_map(|e| e.size)
+            .sum();
+        (done.len() as u64, bytes)
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let json = serde_json::to_string_pretty(self)?;
+
+        // Write-then-rename so a crash mid-write never truncates the record.
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, &json).await?;
+        fsutil::move_file(&tmp_path, path).await?;
+        Ok(())
+    }
+}
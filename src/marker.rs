@@ -0,0 +1,116 @@
+use crate::aws::S3Uploader;
+use crate::config::Config;
+use crate::transfer::RunStats;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+/// User metadata on a marker holding the run's start time.
+const STARTED_AT: &str = "started-at";
+
+/// `--since-object`: when the backup `key` marks started, so a run can pick
+/// up what changed after it. That's the start time recorded on a marker
+/// this tool wrote, and otherwise when the object was written, along with
+/// which of the two it was. `None` if there's no such object.
+pub async fn since(s3: &S3Uploader, key: &str) -> Result<Option<(DateTime<Utc>, &'static str)>> {
+    let Some((written, metadata)) = s3.head_metadata(key).await? else {
+        return Ok(None);
+    };
+    let started = metadata
+        .get(STARTED_AT)
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+    Ok(match (started, written) {
+        (Some(t), _) => Some((t.with_timezone(&Utc), "the run's start time")),
+        (None, Some(t)) => Some((t, "the object's LastModified")),
+        (None, None) => None,
+    })
+}
+
+/// The marker objects this configuration writes, if any: the completion
+/// marker and the failure marker.
+pub fn names(config: &Config) -> Vec<&str> {
+    match config.completion_marker {
+        Some(ref complete) => vec![complete.as_str(), config.failure_marker.as_str()],
+        None => Vec::new(),
+    }
+}
+
+/// Whether a run with this `result` counts as fully successful: it ended
+/// without error, no file failed and none was left for a later run.
+fn succeeded(result: &Result<RunStats>) -> bool {
+    result.as_ref().is_ok_and(|s| {
+        s.failed == 0
+            && s.not_started == 0
+            && s.unstable == 0
+            && s.not_overwritten == 0
+            && !s.out_of_time
+    })
+}
+
+/// `--completion-marker`: at the end of a run, writes `{prefix}/<marker>`
+/// with the run's summary as JSON, so a downstream job can poll for it
+/// before reading the backup. A run that didn't fully succeed writes the
+/// failure marker instead. Whichever of the two an earlier run the same day
+/// left is removed. Failures are only warned about; the backup has already
+/// happened either way.
+pub async fn write(
+    s3: &S3Uploader,
+    config: &Config,
+    prefix: &str,
+    started_at: DateTime<Utc>,
+    result: &Result<RunStats>,
+) {
+    let Some(ref complete) = config.completion_marker else {
+        return;
+    };
+    let ok = succeeded(result);
+    let (name, other) = if ok {
+        (complete, &config.failure_marker)
+    } else {
+        (&config.failure_marker, complete)
+    };
+    let stats = result.as_ref().ok();
+    let count = |f: fn(&RunStats) -> u64| stats.map(f);
+    let body = json!({
+        "status": if ok { "complete" } else { "failed" },
+        "prefix": prefix,
+        "started_at": started_at.to_rfc3339(),
+        "finished_at": Utc::now().to_rfc3339(),
+        "files": {
+            "uploaded": count(|s| s.uploaded as u64),
+            "failed": count(|s| s.failed as u64),
+            "deduplicated": count(|s| s.deduplicated as u64),
+            "not_started": count(|s| s.not_started as u64),
+            "unstable": count(|s| s.unstable as u64),
+            "not_overwritten": count(|s| s.not_overwritten as u64),
+            "removed_from_drive": count(|s| s.removed as u64),
+            "not_deleted": count(|s| s.not_deleted as u64),
+            "revisions": count(|s| s.revisions as u64),
+        },
+        "bytes": {
+            "uploaded": count(|s| s.bytes_uploaded),
+            "deduplicated": count(|s| s.bytes_deduplicated),
+            "revisions": count(|s| s.bytes_revisions),
+            "removed_from_drive": count(|s| s.bytes_removed),
+        },
+        "out_of_time": stats.is_some_and(|s| s.out_of_time),
+        "error": result.as_ref().err().map(|e| format!("{e:#}")),
+    });
+
+    let key = format!("{prefix}/{name}");
+    let body = serde_json::to_string_pretty(&body).unwrap_or_default();
+    // Also as metadata, for `--since-object`: it's readable after the
+    // lifecycle has archived the marker, the body isn't.
+    let metadata = [(STARTED_AT, started_at.to_rfc3339())];
+    match s3
+        .put_small(&key, body, "application/json", &metadata, false)
+        .await
+    {
+        Ok(_) => println!("Marker written: {key}"),
+        Err(e) => eprintln!("Warning: could not write the marker {key}: {e:#}"),
+    }
+    let stale = format!("{prefix}/{other}");
+    if let Err(e) = s3.delete_object(&stale).await {
+        eprintln!("Warning: could not remove the marker {stale}: {e:#}");
+    }
+}
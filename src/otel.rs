@@ -0,0 +1,299 @@
+use crate::http_log::SendLogged;
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often finished spans are sent while a run is going.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Set once at startup by `--otlp-endpoint`.
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+struct Tracer {
+    http: Client,
+    /// `<endpoint>/v1/traces`.
+    url: String,
+    /// The whole run is one trace.
+    trace_id: String,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The run's root span, parent of every file span.
+    root: Option<String>,
+    /// Open spans by key: `run`, `<file id>`, or `<file id>/<stage>`.
+    open: HashMap<String, Span>,
+    /// Ended spans waiting to be exported.
+    done: Vec<Span>,
+    export_failed: bool,
+}
+
+struct Span {
+    id: String,
+    parent: Option<String>,
+    name: String,
+    start: SystemTime,
+    end: Option<SystemTime>,
+    attributes: Vec<(&'static str, Value)>,
+    /// `Some` once ended with an error.
+    error: Option<String>,
+}
+
+fn random_hex(bytes: usize) -> String {
+    (0..bytes)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect()
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// An OTLP attribute value: strings, integers and booleans.
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_u64() || n.is_i64() => json!({ "intValue": n.to_string() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+impl Span {
+    fn to_otlp(&self, trace_id: &str) -> Value {
+        let status = match self.error {
+            Some(ref message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 1 }),
+        };
+        json!({
+            "traceId": trace_id,
+            "spanId": self.id,
+            "parentSpanId": self.parent.clone().unwrap_or_default(),
+            "name": self.name,
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end.unwrap_or_else(SystemTime::now)),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(k, v)| attribute(k, v))
+                .collect::<Vec<_>>(),
+            "status": status,
+        })
+    }
+}
+
+/// `--otlp-endpoint`: traces each backup run to an OpenTelemetry collector
+/// over OTLP/HTTP (JSON). Finished spans are sent every few seconds and at
+/// the end; on Ctrl-C whatever is still open is ended as interrupted and
+/// sent before exiting.
+pub fn init(http: &Client, endpoint: &str) -> Result<()> {
+    let base = url::Url::parse(endpoint)
+        .with_context(|| format!("Invalid --otlp-endpoint \"{endpoint}\""))?;
+    let url = if base.path().ends_with("/v1/traces") {
+        base.to_string()
+    } else {
+        format!("{}/v1/traces", base.as_str().trim_end_matches('/'))
+    };
+    let _ = TRACER.set(Tracer {
+        http: http.clone(),
+        url,
+        trace_id: random_hex(16),
+        state: Mutex::default(),
+    });
+
+    tokio::spawn(async {
+        let mut tick = tokio::time::interval(EXPORT_INTERVAL);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            export().await;
+        }
+    });
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("\nInterrupted.");
+            end_all("interrupted");
+            export().await;
+            std::process::exit(130);
+        }
+    });
+    Ok(())
+}
+
+fn tracer() -> Option<&'static Tracer> {
+    TRACER.get()
+}
+
+fn open(key: String, name: &str, parent: Option<String>, attributes: Vec<(&'static str, Value)>) {
+    let Some(t) = tracer() else {
+        return;
+    };
+    let mut state = t.state.lock().unwrap();
+    let span = Span {
+        id: random_hex(8),
+        parent,
+        name: name.to_string(),
+        start: SystemTime::now(),
+        end: None,
+        attributes,
+        error: None,
+    };
+    if key == "run" {
+        state.root = Some(span.id.clone());
+    }
+    // A span reopened under the same key (a retried stage) ends the old one.
+    if let Some(mut old) = state.open.insert(key, span) {
+        old.end = Some(SystemTime::now());
+        state.done.push(old);
+    }
+}
+
+fn close(key: &str, attributes: Vec<(&'static str, Value)>, error: Option<String>) {
+    let Some(t) = tracer() else {
+        return;
+    };
+    let mut state = t.state.lock().unwrap();
+    if let Some(mut span) = state.open.remove(key) {
+        span.end = Some(SystemTime::now());
+        span.attributes.extend(attributes);
+        span.error = error;
+        state.done.push(span);
+    }
+}
+
+/// Ends every open span with `why` as its error.
+fn end_all(why: &str) {
+    let Some(t) = tracer() else {
+        return;
+    };
+    let mut state = t.state.lock().unwrap();
+    let now = SystemTime::now();
+    let open: Vec<Span> = state.open.drain().map(|(_, span)| span).collect();
+    for mut span in open {
+        span.end = Some(now);
+        span.error = Some(why.to_string());
+        state.done.push(span);
+    }
+}
+
+/// Starts the run's root span.
+pub fn start_run() {
+    open("run".to_string(), "backup run", None, Vec::new());
+}
+
+/// Ends the run's root span, and with it anything left open, and sends
+/// what's left.
+pub async fn finish_run(error: Option<&anyhow::Error>) {
+    let Some(t) = tracer() else {
+        return;
+    };
+    close("run", Vec::new(), error.map(|e| format!("{e:#}")));
+    end_all("not finished");
+    t.state.lock().unwrap().root = None;
+    export().await;
+}
+
+/// Starts the span for one file's transfer, under the run.
+pub fn start_file(file_id: &str, name: &str, bytes: Option<u64>) {
+    let parent = tracer().and_then(|t| t.state.lock().unwrap().root.clone());
+    let mut attributes = vec![
+        ("drive.file_id", json!(file_id)),
+        ("file.name", json!(name)),
+    ];
+    if let Some(bytes) = bytes {
+        attributes.push(("file.bytes", json!(bytes)));
+    }
+    open(file_id.to_string(), "file", parent, attributes);
+}
+
+/// Starts a `download`, `upload`, `stream` or `delete` span under the file's.
+pub fn start_stage(file_id: &str, stage: &str) {
+    let parent = tracer().and_then(|t| {
+        let state = t.state.lock().unwrap();
+        state.open.get(file_id).map(|s| s.id.clone())
+    });
+    open(format!("{file_id}/{stage}"), stage, parent, Vec::new());
+}
+
+pub fn end_stage<T>(file_id: &str, stage: &str, result: &Result<T>) {
+    let error = result.as_ref().err().map(|e| format!("{e:#}"));
+    close(&format!("{file_id}/{stage}"), Vec::new(), error);
+}
+
+/// Ends the file's span with its `outcome` (`archived`, `duplicate`,
+/// `skipped` or `failed`), and any stage still open under it.
+pub fn end_file(file_id: &str, outcome: &'static str, error: Option<&anyhow::Error>) {
+    let Some(t) = tracer() else {
+        return;
+    };
+    let error = error.map(|e| format!("{e:#}"));
+    let stages: Vec<String> = t
+        .state
+        .lock()
+        .unwrap()
+        .open
+        .keys()
+        .filter(|k| {
+            k.strip_prefix(file_id)
+                .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .cloned()
+        .collect();
+    for stage in stages {
+        close(&stage, Vec::new(), error.clone());
+    }
+    close(file_id, vec![("outcome", json!(outcome))], error);
+}
+
+/// Sends the spans ended so far. A failed export is warned about once, and
+/// its spans dropped rather than piling up.
+async fn export() {
+    let Some(t) = tracer() else {
+        return;
+    };
+    let spans: Vec<Value> = {
+        let mut state = t.state.lock().unwrap();
+        state
+            .done
+            .drain(..)
+            .map(|s| s.to_otlp(&t.trace_id))
+            .collect()
+    };
+    if spans.is_empty() {
+        return;
+    }
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", &json!("google-photos-backup"))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "google-photos-backup", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    });
+    let sent = t
+        .http
+        .post(&t.url)
+        .json(&body)
+        .send_logged()
+        .await
+        .and_then(|r| r.error_for_status());
+    if let Err(e) = sent {
+        let mut state = t.state.lock().unwrap();
+        if !state.export_failed {
+            state.export_failed = true;
+            eprintln!("Warning: could not send traces to {}: {e}", t.url);
+        }
+    }
+}
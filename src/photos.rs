@@ -0,0 +1,328 @@
+use crate::auth::TokenHolder;
+use crate::aws;
+use crate::drive::{self, ByteCap, DriveFile};
+use crate::http_log::SendLogged;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use indicatif::ProgressBar;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::path::Path;
+
+const PHOTOS_API: &str = "https://photoslibrary.googleapis.com/v1";
+
+#[derive(Deserialize)]
+struct MediaItem {
+    id: String,
+    filename: String,
+    #[serde(rename = "mimeType")]
+    mime_type: String,
+    /// Only valid for about an hour after it's handed out.
+    #[serde(rename = "baseUrl")]
+    base_url: String,
+    #[serde(rename = "mediaMetadata")]
+    media_metadata: MediaMetadata,
+}
+
+#[derive(Deserialize)]
+struct MediaMetadata {
+    #[serde(rename = "creationTime")]
+    creation_time: Option<DateTime<Utc>>,
+}
+
+impl MediaItem {
+    /// The pipeline works on `DriveFile`s; a media item maps onto one with
+    /// no size or checksum (the Library API reports neither). Items are
+    /// grouped by the month they were taken, as camera filenames repeat.
+    fn into_file(self) -> DriveFile {
+        let folder_path = self
+            .media_metadata
+            .creation_time
+            .map(|t| t.format("%Y/%m").to_string())
+            .unwrap_or_default();
+        DriveFile {
+            id: self.id,
+            name: self.filename,
+            mime_type: self.mime_type,
+            size: None,
+            md5_checksum: None,
+            trashed: false,
+            modified_time: self.media_metadata.creation_time,
+            capabilities: None,
+            shortcut_details: None,
+            shortcut_id: None,
+            folder_path,
+            local_path: None,
+        }
+    }
+
+    /// Full-resolution download URL: `=d` keeps a photo's original bytes and
+    /// metadata, `=dv` fetches a video instead of its thumbnail.
+    fn download_url(&self) -> String {
+        if self.mime_type.starts_with("video/") {
+            format!("{}=dv", self.base_url)
+        } else {
+            format!("{}=d", self.base_url)
+        }
+    }
+}
+
+/// A Google Photos album, owned or shared with this account.
+#[derive(Deserialize)]
+pub struct Album {
+    pub id: String,
+    pub title: Option<String>,
+    /// Item count as a decimal string, as the API returns it.
+    #[serde(rename = "mediaItemsCount")]
+    pub media_items_count: Option<String>,
+    /// Set for albums found through `sharedAlbums` (joined, not owned).
+    #[serde(skip)]
+    pub shared: bool,
+}
+
+impl Album {
+    pub fn kind(&self) -> &'static str {
+        if self.shared {
+            "shared"
+        } else {
+            "owned"
+        }
+    }
+}
+
+/// Read-only access to the Google Photos library. Nothing is ever deleted:
+/// the Library API can't remove media items.
+pub struct PhotosClient<'a> {
+    http: &'a Client,
+    tokens: TokenHolder,
+    /// `--max-file-size`.
+    max_file_size: Option<u64>,
+    /// `--download-buffer`.
+    write_buffer: usize,
+}
+
+impl<'a> PhotosClient<'a> {
+    pub fn new(http: &'a Client, tokens: TokenHolder) -> Self {
+        Self {
+            http,
+            tokens,
+            max_file_size: None,
+            write_buffer: drive::DEFAULT_WRITE_BUFFER,
+        }
+    }
+
+    pub fn with_max_file_size(mut self, max: Option<u64>) -> Self {
+        self.max_file_size = max;
+        self
+    }
+
+    pub fn with_write_buffer(mut self, size: usize) -> Self {
+        self.write_buffer = size;
+        self
+    }
+
+    /// Refreshes the shared token if it's close to expiry.
+    pub async fn refresh(&self) -> Result<()> {
+        self.tokens.refresh(false, None).await
+    }
+
+    /// Sends an authenticated request, refreshing the token and retrying once
+    /// on a 401 like `DriveClient` does.
+    async fn send(&self, build: impl Fn(&Client) -> RequestBuilder) -> Result<Response> {
+        let sent_with = self.tokens.access_token();
+        let response = build(self.http)
+            .bearer_auth(&sent_with)
+            .send_logged()
+            .await?;
+        let response = if response.status() == StatusCode::UNAUTHORIZED {
+            self.tokens.refresh(true, Some(&sent_with)).await?;
+            build(self.http)
+                .bearer_auth(self.tokens.access_token())
+                .send_logged()
+                .await?
+        } else {
+            response
+        };
+        check(response).await
+    }
+
+    /// Every media item the API will list, as `DriveFile`s. Since 2025-03-31
+    /// that is only what this OAuth client created, not the whole library.
+    pub async fn list_media_items(&self) -> Result<Vec<DriveFile>> {
+        #[derive(Deserialize)]
+        struct Resp {
+            #[serde(rename = "mediaItems", default)]
+            media_items: Vec<MediaItem>,
+            #[serde(rename = "nextPageToken")]
+            next_page_token: Option<String>,
+        }
+
+        let mut all = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut params = vec![("pageSize", "100".to_string())];
+            if let Some(ref t) = page_token {
+                params.push(("pageToken", t.clone()));
+            }
+            let resp: Resp = self
+                .send(|http| http.get(format!("{PHOTOS_API}/mediaItems")).query(&params))
+                .await?
+                .json()
+                .await?;
+            all.extend(resp.media_items.into_iter().map(MediaItem::into_file));
+            page_token = resp.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        Ok(all)
+    }
+
+    /// Finds the album whose id or title is `name_or_id`. Only albums this
+    /// OAuth client created are listed; `sharedAlbums` now comes back empty
+    /// but is still asked in case Google restores it.
+    pub async fn find_album(&self, name_or_id: &str) -> Result<Album> {
+        let mut albums = self.list_albums("albums", false).await?;
+        albums.extend(self.list_albums("sharedAlbums", true).await?);
+
+        if let Some(album) = albums.iter().position(|a| a.id == name_or_id) {
+            return Ok(albums.swap_remove(album));
+        }
+        let mut matches: Vec<Album> = albums
+            .into_iter()
+            .filter(|a| a.title.as_deref() == Some(name_or_id))
+            .collect();
+        match matches.len() {
+            0 => anyhow::bail!("No album with id or title \"{name_or_id}\" found in Google Photos"),
+            1 => Ok(matches.remove(0)),
+            n => {
+                let ids: Vec<String> = matches
+                    .iter()
+                    .map(|a| format!("  {} ({})", a.id, a.kind()))
+                    .collect();
+                anyhow::bail!(
+                    "{n} albums titled \"{name_or_id}\":\n{}\nPass one of these ids to --album instead",
+                    ids.join("\n")
+                )
+            }
+        }
+    }
+
+    /// Pages through `albums` or `sharedAlbums` (the response lists them
+    /// under the same name as the endpoint).
+    async fn list_albums(&self, endpoint: &str, shared: bool) -> Result<Vec<Album>> {
+        let mut all = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut params = vec![("pageSize", "50".to_string())];
+            if let Some(ref t) = page_token {
+                params.push(("pageToken", t.clone()));
+            }
+            let mut resp: serde_json::Value = self
+                .send(|http| http.get(format!("{PHOTOS_API}/{endpoint}")).query(&params))
+                .await?
+                .json()
+                .await?;
+            let albums: Vec<Album> =
+                serde_json::from_value(resp[endpoint].take()).unwrap_or_default();
+            all.extend(albums.into_iter().map(|a| Album { shared, ..a }));
+            page_token = resp["nextPageToken"].as_str().map(String::from);
+            if page_token.is_none() {
+                break;
+            }
+        }
+        Ok(all)
+    }
+
+    /// Every media item in the album with id `album_id`, as `DriveFile`s.
+    pub async fn list_album_items(&self, album_id: &str) -> Result<Vec<DriveFile>> {
+        #[derive(Deserialize)]
+        struct Resp {
+            #[serde(rename = "mediaItems", default)]
+            media_items: Vec<MediaItem>,
+            #[serde(rename = "nextPageToken")]
+            next_page_token: Option<String>,
+        }
+
+        let mut all = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut body = serde_json::json!({ "albumId": album_id, "pageSize": 100 });
+            if let Some(ref t) = page_token {
+                body["pageToken"] = t.clone().into();
+            }
+            let resp: Resp = self
+                .send(|http| {
+                    http.post(format!("{PHOTOS_API}/mediaItems:search"))
+                        .json(&body)
+                })
+                .await?
+                .json()
+                .await?;
+            all.extend(resp.media_items.into_iter().map(MediaItem::into_file));
+            page_token = resp.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        Ok(all)
+    }
+
+    /// Downloads a media item at full resolution. Its `baseUrl` is fetched
+    /// again first, as the one from the listing may have expired by now.
+    pub async fn download(&self, file: &DriveFile, dest: &Path, bar: &ProgressBar) -> Result<()> {
+        let item: MediaItem = self
+            .send(|http| http.get(format!("{PHOTOS_API}/mediaItems/{}", file.id)))
+            .await?
+            .json()
+            .await?;
+
+        // baseUrl downloads are pre-authorized; no bearer token needed.
+        let response = check(self.http.get(item.download_url()).send_logged().await?).await?;
+        if let Some(len) = response.content_length() {
+            bar.set_length(len);
+        }
+        let cap = ByteCap::at_most(self.max_file_size);
+        if let Err(e) = drive::write_response(
+            response,
+            dest,
+            bar,
+            cap,
+            self.write_buffer,
+            &mut aws::Hasher::None,
+        )
+        .await
+        {
+            let _ = tokio::fs::remove_file(dest).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Turns an error status into an error carrying Google's message.
+async fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    #[derive(Deserialize)]
+    struct Body {
+        error: Detail,
+    }
+    #[derive(Deserialize)]
+    struct Detail {
+        message: String,
+    }
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Body>(&body)
+        .map(|b| b.error.message)
+        .ok()
+        .unwrap_or_else(|| {
+            status
+                .canonical_reason()
+                .unwrap_or("unknown error")
+                .to_string()
+        });
+    anyhow::bail!("Photos API error {status}: {message}")
+}
@@ -0,0 +1,150 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Set once at startup by `--plain`, or when stdout isn't a terminal.
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// Marks in status lines and what plain mode prints for them.
+const ASCII: &[(char, &str)] = &[
+    ('✓', "ok"),
+    ('✗', "FAILED"),
+    ('—', "-"),
+    ('–', "-"),
+    ('→', "->"),
+    ('·', "-"),
+    ('…', "..."),
+];
+
+pub fn set_plain() {
+    PLAIN.store(true, Ordering::Relaxed);
+}
+
+pub fn plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// Where progress bars draw: stderr, or nowhere in plain mode, where the
+/// status lines are all there is.
+pub fn draw_target() -> ProgressDrawTarget {
+    if plain() {
+        ProgressDrawTarget::hidden()
+    } else {
+        ProgressDrawTarget::stderr()
+    }
+}
+
+/// Prints `line` above `bar`, or in plain mode to stdout on its own, with
+/// the marks in `ASCII` spelled out. A hidden bar would drop it otherwise.
+pub fn println(bar: &ProgressBar, line: String) {
+    if !plain() {
+        bar.println(line);
+        return;
+    }
+    let mut out = String::with_capacity(line.len());
+    for c in line.chars() {
+        match ASCII.iter().find(|(mark, _)| *mark == c) {
+            Some((_, text)) => out.push_str(text),
+            None => out.push(c),
+        }
+    }
+    println!("{out}");
+}
+
+/// A fixed set of progress bars, laid out once and reused file after file.
+///
+/// Each concurrent worker borrows a slot for the duration of one transfer,
+/// so bars never churn in and out of the `MultiProgress` and the display
+/// keeps a stable order however many tasks are running.
+pub struct BarPool {
+    free: Mutex<Vec<ProgressBar>>,
+    all: Vec<ProgressBar>,
+    active: ProgressStyle,
+    idle: ProgressStyle,
+    /// Spinners need a steady tick while active; byte bars advance themselves.
+    spinner: bool,
+}
+
+impl BarPool {
+    /// Appends `slots` bars to `mp`, all starting out idle.
+    pub fn new(
+        mp: &MultiProgress,
+        slots: usize,
+        active: ProgressStyle,
+        idle: ProgressStyle,
+        spinner: bool,
+    ) -> Self {
+        let all: Vec<ProgressBar> = (0..slots)
+            .map(|_| {
+                let bar = mp.add(ProgressBar::new(0));
+                bar.set_style(idle.clone());
+                bar.set_message("idle");
+                bar
+            })
+            .collect();
+        Self {
+            free: Mutex::new(all.iter().rev().cloned().collect()),
+            all,
+            active,
+            idle,
+            spinner,
+        }
+    }
+
+    /// Borrows a free slot; it goes back to the pool (idle) when dropped.
+    ///
+    /// Callers must not run more workers than there are slots.
+    pub fn acquire(&self) -> BarSlot<'_> {
+        let bar = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("more concurrent workers than progress bar slots");
+        bar.reset();
+        bar.set_length(0);
+        bar.set_style(self.active.clone());
+        if self.spinner {
+            bar.enable_steady_tick(Duration::from_millis(80));
+        }
+        BarSlot { pool: self, bar }
+    }
+
+    /// Removes every slot from the display.
+    pub fn finish(&self) {
+        for bar in &self.all {
+            bar.finish_and_clear();
+        }
+    }
+
+    fn release(&self, bar: &ProgressBar) {
+        if self.spinner {
+            bar.disable_steady_tick();
+        }
+        bar.set_style(self.idle.clone());
+        bar.set_message("idle");
+        self.free.lock().unwrap().push(bar.clone());
+    }
+}
+
+/// A progress bar on loan from a `BarPool`.
+pub struct BarSlot<'a> {
+    pool: &'a BarPool,
+    bar: ProgressBar,
+}
+
+impl Deref for BarSlot<'_> {
+    type Target = ProgressBar;
+
+    fn deref(&self) -> &ProgressBar {
+        &self.bar
+    }
+}
+
+impl Drop for BarSlot<'_> {
+    fn drop(&mut self) {
+        self.pool.release(&self.bar);
+    }
+}
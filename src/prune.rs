@@ -0,0 +1,238 @@
+use crate::auth;
+use crate::aws::S3Uploader;
+use crate::config::{Config, Source};
+use crate::drive::{self, DriveClient};
+use crate::lock;
+use crate::manifest::{self, Manifest, ManifestEntry, Trashed};
+use crate::retry::retry;
+use anyhow::Result;
+use chrono::Utc;
+use indicatif::HumanBytes;
+use reqwest::Client;
+
+/// `prune <prefix>` — the second half of a `--no-delete` backup. Every file
+/// the prefix's manifest records as archived but still in Drive is checked
+/// again before it goes: each of its objects must be in S3 with the recorded
+/// size (and MD5, where the ETag is one), and the Drive copy must not have
+/// changed since. Only then is it deleted from Drive, or trashed with
+/// `--delete-after`, and the manifest updated. `--dry-run` only reports.
+pub async fn run(http: &Client, config: &Config, s3: &S3Uploader, prefix: &str) -> Result<()> {
+    if config.source == Source::Photos {
+        anyhow::bail!("Nothing to prune: Photos backups never delete from the library");
+    }
+    if config.keep_in_drive {
+        anyhow::bail!("prune deletes from Drive, so it can't be combined with --no-delete");
+    }
+    let prefix = prefix.trim_end_matches('/');
+    let manifest_path = manifest::path_for(&config.manifest_dir, prefix);
+    let Some(mut manifest) = Manifest::load(&manifest_path).await? else {
+        anyhow::bail!(
+            "No manifest at {}: prune only deletes what a manifest records as archived",
+            manifest_path.display()
+        );
+    };
+
+    // A file's entries (its own, and any revisions) go together: it's left
+    // alone if any of them says it's been deleted already.
+    let mut pending: Vec<String> = Vec::new();
+    for entry in &manifest.files {
+        if !pending.contains(&entry.file_id)
+            && !manifest.files.iter().any(|e| {
+                e.file_id == entry.file_id && (e.deleted_from_drive || e.trashed.is_some())
+            })
+        {
+            pending.push(entry.file_id.clone());
+        }
+    }
+    if pending.is_empty() {
+        println!(
+            "Every file in {} is already out of Drive.",
+            manifest_path.display()
+        );
+        return Ok(());
+    }
+    println!(
+        "{} file(s) in {} are archived but still in Drive.",
+        pending.len(),
+        manifest_path.display()
+    );
+
+    println!("Authenticating with Google Drive ...");
+    let token = auth::load_or_authenticate(http, config).await?;
+    let drive = DriveClient::new(http, auth::TokenHolder::new(http, config, token));
+
+    let lock = if config.dry_run {
+        None
+    } else {
+        Some(lock::acquire(s3, &lock::key(config, prefix), config.force).await?)
+    };
+    let trash = config.delete_after.is_some();
+    let (mut pruned, mut gone, mut skipped, mut bytes) = (0, 0, 0, 0u64);
+    for file_id in &pending {
+        let entries: Vec<&ManifestEntry> = manifest
+            .files
+            .iter()
+            .filter(|e| &e.file_id == file_id)
+            .collect();
+        // Revisions are keyed below their file's key (older manifests don't
+        // mark them).
+        let Some(head) = entries
+            .iter()
+            .find(|e| {
+                e.revision.is_none()
+                    && !entries
+                        .iter()
+                        .any(|o| e.key.starts_with(&format!("{}/rev-", o.key)))
+            })
+            .copied()
+        else {
+            continue;
+        };
+        let name = head.name.clone();
+        let delete_id = head.shortcut_id.clone().unwrap_or_else(|| file_id.clone());
+
+        let outcome = match check_s3(s3, &entries).await {
+            Err(why) => Outcome::Skipped(why),
+            Ok(()) => match retry(|| drive.get_file(file_id)).await {
+                Err(e) if drive::is_not_found(&e) => Outcome::Gone,
+                Err(e) => Outcome::Skipped(format!("cannot check it in Drive: {e:#}")),
+                Ok(current) if current.trashed => Outcome::Gone,
+                Ok(current) if changed(head, &current) => {
+                    Outcome::Skipped("changed in Drive since it was archived".to_string())
+                }
+                Ok(_) if config.dry_run => Outcome::Pruned,
+                // A shortcut's own permissions aren't the target's.
+                Ok(current) if head.shortcut_id.is_none() && !current.can_remove(trash) => {
+                    Outcome::Skipped(format!(
+                        "no {} permission in Drive",
+                        if trash { "trash" } else { "delete" }
+                    ))
+                }
+                Ok(_) => {
+                    let removed = if trash {
+                        retry(|| drive.trash(&delete_id)).await
+                    } else {
+                        retry(|| drive.delete(&delete_id)).await
+                    };
+                    match removed {
+                        Ok(()) => Outcome::Pruned,
+                        Err(e) if drive::is_not_found(&e) => Outcome::Gone,
+                        Err(e) => Outcome::Skipped(match drive::permission_denied(&e) {
+                            Some(why) => format!("cannot delete: {}", why.advice()),
+                            None => format!("Drive delete failed: {e:#}"),
+                        }),
+                    }
+                }
+            },
+        };
+
+        let size = head.size.unwrap_or(0);
+        match outcome {
+            Outcome::Pruned if config.dry_run => {
+                println!("  ✓ {name} — verified, would be {}", done_word(trash));
+                pruned += 1;
+                bytes += size;
+            }
+            Outcome::Pruned => {
+                println!("  ✓ {name} — verified and {}", done_word(trash));
+                pruned += 1;
+                bytes += size;
+            }
+            Outcome::Gone => {
+                println!("  ✓ {name} — already gone from Drive");
+                gone += 1;
+            }
+            Outcome::Skipped(why) => {
+                println!("  ✗ {name} — kept in Drive: {why}");
+                skipped += 1;
+                continue;
+            }
+        }
+        if config.dry_run {
+            continue;
+        }
+        let trashed = (trash && matches!(outcome, Outcome::Pruned)).then(|| Trashed {
+            id: delete_id.clone(),
+            at: Utc::now(),
+            purged_at: None,
+        });
+        for entry in manifest.files.iter_mut().filter(|e| &e.file_id == file_id) {
+            entry.deleted_from_drive = true;
+            entry.trashed = trashed.clone();
+        }
+        if let Err(e) = manifest.save(&manifest_path).await {
+            eprintln!("Warning: could not save manifest: {e:#}");
+        }
+    }
+    if let Some(ref lock) = lock {
+        lock::release(s3, lock).await;
+    }
+
+    let verb = if config.dry_run {
+        format!("would be {}", done_word(trash))
+    } else {
+        done_word(trash).to_string()
+    };
+    println!(
+        "\nPrune: {pruned} file(s) ({}) {verb}, {gone} already gone, {skipped} kept in Drive.",
+        HumanBytes(bytes)
+    );
+    if skipped > 0 {
+        anyhow::bail!("{skipped} file(s) under {prefix}/ could not be pruned");
+    }
+    Ok(())
+}
+
+enum Outcome {
+    Pruned,
+    /// Deleted (or trashed) by hand since the backup, or by Drive.
+    Gone,
+    Skipped(String),
+}
+
+fn done_word(trash: bool) -> &'static str {
+    if trash {
+        "moved to the Drive trash"
+    } else {
+        "deleted from Drive"
+    }
+}
+
+/// Checks that S3 still holds every object in `entries` as recorded (for a
+/// bundled file, the bundle), or says what's wrong.
+async fn check_s3(s3: &S3Uploader, entries: &[&ManifestEntry]) -> Result<(), String> {
+    for entry in entries {
+        let key = entry.object_key();
+        let object = match retry(|| s3.head(key)).await {
+            Ok(Some(object)) => object,
+            Ok(None) => return Err(format!("{key} is not in S3")),
+            Err(e) => return Err(format!("cannot check {key}: {e:#}")),
+        };
+        // A `--bundle` tar holds many files; its size and MD5 are nobody's.
+        if entry.bundle.is_some() {
+            continue;
+        }
+        if let Some(size) = entry.size.filter(|&s| s != object.size) {
+            return Err(format!(
+                "{key} is {} bytes in S3, expected {size}",
+                object.size
+            ));
+        }
+        // As in `verify`: only a single-part upload's ETag is its MD5.
+        if let (Some(md5), Some(etag)) = (&entry.md5, &object.etag) {
+            if !etag.contains('-') && !etag.eq_ignore_ascii_case(md5) {
+                return Err(format!("{key} has MD5 {etag} in S3, expected {md5}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the Drive file's content differs from what was archived, going
+/// by MD5 where both have one and by size otherwise.
+fn changed(archived: &ManifestEntry, current: &drive::DriveFile) -> bool {
+    match (&archived.md5, &current.md5_checksum) {
+        (Some(a), Some(c)) => !a.eq_ignore_ascii_case(c),
+        _ => archived.size.is_some() && archived.size != current.size_bytes(),
+    }
+}
@@ -0,0 +1,185 @@
+use crate::auth;
+use crate::aws::{Retrieval, S3Uploader};
+use crate::config::Config;
+use crate::drive::DriveClient;
+use crate::lock;
+use crate::manifest::{self, Manifest};
+use crate::marker;
+use crate::retry::retry;
+use anyhow::{Context, Result};
+use aws_sdk_s3::types::Tier;
+use reqwest::Client;
+use std::collections::{HashMap, HashSet};
+
+/// How long a copy retrieved from Glacier / Deep Archive stays readable.
+const RETRIEVAL_DAYS: i32 = 7;
+
+/// `restore <prefix> <drive-folder-id>` — copies a backup from S3 back into
+/// the Drive folder `folder_id`, recreating subfolders from the keys.
+/// Archived objects have to be retrieved first, which takes hours, so a run
+/// requests retrieval for those and restores whatever is readable; running
+/// it again later picks up the rest. Files already in Drive are skipped.
+pub async fn run(
+    http: &Client,
+    config: &Config,
+    s3: &S3Uploader,
+    prefix: &str,
+    folder_id: &str,
+) -> Result<()> {
+    let prefix = prefix.trim_end_matches('/');
+    let tier = match config.restore_tier.as_str() {
+        "Bulk" => Tier::Bulk,
+        "Standard" => Tier::Standard,
+        other => anyhow::bail!("Invalid S3_RESTORE_TIER \"{other}\" (expected Bulk or Standard)"),
+    };
+
+    // Keys hold sanitized names; the manifest has the originals.
+    let manifest_path = manifest::path_for(&config.manifest_dir, prefix);
+    let entries = Manifest::load(&manifest_path)
+        .await?
+        .map(|m| m.files)
+        .unwrap_or_default();
+    let revisions: HashSet<&str> = entries
+        .iter()
+        .filter(|e| e.revision.is_some())
+        .map(|e| e.key.as_str())
+        .collect();
+    let names: HashMap<&str, &str> = entries
+        .iter()
+        .map(|e| (e.key.as_str(), e.name.as_str()))
+        .collect();
+
+    println!("Listing objects under {prefix}/ ...");
+    let lock_key = format!("{prefix}/{}", lock::LOCK_NAME);
+    let markers: Vec<String> = marker::names(config)
+        .iter()
+        .map(|m| format!("{prefix}/{m}"))
+        .collect();
+    let objects: Vec<_> = s3
+        .list_objects(&format!("{prefix}/"))
+        .await?
+        .into_iter()
+        .filter(|o| o.key != lock_key && !markers.contains(&o.key))
+        .collect();
+    println!("  {} object(s).", objects.len());
+
+    // `--include-revisions` copies sit below their file's key. Only the
+    // current content goes back to Drive, not a folder per file holding them.
+    let keys: HashSet<&str> = objects.iter().map(|o| o.key.as_str()).collect();
+    let (revision_objects, objects): (Vec<_>, Vec<_>) = objects.iter().partition(|o| {
+        revisions.contains(o.key.as_str())
+            || o.key
+                .rsplit_once("/rev-")
+                .is_some_and(|(file, id)| !id.contains('/') && keys.contains(file))
+    });
+    if !revision_objects.is_empty() {
+        println!(
+            "  Skipping {} earlier revision(s) archived with --include-revisions.",
+            revision_objects.len()
+        );
+    }
+
+    println!("Authenticating with Google Drive ...");
+    let token = auth::load_or_authenticate(http, config).await?;
+    let drive = DriveClient::new(http, auth::TokenHolder::new(http, config, token));
+
+    let tmp_dir = tempfile::tempdir()?;
+    let mut folders: HashMap<String, String> = HashMap::new();
+    let (mut restored, mut present, mut requested, mut pending, mut failed) = (0, 0, 0, 0, 0);
+
+    for object in &objects {
+        let mut segments: Vec<&str> = object.key[prefix.len() + 1..].split('/').collect();
+        if config.prefix_entropy && segments.len() > 1 {
+            segments.remove(0);
+        }
+        let Some(last) = segments.pop() else {
+            continue;
+        };
+        let name = names.get(object.key.as_str()).copied().unwrap_or(last);
+        let path = segments.join("/");
+
+        let result = async {
+            let (state, content_type) = retry(|| s3.retrieval_state(&object.key)).await?;
+            match state {
+                Retrieval::Archived => {
+                    retry(|| s3.request_retrieval(&object.key, RETRIEVAL_DAYS, tier.clone()))
+                        .await?;
+                    requested += 1;
+                }
+                Retrieval::InProgress => pending += 1,
+                Retrieval::Ready => {
+                    let parent = folder_for(&drive, &mut folders, folder_id, &segments).await?;
+                    if drive.find_child(&parent, name, false).await?.is_some() {
+                        present += 1;
+                        return Ok(());
+                    }
+                    let local = tmp_dir.path().join(format!("{restored}-restore"));
+                    retry(|| s3.download(&object.key, &local)).await?;
+                    let uploaded = retry(|| {
+                        drive.upload_resumable(&parent, name, &local, content_type.as_deref())
+                    })
+                    .await;
+                    let _ = tokio::fs::remove_file(&local).await;
+                    uploaded?;
+                    println!(
+                        "  ✓ {}",
+                        if path.is_empty() {
+                            name.to_string()
+                        } else {
+                            format!("{path}/{name}")
+                        }
+                    );
+                    restored += 1;
+                }
+            }
+            anyhow::Ok(())
+        };
+        if let Err(e) = result.await {
+            eprintln!("  ✗ {} — {e:#}", object.key);
+            failed += 1;
+        }
+    }
+
+    println!("\nRestore: {restored} restored, {present} already in Drive, {failed} failed.");
+    if requested + pending > 0 {
+        println!(
+            "{requested} object(s) asked to be retrieved from the archive, {pending} still being \
+             retrieved. Deep Archive retrievals take up to 48 hours ({} tier); run restore again \
+             then to finish.",
+            config.restore_tier
+        );
+    }
+    if failed > 0 {
+        anyhow::bail!("Restore failed for {failed} object(s)");
+    }
+    Ok(())
+}
+
+/// The Drive folder for the subfolder `segments` below `root`, found or
+/// created along the way and remembered in `folders`.
+async fn folder_for(
+    drive: &DriveClient<'_>,
+    folders: &mut HashMap<String, String>,
+    root: &str,
+    segments: &[&str],
+) -> Result<String> {
+    let mut parent = root.to_string();
+    for depth in 1..=segments.len() {
+        let path = segments[..depth].join("/");
+        if let Some(id) = folders.get(&path) {
+            parent = id.clone();
+            continue;
+        }
+        let name = segments[depth - 1];
+        let id = match drive.find_child(&parent, name, true).await? {
+            Some(id) => id,
+            None => drive
+                .create_folder(&parent, name)
+                .await
+                .with_context(|| format!("Cannot create folder {path}"))?,
+        };
+        folders.insert(path, id.clone());
+        parent = id;
+    }
+    Ok(parent)
+}
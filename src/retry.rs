@@ -0,0 +1,159 @@
+use anyhow::Result;
+use std::cell::Cell;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const MAX_RETRIES: u32 = 3;
+
+/// S3 answering 503 SlowDown means we're over its request rate for the
+/// prefix, so keep trying for longer, starting from a longer wait.
+pub const SLOW_DOWN_RETRIES: u32 = 6;
+const SLOW_DOWN_DELAY: Duration = Duration::from_secs(15);
+pub const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// How `retry` handles S3 `SlowDown`: up to `attempts` tries in all, each
+/// wait a random time up to a ceiling that starts at 15 s and doubles with
+/// every `SlowDown`, never past `max_delay` ("full jitter"), so uploads
+/// throttled together don't all come back at once.
+#[derive(Clone, Copy)]
+pub struct SlowDownPolicy {
+    pub attempts: u32,
+    pub max_delay: Duration,
+}
+
+/// Set once at startup from `--slowdown-retries` / `--slowdown-max-delay`.
+static SLOW_DOWN_POLICY: OnceLock<SlowDownPolicy> = OnceLock::new();
+
+/// Every `SlowDown` answer `retry` has seen in this process.
+static SLOW_DOWNS: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_slow_down_policy(policy: SlowDownPolicy) {
+    let _ = SLOW_DOWN_POLICY.set(policy);
+}
+
+fn slow_down_policy() -> SlowDownPolicy {
+    SLOW_DOWN_POLICY.get().copied().unwrap_or(SlowDownPolicy {
+        attempts: SLOW_DOWN_RETRIES,
+        max_delay: MAX_DELAY,
+    })
+}
+
+/// How many times S3 has answered `SlowDown` so far.
+pub fn slow_downs() -> u64 {
+    SLOW_DOWNS.load(Ordering::Relaxed)
+}
+
+/// True if `e` is S3 throttling requests (`SlowDown`). The SDK only puts the
+/// error code in the debug form.
+fn is_slow_down(e: &anyhow::Error) -> bool {
+    format!("{e:?}").contains("SlowDown")
+}
+
+/// Throttled attempts seen by `retry` inside `count_throttles`.
+#[derive(Clone, Copy, Default)]
+pub struct Throttles {
+    /// By Drive or S3.
+    pub any: u32,
+    /// Of those, S3 `SlowDown`s.
+    pub slow_down: u32,
+}
+
+tokio::task_local! {
+    static THROTTLED: Cell<Throttles>;
+}
+
+/// True if Drive or S3 answered `e` by asking us to slow down.
+pub fn is_throttled(e: &anyhow::Error) -> bool {
+    crate::drive::is_throttled(e)
+        || is_slow_down(e)
+        || format!("{e:?}").contains("ServiceUnavailable")
+}
+
+/// Runs `work`, also returning how many of the `retry` attempts inside it
+/// were throttled, including ones a later attempt recovered from.
+pub async fn count_throttles<T>(work: impl Future<Output = T>) -> (T, Throttles) {
+    THROTTLED
+        .scope(Cell::new(Throttles::default()), async {
+            let out = work.await;
+            (out, THROTTLED.with(Cell::get))
+        })
+        .await
+}
+
+/// True if `e` looks like the network dropping out (no connection, or a
+/// timeout) rather than an answer from Google or S3.
+pub fn is_network_error(e: &anyhow::Error) -> bool {
+    let reqwest_network = e.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|r| r.is_connect() || r.is_timeout())
+    });
+    // The S3 SDK's error types are generic per operation; its variant names
+    // show in the debug form.
+    let debug = format!("{e:?}");
+    reqwest_network || debug.contains("DispatchFailure") || debug.contains("TimeoutError")
+}
+
+pub async fn retry<F, Fut, T>(op: F) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut delay = Duration::from_secs(5);
+    let mut max_attempts = MAX_RETRIES;
+    // The jitter ceiling for the next wait after a SlowDown.
+    let mut slow_down_ceiling = SLOW_DOWN_DELAY;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match op().await {
+            Ok(v) => return Ok(v),
+            // Permissions don't change between attempts.
+            Err(e) if crate::drive::permission_denied(&e).is_some() => return Err(e),
+            // Nor would another attempt at a runaway download stop sooner.
+            Err(e) if crate::drive::is_too_large(&e) => return Err(e),
+            // Nor would S3 take an upload `--no-overwrite` refused.
+            Err(e) if crate::aws::is_already_exists(&e) => return Err(e),
+            Err(e) => {
+                let slow_down = is_slow_down(&e);
+                if is_throttled(&e) {
+                    let _ = THROTTLED.try_with(|t| {
+                        let mut seen = t.get();
+                        seen.any += 1;
+                        seen.slow_down += u32::from(slow_down);
+                        t.set(seen);
+                    });
+                }
+                let policy = slow_down_policy();
+                if slow_down {
+                    SLOW_DOWNS.fetch_add(1, Ordering::Relaxed);
+                    max_attempts = policy.attempts;
+                }
+                if attempt >= max_attempts {
+                    let msg = e.to_string();
+                    if msg.contains("RequestTimeTooSkewed") {
+                        return Err(e.context(
+                            "AWS clock skew — sync your system clock with: sudo sntp -sS time.apple.com",
+                        ));
+                    }
+                    return Err(e);
+                }
+                let wait = if slow_down {
+                    let ceiling = slow_down_ceiling.min(policy.max_delay);
+                    slow_down_ceiling = ceiling * 2;
+                    Duration::from_millis(rand::random_range(0..=ceiling.as_millis() as u64))
+                } else {
+                    delay
+                };
+                eprintln!(
+                    "  attempt {attempt}/{max_attempts} failed: {e:#} — retrying in {}s ...",
+                    wait.as_secs()
+                );
+                tokio::time::sleep(wait).await;
+                delay = (delay * 2).min(MAX_DELAY);
+            }
+        }
+    }
+}
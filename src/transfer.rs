@@ -0,0 +1,1616 @@
+use crate::aimd::Aimd;
+use crate::aws::{self, S3Uploader};
+use crate::chaos;
+use crate::config::Config;
+use crate::drive::{self, DriveClient, DriveFile};
+use crate::events::{Event, Events};
+use crate::failures::{self, Failure, Stage};
+use crate::fsutil;
+use crate::journal::{Journal, Milestone, Status};
+use crate::lock;
+use crate::manifest::{self, Manifest, ManifestEntry, Trashed};
+use crate::marker;
+use crate::otel;
+use crate::photos::PhotosClient;
+use crate::progress::{self, BarPool};
+use crate::retry::{self, retry};
+use anyhow::Result;
+use bytes::Bytes;
+use chrono::Utc;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+
+/// Replaces path separators and null bytes with '_' so a Drive filename can
+/// be safely used as a local path component or S3 key segment.
+pub fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if matches!(c, '/' | '\\' | '\0') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Where `file` goes under the backup prefix: its sanitized name, below its
+/// subfolder path when it was found by a recursive listing. With `--rename`
+/// the name is rewritten after sanitizing, and sanitized again.
+pub fn object_name(config: &Config, file: &DriveFile) -> String {
+    let name = match config.rename {
+        Some(ref rename) => sanitize_filename(&rename.apply(&sanitize_filename(&file.name))),
+        None => file.name.clone(),
+    };
+    // A top-level file must not take the run lock's or a marker's key.
+    if file.folder_path.is_empty()
+        && (name == lock::LOCK_NAME || marker::names(config).contains(&name.as_str()))
+    {
+        return format!("_{}", sanitize_filename(&name));
+    }
+    file.folder_path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .chain(std::iter::once(name.as_str()))
+        .map(|segment| match segment {
+            // Kept-local copies are written under this path too.
+            "." | ".." => "_".to_string(),
+            // An empty name would leave a trailing slash.
+            "" => "_".to_string(),
+            s => sanitize_filename(s),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The S3 key for `file` in the backup under `prefix`. With
+/// `--prefix-entropy` a two-hex-digit hash of the name follows the prefix,
+/// spreading a big run's PUTs across S3 partitions instead of one. A key
+/// over S3's length limit has its file name shortened to fit.
+pub fn object_key(config: &Config, prefix: &str, file: &DriveFile) -> String {
+    fit_key(full_key(config, prefix, file), aws::MAX_KEY_BYTES)
+}
+
+/// Whether `file`'s name had to be shortened for its S3 key.
+fn key_shortened(config: &Config, prefix: &str, file: &DriveFile) -> bool {
+    full_key(config, prefix, file).len() > aws::MAX_KEY_BYTES
+}
+
+/// The metadata stored with `file`'s object: its name as it is in Drive,
+/// URL-encoded, when the key doesn't carry it whole.
+fn key_metadata(config: &Config, prefix: &str, file: &DriveFile) -> Vec<(&'static str, String)> {
+    if !key_shortened(config, prefix, file) {
+        return Vec::new();
+    }
+    let name: String = url::form_urlencoded::byte_serialize(file.name.as_bytes()).collect();
+    // S3 allows 2 KB of user metadata in all.
+    if name.len() > ORIGINAL_NAME_MAX {
+        return Vec::new();
+    }
+    vec![(ORIGINAL_NAME, name)]
+}
+
+/// The object metadata holding a shortened file's original name.
+const ORIGINAL_NAME: &str = "original-name";
+const ORIGINAL_NAME_MAX: usize = 2000;
+
+/// An extension longer than this is taken as part of the name.
+const MAX_EXTENSION_BYTES: usize = 16;
+
+/// `key` cut down to `limit` bytes (S3's limit, or less to leave room for a
+/// suffix) if it's over it. The end of the file name's
+/// stem is replaced by `~` and a hash of the whole key, so names that only
+/// differ there still get different keys, and the extension is kept. A key
+/// whose folders alone are too long is left for `validate_key` to refuse.
+fn fit_key(key: String, limit: usize) -> String {
+    if key.len() <= limit {
+        return key;
+    }
+    let (dir, name) = key.rsplit_once('/').unwrap_or(("", &key));
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= MAX_EXTENSION_BYTES => name.split_at(i),
+        _ => (name, ""),
+    };
+    let hash = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+    let tag: String = std::iter::once("~".to_string())
+        .chain(hash.as_ref()[..4].iter().map(|b| format!("{b:02x}")))
+        .collect();
+    let Some(room) = limit
+        .checked_sub(dir.len() + 1 + tag.len() + extension.len())
+        .filter(|&room| room > 0)
+    else {
+        return key;
+    };
+    let mut cut = room.min(stem.len());
+    while !stem.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    format!("{dir}/{}{tag}{extension}", &stem[..cut])
+}
+
+/// Where an earlier revision of the file at `key` is archived:
+/// `<key>/rev-<id>`, with `key` shortened first if that would be too long.
+fn revision_key(key: &str, id: &str) -> String {
+    let suffix = format!("/rev-{id}");
+    let base = fit_key(key.to_string(), aws::MAX_KEY_BYTES - suffix.len());
+    format!("{base}{suffix}")
+}
+
+fn full_key(config: &Config, prefix: &str, file: &DriveFile) -> String {
+    let name = object_name(config, file);
+    if config.prefix_entropy {
+        let hash = ring::digest::digest(&ring::digest::SHA256, name.as_bytes());
+        format!("{prefix}/{:02x}/{name}", hash.as_ref()[0])
+    } else {
+        format!("{prefix}/{name}")
+    }
+}
+
+pub fn download_style() -> Result<ProgressStyle> {
+    Ok(ProgressStyle::with_template(
+        "  ↓  {bar:30.cyan/white} {bytes}/{total_bytes} at {bytes_per_sec} eta {eta}",
+    )?
+    .progress_chars("█▉▊▋▌▍▎▏ "))
+}
+
+/// Drive mimeTypes that say nothing about the content.
+const GENERIC_MIMETYPES: &[&str] = &[
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/unknown",
+];
+
+/// How much of a file to read when sniffing its type from magic bytes.
+const SNIFF_BYTES: usize = 8192;
+
+/// Warn when the S3 credentials have less than this left beyond a file's
+/// expected transfer time.
+const CREDENTIALS_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// How often `--wait-for-network` checks whether Google is reachable again.
+const NETWORK_PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Counters for the end-of-run summary.
+#[derive(Default)]
+pub struct RunStats {
+    /// Files admitted into the pipeline.
+    pub started: usize,
+    pub uploaded: usize,
+    pub failed: usize,
+    /// Archived files deleted (or trashed) from Drive, and the space that
+    /// frees there.
+    pub removed: usize,
+    pub bytes_removed: u64,
+    pub not_deleted: usize,
+    /// Of `not_deleted`, the files Drive refused to delete for lack of
+    /// permission, and whether any of those were down to the token's scope.
+    pub delete_denied: usize,
+    pub delete_denied_scope: bool,
+    pub bytes_uploaded: u64,
+    /// Earlier revisions archived by `--include-revisions`, and their bytes
+    /// (not counted in `bytes_uploaded`).
+    pub revisions: usize,
+    pub bytes_revisions: u64,
+    /// Files skipped by `--dedup` because their content was already uploaded.
+    pub deduplicated: usize,
+    pub bytes_deduplicated: u64,
+    /// Files left in Drive by `--no-overwrite` because S3 already held
+    /// something else at their key.
+    pub not_overwritten: usize,
+    /// Files left in Drive by `--stable-check` because they changed between
+    /// the listing and their download.
+    pub unstable: usize,
+    /// `SlowDown` answers from S3, each retried after a jittered wait.
+    pub slow_downs: u64,
+    /// Files left untouched because `--max-files` / `--max-bytes` was reached.
+    pub not_started: usize,
+    /// Set when `--max-runtime` ran out before every file was started.
+    pub out_of_time: bool,
+    /// Wall-clock time spent transferring.
+    pub elapsed: Duration,
+    /// The uploaded files with the highest and lowest transfer rate.
+    pub fastest: Option<FileTiming>,
+    pub slowest: Option<FileTiming>,
+}
+
+impl RunStats {
+    /// Counts a file removed from Drive. A resolved shortcut frees nothing:
+    /// only the shortcut goes.
+    pub fn note_removed(&mut self, file: &DriveFile) {
+        self.removed += 1;
+        if file.shortcut_id.is_none() {
+            self.bytes_removed += file.size_bytes().unwrap_or(0);
+        }
+    }
+
+    /// Counts a file archived but left in Drive because the delete failed,
+    /// `denied` if Drive refused it.
+    pub fn note_not_deleted(&mut self, denied: Option<drive::Denied>) {
+        self.not_deleted += 1;
+        if let Some(why) = denied {
+            self.delete_denied += 1;
+            self.delete_denied_scope |= why == drive::Denied::Scope;
+        }
+    }
+
+    /// Counts one transferred file towards the fastest and slowest. Files
+    /// without a size, or already local in test mode, have no rate.
+    pub fn time(&mut self, file: &DriveFile, took: Duration) {
+        let Some(bytes) = file.size_bytes().filter(|&b| b > 0) else {
+            return;
+        };
+        if took.is_zero() {
+            return;
+        }
+        let timing = FileTiming {
+            name: file.name.clone(),
+            bytes,
+            seconds: took.as_secs_f64(),
+        };
+        if self
+            .fastest
+            .as_ref()
+            .is_none_or(|f| timing.rate() > f.rate())
+        {
+            self.fastest = Some(timing.clone());
+        }
+        if self
+            .slowest
+            .as_ref()
+            .is_none_or(|s| timing.rate() < s.rate())
+        {
+            self.slowest = Some(timing);
+        }
+    }
+
+    /// Bytes per second over the whole run.
+    pub fn average_rate(&self) -> Option<f64> {
+        (!self.elapsed.is_zero() && self.bytes_uploaded > 0)
+            .then(|| self.bytes_uploaded as f64 / self.elapsed.as_secs_f64())
+    }
+
+    /// Bytes per second of the fastest file.
+    pub fn peak_rate(&self) -> Option<f64> {
+        self.fastest.as_ref().map(FileTiming::rate)
+    }
+}
+
+/// How long one file took, for the throughput summary.
+#[derive(Clone, Serialize)]
+pub struct FileTiming {
+    pub name: String,
+    pub bytes: u64,
+    /// Downloading plus uploading; waiting between the two isn't counted.
+    pub seconds: f64,
+}
+
+impl FileTiming {
+    /// Bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.bytes as f64 / self.seconds
+    }
+}
+
+/// Files and bytes admitted into the pipeline, checked against the run caps.
+/// A failed file gives its share back.
+#[derive(Default)]
+struct Budget {
+    started: usize,
+    files: usize,
+    bytes: u64,
+    out_of_time: bool,
+}
+
+/// Everything the pipeline needs from setup in `main`.
+pub struct Context<'a> {
+    pub config: &'a Config,
+    pub s3: &'a S3Uploader,
+    /// `None` in test mode, where files are already local, and with the
+    /// Photos source; either way nothing is deleted.
+    pub drive: Option<&'a DriveClient<'a>>,
+    /// Set with `--source=photos`: files are media items downloaded from here.
+    pub photos: Option<&'a PhotosClient<'a>>,
+    pub tmp_dir: &'a Path,
+    pub bucket: &'a str,
+    pub date_prefix: &'a str,
+    /// When the listing finished; `None` for a streamed listing, where a
+    /// file counts as listed once the pipeline takes it.
+    pub listed_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// A file that has been downloaded and is waiting for the upload stage.
+/// `F` is `&DriveFile` for a listing collected up front, or an owned
+/// `DriveFile` when the listing is streamed.
+struct Downloaded<F> {
+    index: usize,
+    file: F,
+    staged: Staged,
+    /// Time spent downloading.
+    took: Duration,
+}
+
+/// Where a downloaded file's content waits for the upload.
+enum Staged {
+    /// A file on disk, with its upload checksum when that was computed as
+    /// it was downloaded.
+    Disk(PathBuf, Option<String>),
+    /// With `--temp-in-memory`, the whole of a small file. At most one per
+    /// download, queue slot and upload, so memory stays bounded by the
+    /// concurrency settings.
+    Memory(Bytes),
+}
+
+impl Staged {
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Staged::Disk(path, _) => Some(path),
+            Staged::Memory(_) => None,
+        }
+    }
+}
+
+struct Pipeline<'a> {
+    ctx: &'a Context<'a>,
+    stats: Mutex<RunStats>,
+    failures: Mutex<Vec<Failure>>,
+    /// Drive MD5 → key of the object this run uploaded it to, for `--dedup`.
+    uploaded_md5: Mutex<HashMap<String, String>>,
+    /// With `--rename`, S3 key → id and MD5 of the file this run gave it.
+    keys: Mutex<HashMap<String, (String, Option<String>)>>,
+    budget: Mutex<Budget>,
+    manifest: tokio::sync::Mutex<Manifest>,
+    manifest_path: PathBuf,
+    /// Unknown while the listing is still being streamed.
+    total: Option<usize>,
+    /// Held while probing for the network with `--wait-for-network`, so
+    /// only one task polls and the rest wait for it.
+    network: tokio::sync::Mutex<()>,
+    /// `--progress-socket` output.
+    events: Events,
+    /// `--transfer-log` output.
+    journal: Journal,
+    /// With `--adaptive-concurrency`, the self-tuning limits per stage.
+    download_gate: Option<Aimd>,
+    upload_gate: Option<Aimd>,
+    overall: ProgressBar,
+    /// Bytes earlier runs stored under the prefix, shown on the overall bar.
+    bytes_before: u64,
+    download_bars: BarPool,
+    upload_bars: BarPool,
+    /// When the transfers began, for the observed throughput.
+    started: Instant,
+    /// Set once the S3 credentials have been warned about.
+    credentials_warned: AtomicBool,
+}
+
+/// Backs up `files` through a two-stage pipeline. Up to
+/// `config.download_concurrency` downloads run at once and feed a bounded
+/// queue drained by up to `config.upload_concurrency` uploads, so a slow side
+/// never lets temp files pile up on disk without limit.
+pub async fn run(ctx: &Context<'_>, files: &[DriveFile]) -> Result<RunStats> {
+    run_stream(ctx, stream::iter(files), Some(files.len())).await
+}
+
+/// Like `run`, for files still arriving from a streamed listing: the first
+/// download starts as soon as the first file is listed. `total` is the
+/// number of files, when known up front.
+pub async fn run_stream<F>(
+    ctx: &Context<'_>,
+    files: impl Stream<Item = F>,
+    total: Option<usize>,
+) -> Result<RunStats>
+where
+    F: Borrow<DriveFile>,
+{
+    let mp = MultiProgress::with_draw_target(progress::draw_target());
+
+    // Earlier runs on the same day share the prefix, so extend their manifest.
+    let manifest_path = manifest::path_for(&ctx.config.manifest_dir, ctx.date_prefix);
+    let manifest = Manifest::load(&manifest_path)
+        .await?
+        .unwrap_or_else(|| Manifest::new(ctx.date_prefix));
+
+    // What they finished isn't listed again, but counts towards the day's
+    // progress all the same.
+    let (files_before, bytes_before) = manifest.completed();
+    let overall = mp.add(
+        ProgressBar::new(total.unwrap_or(0) as u64 + files_before).with_position(files_before),
+    );
+    // The ETA follows this run's pace only; with a streamed listing the
+    // total keeps growing, so there's none.
+    let template = if total.is_some() {
+        "[{pos}/{len}] {bar:40.green/white} {prefix} · {eta} left  {msg}"
+    } else {
+        "[{pos}/{len}] {bar:40.green/white} {prefix}  {msg}"
+    };
+    overall.set_style(ProgressStyle::with_template(template)?.progress_chars("█▉▊▋▌▍▎▏ "));
+    overall.reset_eta();
+    overall.set_prefix(HumanBytes(bytes_before).to_string());
+    overall.set_message("starting ...");
+
+    // One persistent slot per worker, below the overall bar: downloads first,
+    // then uploads (a spinner, as the S3 SDK doesn't expose byte progress).
+    let idle = ProgressStyle::with_template("  ·  {msg:.dim}")?;
+    let download_bars = BarPool::new(
+        &mp,
+        ctx.config.download_concurrency,
+        download_style()?,
+        idle.clone(),
+        false,
+    );
+    let upload_bars = BarPool::new(
+        &mp,
+        ctx.config.upload_concurrency,
+        ProgressStyle::with_template("  {spinner:.yellow}  {msg}")?.tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ "),
+        idle,
+        true,
+    );
+
+    let events = match ctx.config.progress_socket {
+        Some(ref path) => Events::open(path)?,
+        None => Events::default(),
+    };
+
+    let journal = match ctx.config.transfer_log {
+        Some(ref path) => Journal::open(path, ctx.date_prefix)?,
+        None => Journal::default(),
+    };
+
+    let pipeline = Pipeline {
+        ctx,
+        stats: Mutex::new(RunStats::default()),
+        failures: Mutex::new(Vec::new()),
+        uploaded_md5: Mutex::new(HashMap::new()),
+        keys: Mutex::new(HashMap::new()),
+        budget: Mutex::new(Budget::default()),
+        manifest: tokio::sync::Mutex::new(manifest),
+        manifest_path,
+        total,
+        network: tokio::sync::Mutex::new(()),
+        events,
+        journal,
+        download_gate: config_gate(ctx.config, ctx.config.download_concurrency),
+        upload_gate: Some(upload_gate(ctx.config)),
+        overall,
+        bytes_before,
+        download_bars,
+        upload_bars,
+        started: Instant::now(),
+        credentials_warned: AtomicBool::new(false),
+    };
+
+    // Downloaded files waiting for an upload slot; a download that finds it
+    // full waits, so no more than this many sit on disk between the stages.
+    // By default sized to the upload stage, so every upload worker has its
+    // next file ready.
+    let (tx, rx) = mpsc::channel::<Downloaded<F>>(ctx.config.upload_queue());
+
+    let p = &pipeline;
+    let download_stage = async move {
+        files
+            .enumerate()
+            .take_while(|(_, file)| future::ready(p.admit(file.borrow())))
+            .for_each_concurrent(ctx.config.download_concurrency, |(index, file)| {
+                let tx = tx.clone();
+                async move {
+                    let downloaded = p.gated(
+                        &p.download_gate,
+                        "Downloads",
+                        p.download(index, file.borrow()),
+                    );
+                    if let Some((staged, took)) = downloaded.await {
+                        // The receiver only goes away once uploads are done.
+                        let _ = tx
+                            .send(Downloaded {
+                                index,
+                                file,
+                                staged,
+                                took,
+                            })
+                            .await;
+                    }
+                }
+            })
+            .await;
+        // Dropping the last sender here lets the upload stage finish.
+    };
+
+    let upload_stage = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|d| (d, rx)) })
+        .for_each_concurrent(ctx.config.upload_concurrency, |d| {
+            p.gated(&p.upload_gate, "Uploads", p.upload(d))
+        });
+
+    let slow_downs_before = retry::slow_downs();
+    tokio::join!(download_stage, upload_stage);
+    let elapsed = pipeline.started.elapsed();
+
+    pipeline.download_bars.finish();
+    pipeline.upload_bars.finish();
+    pipeline.overall.finish_and_clear();
+
+    // Rewritten every run: stale rows from an earlier run would mislead.
+    let failures = pipeline.failures.into_inner().unwrap();
+    let failures_path = failures::path_for(&ctx.config.manifest_dir, ctx.date_prefix);
+    if failures.is_empty() {
+        let _ = tokio::fs::remove_file(&failures_path).await;
+    } else {
+        match failures::write(&failures_path, &failures).await {
+            Ok(()) => println!(
+                "{} failure(s) listed in {}",
+                failures.len(),
+                failures_path.display()
+            ),
+            Err(e) => eprintln!("Warning: could not write failures CSV: {e:#}"),
+        }
+    }
+    let mut stats = pipeline.stats.into_inner().unwrap();
+    stats.elapsed = elapsed;
+    stats.slow_downs = retry::slow_downs() - slow_downs_before;
+    if pipeline.events.is_on() {
+        pipeline.events.emit(Event::Finished {
+            uploaded: stats.uploaded,
+            failed: stats.failed,
+            bytes: stats.bytes_uploaded,
+            seconds: elapsed.as_secs_f64(),
+            average_bytes_per_sec: stats.average_rate(),
+            peak_bytes_per_sec: stats.peak_rate(),
+            fastest: stats.fastest.as_ref(),
+            slowest: stats.slowest.as_ref(),
+        });
+        // Give the writers a moment to pass the last events on.
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    }
+    pipeline.events.close();
+    let budget = pipeline.budget.into_inner().unwrap();
+    stats.started = budget.started;
+    stats.out_of_time = budget.out_of_time;
+    // A streamed listing stops being read once a cap is hit, so what's left
+    // is unknown there.
+    stats.not_started = total.map_or(0, |total| total - stats.started);
+    Ok(stats)
+}
+
+/// An adaptive limit for a stage capped at `max`, if enabled.
+fn config_gate(config: &Config, max: usize) -> Option<Aimd> {
+    config
+        .adaptive_concurrency
+        .then(|| Aimd::new(config.concurrency_min, max))
+}
+
+/// The uploads' limit. Without `--adaptive-concurrency` it stays at
+/// `--concurrency-uploads` except while S3 answers `SlowDown`, when it
+/// halves and then recovers one step at a time.
+fn upload_gate(config: &Config) -> Aimd {
+    config_gate(config, config.upload_concurrency)
+        .unwrap_or_else(|| Aimd::at_max(1, config.upload_concurrency))
+}
+
+impl Pipeline<'_> {
+    /// Runs one transfer of `stage` under its adaptive limit, if any, and
+    /// feeds back whether Drive or S3 throttled it; without
+    /// `--adaptive-concurrency`, only S3 `SlowDown` counts.
+    async fn gated<T>(&self, gate: &Option<Aimd>, stage: &str, work: impl Future<Output = T>) -> T {
+        let Some(gate) = gate else {
+            return work.await;
+        };
+        let permit = gate.acquire().await;
+        let (out, throttles) = retry::count_throttles(work).await;
+        let (throttled, why) = if self.ctx.config.adaptive_concurrency {
+            (throttles.any > 0, " (throttled)")
+        } else {
+            (throttles.slow_down > 0, " (S3 SlowDown)")
+        };
+        if let Some(limit) = gate.release(permit, throttled) {
+            let why = if throttled { why } else { "" };
+            self.say(format!("{stage}: concurrency now {limit}{why}"));
+        }
+        out
+    }
+
+    /// Prints a status line above the bars.
+    fn say(&self, line: String) {
+        progress::println(&self.overall, line);
+    }
+
+    /// Puts the bytes stored under the prefix so far, this run's and earlier
+    /// ones', on the overall bar.
+    fn show_bytes(&self, stats: &RunStats) {
+        let bytes = self.bytes_before + stats.bytes_uploaded + stats.bytes_revisions;
+        self.overall.set_prefix(HumanBytes(bytes).to_string());
+    }
+
+    fn label(&self, index: usize) -> String {
+        match self.total {
+            Some(total) => format!("[{}/{total}]", index + 1),
+            None => format!("[{}]", index + 1),
+        }
+    }
+
+    /// Reserves room for `file` under the run caps. Returns false once a cap
+    /// is reached or `--max-runtime` is up, which ends the download stage;
+    /// transfers already started finish normally.
+    fn admit(&self, file: &DriveFile) -> bool {
+        let config = self.ctx.config;
+        let mut budget = self.budget.lock().unwrap();
+        if config.max_files.is_some_and(|max| budget.files >= max)
+            || config.max_bytes.is_some_and(|max| budget.bytes >= max)
+        {
+            return false;
+        }
+        if config.deadline.is_some_and(|d| Instant::now() >= d) {
+            budget.out_of_time = true;
+            return false;
+        }
+        budget.started += 1;
+        if self.total.is_none() {
+            self.overall.inc_length(1);
+        }
+        budget.files += 1;
+        budget.bytes += file.size_bytes().unwrap_or(0);
+        drop(budget);
+        self.check_credentials(file);
+        let listed_at = self.ctx.listed_at.unwrap_or_else(Utc::now);
+        self.journal.stamp(&file.id, Milestone::Listed(listed_at));
+        true
+    }
+
+    /// Warns, once, when the S3 credentials may lapse before `file` is
+    /// through: they're assumed at startup and not refreshed. The expected
+    /// time comes from the throughput so far.
+    fn check_credentials(&self, file: &DriveFile) {
+        let Some(remaining) = self.ctx.s3.credentials_remaining() else {
+            return;
+        };
+        let done = self.stats.lock().unwrap().bytes_uploaded;
+        let expected = match file.size_bytes() {
+            Some(size) if done > 0 => Duration::from_secs_f64(
+                size as f64 * self.started.elapsed().as_secs_f64() / done as f64,
+            ),
+            _ => Duration::ZERO,
+        };
+        if remaining < expected + CREDENTIALS_MARGIN
+            && !self.credentials_warned.swap(true, Ordering::Relaxed)
+        {
+            self.say(format!(
+                "Warning: the upload credentials expire in {}, and aren't refreshed during a \
+                 run; \"{}\" and later files may fail to upload (they stay in Drive).",
+                HumanDuration(remaining),
+                file.name
+            ));
+        }
+    }
+
+    /// With `--rename`, reserves `key` for `file`, failing when the rename
+    /// already gave it to another file this run. Under `--dedup` a file
+    /// with the same content may share it, to be skipped as a duplicate.
+    fn claim_key(&self, key: &str, file: &DriveFile) -> Result<()> {
+        if self.ctx.config.rename.is_none() {
+            return Ok(());
+        }
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get(key) {
+            None => {
+                keys.insert(
+                    key.to_string(),
+                    (file.id.clone(), file.md5_checksum.clone()),
+                );
+                Ok(())
+            }
+            Some((id, _)) if *id == file.id => Ok(()),
+            Some((_, Some(md5)))
+                if self.ctx.config.dedup && file.md5_checksum.as_ref() == Some(md5) =>
+            {
+                Ok(())
+            }
+            Some((id, _)) => anyhow::bail!(
+                "--rename collision: S3 key {key} is already taken by Drive file {id} this run"
+            ),
+        }
+    }
+
+    /// Records that `file` failed at `stage` and gives its budget back.
+    fn fail(&self, file: &DriveFile, stage: Stage, error: &anyhow::Error) {
+        self.note_failure(file, stage, error);
+        let key = object_key(self.ctx.config, self.ctx.date_prefix, file);
+        self.journal
+            .finish(file, &key, Status::Failed(stage, error));
+        otel::end_file(&file.id, "failed", Some(error));
+        self.stats.lock().unwrap().failed += 1;
+        let mut budget = self.budget.lock().unwrap();
+        budget.files -= 1;
+        budget.bytes -= file.size_bytes().unwrap_or(0);
+    }
+
+    fn note_failure(&self, file: &DriveFile, stage: Stage, error: &anyhow::Error) {
+        self.events.emit(Event::Failed {
+            file_id: &file.id,
+            stage: stage.as_str(),
+            error: format!("{error:#}"),
+        });
+        self.failures.lock().unwrap().push(Failure {
+            // For a resolved shortcut, the shortcut: retrying re-resolves it.
+            file_id: file.delete_id().to_string(),
+            name: file.name.clone(),
+            stage,
+            error: format!("{error:#}"),
+        });
+    }
+
+    /// Refreshes the Google token if it's close to expiry, so a long run
+    /// doesn't start a transfer with a token about to lapse.
+    async fn refresh_token(&self, refresh: impl Future<Output = Result<()>>, when: &str) {
+        if let Err(e) = refresh.await {
+            self.say(format!("Warning: token refresh failed{when}: {e:#}"));
+        }
+    }
+
+    /// Runs `work`, reporting `bar`'s byte count as `stage` progress on the
+    /// `--progress-socket` stream every second until it finishes.
+    async fn track<T>(
+        &self,
+        file: &DriveFile,
+        stage: &'static str,
+        bar: &ProgressBar,
+        work: impl Future<Output = T>,
+    ) -> T {
+        if !self.events.is_on() {
+            return work.await;
+        }
+        let mut work = std::pin::pin!(work);
+        let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                out = &mut work => return out,
+                _ = tick.tick() => self.events.emit(Event::Progress {
+                    file_id: &file.id,
+                    stage,
+                    bytes: bar.position(),
+                    total: bar.length(),
+                }),
+            }
+        }
+    }
+
+    /// `retry`, and with `--wait-for-network`, once more after each outage
+    /// instead of giving up on the file when the network drops.
+    async fn retry_online<F, Fut, T>(&self, op: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        loop {
+            match retry(&op).await {
+                Err(e) if self.network_recovered(&e).await => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// After a failure that looks like lost connectivity, waits for Google
+    /// to answer again (a lightweight `about.get`), showing the wait on the
+    /// overall bar. Returns false, without waiting, when `--wait-for-network`
+    /// is off, the error is something else, or there's no Drive to probe.
+    async fn network_recovered(&self, error: &anyhow::Error) -> bool {
+        if !self.ctx.config.wait_for_network || !retry::is_network_error(error) {
+            return false;
+        }
+        let Some(drive) = self.ctx.drive else {
+            return false;
+        };
+        let _probing = self.network.lock().await;
+        if drive.probe().await.is_ok() {
+            // Back already, or another task waited it out.
+            return true;
+        }
+        self.say(format!(
+            "Network lost ({error:#}) — pausing until it's back ..."
+        ));
+        let since = std::time::Instant::now();
+        loop {
+            self.overall.set_message(format!(
+                "waiting for network ({}s) ...",
+                since.elapsed().as_secs()
+            ));
+            tokio::time::sleep(NETWORK_PROBE_INTERVAL).await;
+            if drive.probe().await.is_ok() {
+                break;
+            }
+        }
+        self.say(format!(
+            "Network is back after {}s — resuming.",
+            since.elapsed().as_secs()
+        ));
+        true
+    }
+
+    /// Downloads `file` into the temp dir (or memory), returning where it is
+    /// and how long that took, or `None` if the download failed (already
+    /// reported and counted).
+    async fn download(&self, index: usize, file: &DriveFile) -> Option<(Staged, Duration)> {
+        if self.ctx.config.stable_check && !self.stable(index, file).await {
+            return None;
+        }
+        self.events.emit(Event::Started {
+            file_id: &file.id,
+            name: &file.name,
+            size: file.size_bytes(),
+        });
+        self.journal.stamp(&file.id, Milestone::DownloadStart);
+        otel::start_file(&file.id, &file.name, file.size_bytes());
+        let key = object_key(self.ctx.config, self.ctx.date_prefix, file);
+        if let Err(e) = aws::validate_key(&key).and_then(|()| self.claim_key(&key, file)) {
+            self.say(format!("{} ✗ {} — {e:#}", self.label(index), file.name));
+            self.fail(file, Stage::Download, &e);
+            return None;
+        }
+        if key_shortened(self.ctx.config, self.ctx.date_prefix, file) {
+            self.say(format!(
+                "{} · {} — name too long for an S3 key; stored as {key}",
+                self.label(index),
+                file.name
+            ));
+        }
+
+        // Checked before downloading, so nothing is fetched for nothing; the
+        // conditional upload still covers an object that appears meanwhile.
+        if self.ctx.config.no_overwrite {
+            if let Ok(Some(_)) = retry(|| self.ctx.s3.head(&key)).await {
+                self.settle_existing(index, file, &key, Duration::ZERO)
+                    .await;
+                return None;
+            }
+        }
+
+        // In test mode the file is already local.
+        if let Some(ref local) = file.local_path {
+            self.journal.stamp(&file.id, Milestone::DownloadEnd);
+            return Some((Staged::Disk(local.clone(), None), Duration::ZERO));
+        }
+        self.overall.set_message(file.name.clone());
+
+        if let Some(existing) = self.duplicate_of(file) {
+            self.skip_duplicate(index, file, &existing).await;
+            return None;
+        }
+
+        if self.ctx.config.stream_upload {
+            if let Some(drive) = self.ctx.drive {
+                self.stream_upload(index, file, drive).await;
+                return None;
+            }
+        }
+
+        if let (Some(drive), Some(max)) = (self.ctx.drive, self.ctx.config.temp_in_memory) {
+            if file.size_bytes().is_some_and(|size| size <= max) {
+                return self.download_to_memory(index, file, drive).await;
+            }
+        }
+
+        // Sanitize the filename to prevent path traversal when writing to the
+        // temp directory. The index prefix keeps concurrent downloads of
+        // identically-named files from clobbering each other.
+        let path = self
+            .ctx
+            .tmp_dir
+            .join(format!("{index}-{}", sanitize_filename(&file.name)));
+
+        let started = Instant::now();
+        otel::start_stage(&file.id, "download");
+        // Refresh the Google token before each file in case it expired mid-run.
+        let dl_result = if let Some(photos) = self.ctx.photos {
+            self.refresh_token(photos.refresh(), "").await;
+            let dl_bar = self.download_bars.acquire();
+            self.track(
+                file,
+                "download",
+                &dl_bar,
+                retry(|| async {
+                    dl_bar.reset();
+                    chaos::inject(Stage::Download)?;
+                    photos.download(file, &path, &dl_bar).await.map(|()| None)
+                }),
+            )
+            .await
+        } else {
+            let drive = self.ctx.drive?;
+            self.refresh_token(drive.refresh(), "").await;
+            // Retries resume a partial file; anything from before is stale.
+            let _ = tokio::fs::remove_file(&path).await;
+            let dl_bar = self.download_bars.acquire();
+            self.track(
+                file,
+                "download",
+                &dl_bar,
+                self.retry_online(|| async {
+                    dl_bar.reset();
+                    chaos::inject(Stage::Download)?;
+                    drive.download(file, &path, &dl_bar).await
+                }),
+            )
+            .await
+        };
+        otel::end_stage(&file.id, "download", &dl_result);
+
+        match dl_result {
+            Ok(checksum) => {
+                self.journal.stamp(&file.id, Milestone::DownloadEnd);
+                Some((Staged::Disk(path, checksum), started.elapsed()))
+            }
+            Err(e) => {
+                self.say(format!(
+                    "{} ✗ {} — download error: {e:#}",
+                    self.label(index),
+                    file.name
+                ));
+                self.fail(file, Stage::Download, &e);
+                self.discard_failed(file, &path).await;
+                None
+            }
+        }
+    }
+
+    /// With `--stable-check`: whether `file` still has the size and
+    /// modifiedTime it was listed with. One that changed, or is gone, may
+    /// still be being written; it's reported and left, untouched, for the
+    /// next run.
+    async fn stable(&self, index: usize, file: &DriveFile) -> bool {
+        let Some(drive) = self.ctx.drive.filter(|_| file.local_path.is_none()) else {
+            return true;
+        };
+        let changed = match retry(|| drive.get_file(&file.id)).await {
+            Ok(now) if now.trashed => Some("trashed".to_string()),
+            Ok(now) if now.size != file.size => Some(format!(
+                "size {} → {}",
+                file.size.as_deref().unwrap_or("?"),
+                now.size.as_deref().unwrap_or("?")
+            )),
+            Ok(now) if now.modified_time != file.modified_time => {
+                Some("modified since the listing".to_string())
+            }
+            Ok(_) => None,
+            Err(e) if drive::is_not_found(&e) => Some("gone from Drive".to_string()),
+            // The download itself will say what's wrong.
+            Err(_) => None,
+        };
+        let Some(changed) = changed else {
+            return true;
+        };
+        self.say(format!(
+            "{} ~ {} — still changing ({changed}); left for the next run",
+            self.label(index),
+            file.name
+        ));
+        self.stats.lock().unwrap().unstable += 1;
+        let mut budget = self.budget.lock().unwrap();
+        budget.files -= 1;
+        budget.bytes -= file.size_bytes().unwrap_or(0);
+        false
+    }
+
+    /// With `--temp-in-memory`: downloads a small file into memory, skipping
+    /// the temp file.
+    async fn download_to_memory(
+        &self,
+        index: usize,
+        file: &DriveFile,
+        drive: &DriveClient<'_>,
+    ) -> Option<(Staged, Duration)> {
+        let started = Instant::now();
+        otel::start_stage(&file.id, "download");
+        self.refresh_token(drive.refresh(), "").await;
+        let dl_bar = self.download_bars.acquire();
+        let result = self
+            .track(
+                file,
+                "download",
+                &dl_bar,
+                self.retry_online(|| async {
+                    dl_bar.reset();
+                    chaos::inject(Stage::Download)?;
+                    drive.download_bytes(file, &dl_bar).await
+                }),
+            )
+            .await;
+        otel::end_stage(&file.id, "download", &result);
+        match result {
+            Ok(bytes) => {
+                self.journal.stamp(&file.id, Milestone::DownloadEnd);
+                Some((Staged::Memory(bytes), started.elapsed()))
+            }
+            Err(e) => {
+                self.say(format!(
+                    "{} ✗ {} — download error: {e:#}",
+                    self.label(index),
+                    file.name
+                ));
+                self.fail(file, Stage::Download, &e);
+                None
+            }
+        }
+    }
+
+    /// Uploads a downloaded file, then deletes it from Drive once S3 has it.
+    async fn upload<F: Borrow<DriveFile>>(&self, downloaded: Downloaded<F>) {
+        let Downloaded {
+            index,
+            file,
+            staged,
+            took,
+        } = downloaded;
+        let file = file.borrow();
+        let label = self.label(index);
+        let s3_key = object_key(self.ctx.config, self.ctx.date_prefix, file);
+
+        // A copy finished uploading while this one was downloading.
+        if let Some(existing) = self.duplicate_of(file) {
+            if let Some(path) = staged.path() {
+                self.remove_temp(file, path).await;
+            }
+            self.skip_duplicate(index, file, &existing).await;
+            return;
+        }
+
+        self.events.emit(Event::Uploading {
+            file_id: &file.id,
+            key: &s3_key,
+        });
+        let spinner = self.upload_bars.acquire();
+        spinner.set_message(format!("Uploading to s3://{}/{s3_key}", self.ctx.bucket));
+        let content_type = self.content_type(file, &staged).await;
+        let metadata = key_metadata(self.ctx.config, self.ctx.date_prefix, file);
+        let started = Instant::now();
+        otel::start_stage(&file.id, "upload");
+        let result = self
+            .retry_online(|| async {
+                chaos::inject(Stage::Upload)?;
+                let s3 = self.ctx.s3;
+                match staged {
+                    Staged::Disk(ref path, ref checksum) => {
+                        s3.upload(
+                            &s3_key,
+                            path,
+                            checksum.clone(),
+                            content_type.as_deref(),
+                            &metadata,
+                        )
+                        .await
+                    }
+                    Staged::Memory(ref bytes) => {
+                        s3.upload_bytes(&s3_key, bytes.clone(), content_type.as_deref(), &metadata)
+                            .await
+                    }
+                }
+            })
+            .await;
+        otel::end_stage(&file.id, "upload", &result);
+        drop(spinner);
+
+        match result {
+            Err(e) if aws::is_already_exists(&e) => {
+                if let Some(path) = staged.path() {
+                    self.remove_temp(file, path).await;
+                }
+                self.settle_existing(index, file, &s3_key, took + started.elapsed())
+                    .await;
+                return;
+            }
+            Err(e) => {
+                self.say(format!("{label} ✗ {} — upload error: {e:#}", file.name));
+                self.fail(file, Stage::Upload, &e);
+                if let Some(path) = staged.path() {
+                    self.discard_failed(file, path).await;
+                }
+                return;
+            }
+            Ok(()) => {}
+        }
+
+        if let Some(path) = staged.path() {
+            match self.ctx.config.keep_local {
+                Some(ref dir) if file.local_path.is_none() => {
+                    self.keep(path, &dir.join(&s3_key)).await;
+                }
+                _ => self.remove_temp(file, path).await,
+            }
+        }
+        self.archived(&label, file, &s3_key, took + started.elapsed())
+            .await;
+    }
+
+    /// With `--stream-upload`: copies `file` from Drive straight into S3
+    /// without a temp file. The upload is only kept if S3 received exactly
+    /// the size Drive reported; otherwise the object is removed and the Drive
+    /// copy stays.
+    async fn stream_upload(&self, index: usize, file: &DriveFile, drive: &DriveClient<'_>) {
+        let label = self.label(index);
+        let s3_key = object_key(self.ctx.config, self.ctx.date_prefix, file);
+        let content_type = (!file.mime_type.is_empty()
+            && !GENERIC_MIMETYPES.contains(&file.mime_type.as_str()))
+        .then(|| file.mime_type.clone());
+        let metadata = key_metadata(self.ctx.config, self.ctx.date_prefix, file);
+
+        self.refresh_token(drive.refresh(), "").await;
+        let started = Instant::now();
+        otel::start_stage(&file.id, "stream");
+        let bar = self.download_bars.acquire();
+        let transfer = self.retry_online(|| async {
+            bar.reset();
+            let response = drive.open_media(file).await?;
+            if let Some(len) = file.size_bytes().or(response.content_length()) {
+                bar.set_length(len);
+            }
+            let body = crate::drive::body_chunks(response, &bar, drive.byte_cap(file.size_bytes()));
+            self.ctx
+                .s3
+                .upload_stream(
+                    &s3_key,
+                    content_type.as_deref(),
+                    body,
+                    drive.expected_size(file),
+                    &metadata,
+                )
+                .await
+        });
+        let result = self.track(file, "stream", &bar, transfer).await;
+        otel::end_stage(&file.id, "stream", &result);
+        drop(bar);
+
+        match result {
+            Ok(sent) => {
+                // S3 already refused a mismatch unless --no-size-check is on;
+                // then this only warns.
+                let _ = drive.check_size(file, sent);
+                self.journal.stamp(&file.id, Milestone::DownloadEnd);
+                self.archived(&label, file, &s3_key, started.elapsed())
+                    .await
+            }
+            Err(e) if aws::is_already_exists(&e) => {
+                self.settle_existing(index, file, &s3_key, started.elapsed())
+                    .await
+            }
+            Err(e) => {
+                self.say(format!("{label} ✗ {} — upload error: {e:#}", file.name));
+                self.fail(file, Stage::Upload, &e);
+            }
+        }
+    }
+
+    /// Finishes a file S3 now holds at `s3_key` after `took` transferring:
+    /// deletes it from Drive, records it and counts it as uploaded.
+    async fn archived(&self, label: &str, file: &DriveFile, s3_key: &str, took: Duration) {
+        if self.ctx.config.dedup {
+            if let Some(ref md5) = file.md5_checksum {
+                self.uploaded_md5
+                    .lock()
+                    .unwrap()
+                    .entry(md5.clone())
+                    .or_insert_with(|| s3_key.to_string());
+            }
+        }
+
+        self.journal.stamp(&file.id, Milestone::UploadEnd);
+        // Only delete from Drive after a confirmed successful S3 upload.
+        let deleted = self.delete_from_drive(label, file, "").await;
+        if deleted {
+            self.journal.stamp(&file.id, Milestone::DeleteEnd);
+        }
+        self.journal.finish(file, s3_key, Status::Archived);
+        otel::end_file(&file.id, "archived", None);
+        self.record(file, s3_key, deleted, None).await;
+        self.events.emit(Event::Done {
+            file_id: &file.id,
+            key: s3_key,
+            deleted_from_drive: deleted,
+        });
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.uploaded += 1;
+        stats.bytes_uploaded += file.size_bytes().unwrap_or(0);
+        stats.time(file, took);
+        self.show_bytes(&stats);
+        drop(stats);
+        self.overall.inc(1);
+    }
+
+    /// Deletes an archived file from Drive and reports it as done, with `note`
+    /// after its name. Returns whether it was deleted; in test mode and with
+    /// the Photos source there is nothing to delete.
+    async fn delete_from_drive(&self, label: &str, file: &DriveFile, note: &str) -> bool {
+        let Some(drive) = self.ctx.drive else {
+            self.say(format!("{label} ✓ {}{note}", file.name));
+            return false;
+        };
+        if !self.archive_revisions(label, file, drive).await {
+            self.say(format!(
+                "{label} ✓ {}{note} (archived) — kept in Drive, as not all of its revisions were archived",
+                file.name
+            ));
+            self.stats.lock().unwrap().note_not_deleted(None);
+            return false;
+        }
+        if self.ctx.config.keep_in_drive {
+            self.say(format!("{label} ✓ {}{note} (kept in Drive)", file.name));
+            return false;
+        }
+        let trash = self.ctx.config.delete_after.is_some();
+        if !file.can_remove(trash) {
+            self.say(format!(
+                "{label} ✓ {}{note} (archived) — no {} permission in Drive, left in place",
+                file.name,
+                if trash { "trash" } else { "delete" }
+            ));
+            self.stats
+                .lock()
+                .unwrap()
+                .note_not_deleted(Some(drive::Denied::File));
+            return false;
+        }
+        self.refresh_token(drive.refresh(), " before delete").await;
+        otel::start_stage(&file.id, "delete");
+        let removed = if let Err(e) = chaos::inject(Stage::Delete) {
+            Err(e)
+        } else if trash {
+            drive.trash(file.delete_id()).await
+        } else {
+            drive.delete(file.delete_id()).await
+        };
+        otel::end_stage(&file.id, "delete", &removed);
+        match removed {
+            Ok(()) => {
+                self.say(format!("{label} ✓ {}{note}", file.name));
+                self.stats.lock().unwrap().note_removed(file);
+                true
+            }
+            Err(e) => {
+                let denied = drive::permission_denied(&e);
+                match denied {
+                    Some(why) => self.say(format!(
+                        "{label} ✓ {}{note} (archived) — cannot delete: {}",
+                        file.name,
+                        why.advice()
+                    )),
+                    None => self.say(format!(
+                        "{label} ✓ {}{note} (archived) — warning: Drive delete failed: {e}",
+                        file.name
+                    )),
+                }
+                self.note_failure(file, Stage::Delete, &e);
+                let mut stats = self.stats.lock().unwrap();
+                stats.note_not_deleted(denied);
+                false
+            }
+        }
+    }
+
+    /// With `--include-revisions`: archives each earlier revision of `file`
+    /// at `<its key>/rev-<revision id>` while Drive still has them. Returns
+    /// false if any failed, so the file isn't deleted and the next run tries
+    /// again. Files whose history this account can't see go without it, as
+    /// do Workspace files, whose revisions are only exports.
+    async fn archive_revisions(
+        &self,
+        label: &str,
+        file: &DriveFile,
+        drive: &DriveClient<'_>,
+    ) -> bool {
+        if !self.ctx.config.include_revisions
+            || file.local_path.is_some()
+            || file.mime_type.starts_with("application/vnd.google-apps.")
+        {
+            return true;
+        }
+        otel::start_stage(&file.id, "revisions");
+        let listed = self.retry_online(|| drive.list_revisions(&file.id)).await;
+        let mut revisions = match listed {
+            Ok(revisions) => revisions,
+            Err(e) if drive::permission_denied(&e).is_some() || drive::is_not_found(&e) => {
+                self.say(format!(
+                    "{label}   {} — no access to its revision history, archiving the current version only",
+                    file.name
+                ));
+                otel::end_stage(&file.id, "revisions", &Ok(()));
+                return true;
+            }
+            Err(e) => {
+                let failed: Result<()> = Err(e.context("Cannot list revisions"));
+                otel::end_stage(&file.id, "revisions", &failed);
+                if let Err(ref e) = failed {
+                    self.say(format!("{label} ✗ {} — {e:#}", file.name));
+                    self.note_failure(file, Stage::Delete, e);
+                }
+                return false;
+            }
+        };
+        // The last one is the current content, archived already.
+        revisions.pop();
+
+        let key = object_key(self.ctx.config, self.ctx.date_prefix, file);
+        let content_type = (!file.mime_type.is_empty()
+            && !GENERIC_MIMETYPES.contains(&file.mime_type.as_str()))
+        .then(|| file.mime_type.clone());
+        let mut all_archived = true;
+        for revision in &revisions {
+            let rev_key = revision_key(&key, &revision.id);
+            let bar = ProgressBar::hidden();
+            let sent = self
+                .retry_online(|| async {
+                    let response = drive.open_revision(&file.id, &revision.id).await?;
+                    let body = crate::drive::body_chunks(
+                        response,
+                        &bar,
+                        drive.byte_cap(revision.size_bytes()),
+                    );
+                    self.ctx
+                        .s3
+                        .upload_stream(
+                            &rev_key,
+                            content_type.as_deref(),
+                            body,
+                            revision.size_bytes().filter(|_| self.ctx.config.size_check),
+                            &[],
+                        )
+                        .await
+                })
+                .await;
+            match sent {
+                Ok(bytes) => {
+                    self.record_entry(ManifestEntry {
+                        file_id: file.id.clone(),
+                        shortcut_id: file.shortcut_id.clone(),
+                        name: format!("{}/rev-{}", file.name, revision.id),
+                        key: rev_key,
+                        size: Some(bytes),
+                        md5: revision.md5_checksum.clone(),
+                        uploaded_at: Utc::now(),
+                        deleted_from_drive: false,
+                        duplicate_of: None,
+                        trashed: None,
+                        bundle: None,
+                        revision: Some(revision.id.clone()),
+                    })
+                    .await;
+                    let mut stats = self.stats.lock().unwrap();
+                    stats.revisions += 1;
+                    stats.bytes_revisions += bytes;
+                    self.show_bytes(&stats);
+                }
+                Err(e) => {
+                    let e = e.context(format!("Cannot archive revision {}", revision.id));
+                    self.say(format!("{label} ✗ {} — {e:#}", file.name));
+                    self.note_failure(file, Stage::Delete, &e);
+                    all_archived = false;
+                }
+            }
+        }
+        let finished = if all_archived {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Not all revisions archived"))
+        };
+        otel::end_stage(&file.id, "revisions", &finished);
+        if !revisions.is_empty() && all_archived {
+            self.say(format!(
+                "{label}   {} — {} earlier revision(s) archived",
+                file.name,
+                revisions.len()
+            ));
+        }
+        all_archived
+    }
+
+    /// With `--dedup`, the key this run already uploaded `file`'s content to.
+    fn duplicate_of(&self, file: &DriveFile) -> Option<String> {
+        if !self.ctx.config.dedup {
+            return None;
+        }
+        let md5 = file.md5_checksum.as_ref()?;
+        self.uploaded_md5.lock().unwrap().get(md5).cloned()
+    }
+
+    /// Settles a file whose content is already archived at `existing`: no
+    /// bytes are stored again, the manifest points at the existing object,
+    /// and the Drive copy is deleted as usual.
+    async fn skip_duplicate(&self, index: usize, file: &DriveFile, existing: &str) {
+        let label = self.label(index);
+        let s3_key = object_key(self.ctx.config, self.ctx.date_prefix, file);
+        let deleted = self
+            .delete_from_drive(&label, file, &format!(" (duplicate of {existing})"))
+            .await;
+        if deleted {
+            self.journal.stamp(&file.id, Milestone::DeleteEnd);
+        }
+        self.journal.finish(file, &s3_key, Status::Duplicate);
+        otel::end_file(&file.id, "duplicate", None);
+        self.record(file, &s3_key, deleted, Some(existing)).await;
+        self.events.emit(Event::Duplicate {
+            file_id: &file.id,
+            duplicate_of: existing,
+        });
+        let mut stats = self.stats.lock().unwrap();
+        stats.deduplicated += 1;
+        stats.bytes_deduplicated += file.size_bytes().unwrap_or(0);
+        drop(stats);
+        self.overall.inc(1);
+    }
+
+    /// With `--no-overwrite`, settles a file whose key S3 already holds.
+    /// When the object provably is this file (a single-part upload whose
+    /// ETag is the file's MD5: an earlier attempt that got through, say),
+    /// the file counts as archived. Otherwise neither copy is touched and
+    /// the file stays in Drive.
+    async fn settle_existing(&self, index: usize, file: &DriveFile, key: &str, took: Duration) {
+        let label = self.label(index);
+        let same = match retry(|| self.ctx.s3.head(key)).await {
+            Ok(Some(object)) => {
+                file.size_bytes() == Some(object.size)
+                    && matches!(
+                        (&file.md5_checksum, &object.etag),
+                        (Some(md5), Some(etag)) if !etag.contains('-') && etag.eq_ignore_ascii_case(md5)
+                    )
+            }
+            _ => false,
+        };
+        if same {
+            self.archived(&label, file, key, took).await;
+            return;
+        }
+        self.say(format!(
+            "{label} – {} — s3://{}/{key} already holds other content; not overwritten, left in Drive",
+            file.name, self.ctx.bucket
+        ));
+        self.journal.finish(file, key, Status::Skipped);
+        otel::end_file(&file.id, "skipped", None);
+        self.stats.lock().unwrap().not_overwritten += 1;
+        let mut budget = self.budget.lock().unwrap();
+        budget.files -= 1;
+        budget.bytes -= file.size_bytes().unwrap_or(0);
+    }
+
+    /// The `Content-Type` for the S3 object: Drive's mimeType, unless that's
+    /// generic and `--sniff-content-type` is on, in which case the first few KB
+    /// of the downloaded file decide.
+    async fn content_type(&self, file: &DriveFile, staged: &Staged) -> Option<String> {
+        let generic =
+            file.mime_type.is_empty() || GENERIC_MIMETYPES.contains(&file.mime_type.as_str());
+        if !generic {
+            return Some(file.mime_type.clone());
+        }
+        if !self.ctx.config.sniff_content_type {
+            return None;
+        }
+        let path = match staged {
+            Staged::Disk(path, _) => path,
+            Staged::Memory(bytes) => {
+                return infer::get(&bytes[..bytes.len().min(SNIFF_BYTES)])
+                    .map(|t| t.mime_type().to_string());
+            }
+        };
+        let mut head = vec![0u8; SNIFF_BYTES];
+        let mut f = tokio::fs::File::open(path).await.ok()?;
+        let mut len = 0;
+        while len < head.len() {
+            match f.read(&mut head[len..]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => len += n,
+            }
+        }
+        infer::get(&head[..len]).map(|t| t.mime_type().to_string())
+    }
+
+    /// Adds the archived file to the manifest and saves it right away, so the
+    /// record survives even if the run is interrupted.
+    async fn record(&self, file: &DriveFile, key: &str, deleted: bool, duplicate_of: Option<&str>) {
+        self.record_entry(ManifestEntry {
+            file_id: file.id.clone(),
+            shortcut_id: file.shortcut_id.clone(),
+            name: file.name.clone(),
+            key: key.to_string(),
+            size: file.size_bytes(),
+            md5: file.md5_checksum.clone(),
+            uploaded_at: Utc::now(),
+            deleted_from_drive: deleted,
+            duplicate_of: duplicate_of.map(String::from),
+            trashed: (deleted && self.ctx.config.delete_after.is_some()).then(|| Trashed {
+                id: file.delete_id().to_string(),
+                at: Utc::now(),
+                purged_at: None,
+            }),
+            bundle: None,
+            revision: None,
+        })
+        .await;
+    }
+
+    async fn record_entry(&self, entry: ManifestEntry) {
+        let mut manifest = self.manifest.lock().await;
+        manifest.record(entry);
+        if let Err(e) = manifest.save(&self.manifest_path).await {
+            self.say(format!("Warning: could not save manifest: {e:#}"));
+        }
+    }
+
+    /// Moves an archived file from its download path to `dest` under the
+    /// `--keep-local` directory, overwriting any older copy there.
+    async fn keep(&self, path: &Path, dest: &Path) {
+        let moved = async {
+            if let Some(parent) = dest.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            fsutil::move_file(path, dest).await
+        };
+        if let Err(e) = moved.await {
+            self.say(format!(
+                "Warning: could not keep local copy at {}: {e} (left at {})",
+                dest.display(),
+                path.display()
+            ));
+        }
+    }
+
+    async fn remove_temp(&self, file: &DriveFile, path: &Path) {
+        if file.local_path.is_none() {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    /// Removes the temp file of a failed transfer, or with
+    /// `--keep-failed-temp` moves it there for inspection.
+    async fn discard_failed(&self, file: &DriveFile, path: &Path) {
+        if file.local_path.is_some() {
+            return;
+        }
+        if let Err(e) = discard_failed(self.ctx.config, file, path).await {
+            self.say(format!("Warning: could not keep failed temp file: {e:#}"));
+        }
+    }
+}
+
+/// Removes `path`, what's left of a failed transfer of `file`, or with
+/// `--keep-failed-temp` moves it into that directory as `<file id>-<name>`,
+/// replacing an older copy. Nothing to do if the attempt left no file.
+pub async fn discard_failed(config: &Config, file: &DriveFile, path: &Path) -> Result<()> {
+    if tokio::fs::metadata(path).await.is_err() {
+        return Ok(());
+    }
+    let Some(ref dir) = config.keep_failed_temp else {
+        let _ = tokio::fs::remove_file(path).await;
+        return Ok(());
+    };
+    tokio::fs::create_dir_all(dir).await?;
+    let dest = dir.join(format!("{}-{}", file.id, sanitize_filename(&file.name)));
+    fsutil::move_file(path, &dest).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revision_keys_fit() {
+        assert_eq!(
+            revision_key("2026-02-22/a.jpg", "0B1"),
+            "2026-02-22/a.jpg/rev-0B1"
+        );
+
+        let key = fit_key(
+            format!("2026-02-22/{}.jpg", "x".repeat(2000)),
+            aws::MAX_KEY_BYTES,
+        );
+        assert_eq!(key.len(), aws::MAX_KEY_BYTES);
+        let rev = revision_key(&key, "0B1abc");
+        assert_eq!(rev.len(), aws::MAX_KEY_BYTES);
+        assert!(rev.starts_with("2026-02-22/x") && rev.ends_with(".jpg/rev-0B1abc"));
+    }
+}
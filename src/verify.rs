@@ -0,0 +1,231 @@
+use crate::auth;
+use crate::aws::{S3Object, S3Uploader};
+use crate::config::{Config, Source};
+use crate::drive::{self, DriveClient};
+use crate::lock;
+use crate::manifest::{self, Manifest};
+use crate::marker;
+use crate::transfer::object_key;
+use anyhow::Result;
+use reqwest::Client;
+use std::collections::{HashMap, HashSet};
+
+/// An object we expect to find under the prefix being verified.
+struct Expected {
+    key: String,
+    size: Option<u64>,
+    md5: Option<String>,
+}
+
+/// `verify <prefix>` — read-only audit of a past backup. Compares the objects
+/// under `prefix` with that prefix's manifest, or with the files still in the
+/// Drive folder when no manifest exists. Transfers no file data.
+pub async fn run(http: &Client, config: &Config, s3: &S3Uploader, prefix: &str) -> Result<()> {
+    let prefix = prefix.trim_end_matches('/');
+    let manifest_path = manifest::path_for(&config.manifest_dir, prefix);
+
+    let (expected, from_manifest) = match Manifest::load(&manifest_path).await? {
+        Some(m) => {
+            println!("Checking against manifest {} ...", manifest_path.display());
+            (expected_from_manifest(&m), true)
+        }
+        None => {
+            println!(
+                "No manifest at {} — checking against files still in Drive.",
+                manifest_path.display()
+            );
+            (expected_from_drive(http, config, prefix).await?, false)
+        }
+    };
+
+    println!("Listing objects under {prefix}/ ...");
+    let mut objects = s3.list_objects(&format!("{prefix}/")).await?;
+    // A run in progress (or one that crashed) leaves its lock behind.
+    let lock_key = format!("{prefix}/{}", lock::LOCK_NAME);
+    let markers: Vec<String> = marker::names(config)
+        .iter()
+        .map(|m| format!("{prefix}/{m}"))
+        .collect();
+    objects.retain(|o| o.key != lock_key && !markers.contains(&o.key));
+    let by_key: HashMap<&str, &S3Object> = objects.iter().map(|o| (o.key.as_str(), o)).collect();
+
+    let mut missing = Vec::new();
+    let mut mismatched = Vec::new();
+    let mut ok = 0usize;
+    for e in &expected {
+        let Some(obj) = by_key.get(e.key.as_str()) else {
+            missing.push(e.key.as_str());
+            continue;
+        };
+        if let Some(size) = e.size.filter(|&s| s != obj.size) {
+            mismatched.push(format!(
+                "{} — size: expected {size}, found {}",
+                e.key, obj.size
+            ));
+            continue;
+        }
+        // A single-part upload's ETag is the content MD5; multipart ETags
+        // (containing '-') aren't comparable.
+        if let (Some(md5), Some(etag)) = (&e.md5, &obj.etag) {
+            if !etag.contains('-') && !etag.eq_ignore_ascii_case(md5) {
+                mismatched.push(format!("{} — md5: expected {md5}, found {etag}", e.key));
+                continue;
+            }
+        }
+        ok += 1;
+    }
+
+    let expected_keys: HashSet<&str> = expected.iter().map(|e| e.key.as_str()).collect();
+    let extras: Vec<&str> = objects
+        .iter()
+        .map(|o| o.key.as_str())
+        .filter(|k| !expected_keys.contains(k))
+        .collect();
+
+    println!("\n{ok}/{} expected object(s) verified.", expected.len());
+    if !missing.is_empty() {
+        println!("Missing from S3 ({}):", missing.len());
+        for key in &missing {
+            println!("  - {key}");
+        }
+    }
+    if !mismatched.is_empty() {
+        println!("Mismatched ({}):", mismatched.len());
+        for line in &mismatched {
+            println!("  - {line}");
+        }
+    }
+    if !extras.is_empty() {
+        if from_manifest {
+            println!("Not in manifest ({}):", extras.len());
+        } else {
+            // Without a manifest, anything already deleted from Drive shows up here.
+            println!(
+                "Not in Drive — likely archived and deleted ({}):",
+                extras.len()
+            );
+        }
+        for key in &extras {
+            println!("  - {key}");
+        }
+    }
+
+    let problems = missing.len() + mismatched.len() + if from_manifest { extras.len() } else { 0 };
+    if problems > 0 {
+        anyhow::bail!("Verification found {problems} problem(s) under {prefix}/");
+    }
+    println!("Backup under {prefix}/ looks complete.");
+    Ok(())
+}
+
+/// The objects `manifest` says were stored: one per file, except that a
+/// `--bundle` tar stands in for all the files inside it. A tar's size isn't
+/// recorded, and a multipart ETag is no MD5, so only its presence is checked.
+fn expected_from_manifest(manifest: &Manifest) -> Vec<Expected> {
+    let mut expected: Vec<Expected> = Vec::new();
+    for e in &manifest.files {
+        let bundled = e.bundle.is_some();
+        let key = e.object_key();
+        if bundled && expected.iter().any(|x| x.key == key) {
+            continue;
+        }
+        expected.push(Expected {
+            key: key.to_string(),
+            size: e.size.filter(|_| !bundled),
+            md5: e.md5.clone().filter(|_| !bundled),
+        });
+    }
+    expected
+}
+
+async fn expected_from_drive(
+    http: &Client,
+    config: &Config,
+    prefix: &str,
+) -> Result<Vec<Expected>> {
+    if config.source == Source::Photos {
+        anyhow::bail!("Verifying a Photos backup needs its manifest");
+    }
+    println!("Authenticating with Google Drive ...");
+    let token = auth::load_or_authenticate(http, config).await?;
+    let drive = DriveClient::new(http, auth::TokenHolder::new(http, config, token));
+    let folder_ids = if config.shared_with_me {
+        vec![drive::SHARED_WITH_ME.to_string()]
+    } else {
+        drive
+            .find_folders(
+                &config.folder_name,
+                config.parent_id.as_deref(),
+                config.on_ambiguous,
+            )
+            .await?
+    };
+
+    let mut files = Vec::new();
+    for folder_id in &folder_ids {
+        if config.recursive {
+            files.extend(
+                drive
+                    .list_tree(folder_id, config.trashed, config.listing_concurrency)
+                    .await?
+                    .0,
+            );
+        } else {
+            files.extend(drive.list_files(folder_id, config.trashed).await?);
+        }
+    }
+    Ok(files
+        .into_iter()
+        .filter(|f| {
+            !drive::is_workspace_file(f, &config.workspace_mimetypes)
+                && !drive::is_shortcut(f)
+                && !drive::is_folder(f)
+                && config.extension_allowed(&f.name)
+        })
+        .map(|f| Expected {
+            key: object_key(config, prefix, &f),
+            size: f.size_bytes(),
+            md5: f.md5_checksum,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ManifestEntry;
+    use chrono::Utc;
+
+    fn entry(key: &str, bundle: Option<&str>) -> ManifestEntry {
+        ManifestEntry {
+            file_id: key.to_string(),
+            shortcut_id: None,
+            name: key.to_string(),
+            key: key.to_string(),
+            size: Some(10),
+            md5: Some("0123456789abcdef0123456789abcdef".into()),
+            uploaded_at: Utc::now(),
+            deleted_from_drive: true,
+            duplicate_of: None,
+            trashed: None,
+            bundle: bundle.map(String::from),
+            revision: None,
+        }
+    }
+
+    #[test]
+    fn bundled_files_expect_their_tar() {
+        let tar = "2026-02-22/takeout-bundle.tar.zst";
+        let mut manifest = Manifest::new("2026-02-22");
+        manifest.files = vec![
+            entry(&format!("{tar}/a.jpg"), Some(tar)),
+            entry(&format!("{tar}/b.jpg"), Some(tar)),
+            entry("2026-02-22/c.jpg", None),
+        ];
+        let expected = expected_from_manifest(&manifest);
+        let keys: Vec<&str> = expected.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, [tar, "2026-02-22/c.jpg"]);
+        assert_eq!((expected[0].size, expected[0].md5.as_deref()), (None, None));
+        assert_eq!(expected[1].size, Some(10));
+    }
+}